@@ -2,6 +2,7 @@ extern crate fastnet;
 extern crate env_logger;
 use std::net::{ToSocketAddrs};
 use std::env;
+use std::io;
 
 fn main() {
     env_logger::init().unwrap();
@@ -14,9 +15,12 @@ fn main() {
     let addr = i.next().unwrap();
     let maybe_serv = fastnet::Server::new(addr, fastnet::PrintingHandler::new());
     if let Err(ref what) = maybe_serv {
-        format!("Error: {:?}", what);
+        println!("Error: {:?}", what);
+        return;
     }
-    let mut serv = maybe_serv.unwrap();
-    println!("Server is running.");
-    loop {}
+    let serv = maybe_serv.unwrap();
+    println!("Server is running.  Press enter to stop.");
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap();
+    serv.shutdown(Some(1000));
 }
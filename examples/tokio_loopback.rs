@@ -0,0 +1,33 @@
+extern crate fastnet;
+extern crate env_logger;
+extern crate futures_core;
+extern crate tokio;
+use futures_core::Stream;
+use std::future::{self, Future};
+use std::net::{ToSocketAddrs};
+use std::env;
+use std::pin::Pin;
+
+fn next<'a, S: Stream+Unpin>(stream: &'a mut S)->impl Future<Output=Option<S::Item>>+'a {
+    future::poll_fn(move |cx| Pin::new(&mut *stream).poll_next(cx))
+}
+
+fn main() {
+    env_logger::init().unwrap();
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() != 3 {
+        println!("Syntax: {} <address> <address>", args[0]);
+        return;
+    }
+    let addr1 = args[1].to_socket_addrs().unwrap().next().unwrap();
+    let addr2 = args[2].to_socket_addrs().unwrap().next().unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let (_listener, mut incoming) = fastnet::Endpoint::bind(addr1).unwrap();
+    let (connector, _) = fastnet::Endpoint::bind(addr2).unwrap();
+    let client = runtime.block_on(connector.connect(addr1)).unwrap();
+    let mut server_side = runtime.block_on(next(&mut incoming)).unwrap();
+    println!("Connected: {:?}", client.id());
+    runtime.block_on(client.send(0, b"hello", true)).unwrap();
+    let message = runtime.block_on(next(&mut server_side)).unwrap();
+    println!("Got {:?} on channel {}", String::from_utf8_lossy(&message.payload), message.channel);
+}
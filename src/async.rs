@@ -1,6 +1,16 @@
-use std::{result, io, net};
+use std::{result, io, net, time, collections, fs, path};
+use std::sync::mpsc;
 use server;
 use uuid;
+use {MAX_CHANNEL, MAX_MESSAGE_SIZE};
+use congestion::CongestionController;
+use capture::PcapWriter;
+use sha2::{Sha256, Digest};
+pub use packets::{MessageHeader, Rpc};
+#[cfg(feature = "serde")]
+use serde;
+#[cfg(feature = "serde")]
+use typed::TypeRegistry;
 
 ///Represents a Fastnet error.
 #[derive(Debug)]
@@ -12,46 +22,553 @@ pub enum Error {
     IncompatibleVersions,
     ConnectionAborted,
     MessageTooLarge,
+    ///There's already a connection to that address.
+    AlreadyConnected,
+    ///Encryption was required, but the other side doesn't support it or refused.
+    EncryptionUnavailable,
+    ///The server's static key doesn't match the pinned one.
+    KeyMismatch,
+    ///Channels above `MAX_CHANNEL` are reserved.
+    InvalidChannel,
+    ///The server runs in a background thread and can't be polled.
+    NotPolled,
+    ///The server stopped after an error was reported to `Handler::server_error`.
+    ServerStopped,
+    ///The peer refused or cancelled an asset transfer.
+    TransferRejected,
+    ///An asset didn't match its hash.
+    TransferCorrupt,
+    ///There's no asset transfer with that id, or the id is already in use.
+    InvalidTransfer,
+    ///The peer has different channels configured as `ChannelMode::ReliableUnordered`.
+    ChannelModeMismatch,
+    ///A message sent with `Server::send_message_with_ttl` wasn't acknowledged in time.
+    MessageExpired,
+    ///A typed message's type isn't in the `TypeRegistry`.
+    UnregisteredType,
+    ///A type or type id was registered twice.
+    DuplicateMessageType,
+    ///An incoming typed message had no type id, or one with nothing registered for it.
+    UnknownMessageType(Option<u32>),
+    ///A header given to `Server::send_message_with_header` has `rpc` set, which is only for `Server::request` and `Server::respond`.
+    InvalidHeader,
+    ///A typed message couldn't be serialized or deserialized, or arrived on the wrong channel.
+    InvalidMessage(String),
     IoError(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+///Whether connections use the `fastnet_encryption` extension.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    Disabled,
+    ///Encrypt if the other side supports it.
+    Preferred,
+    ///Refuse connections which aren't encrypted.
+    Required,
+}
+
+/**Configures encryption.
+
+The default is to encrypt whenever the other side supports it, without a static key.*/
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    pub policy: EncryptionPolicy,
+    /**A long-term secret key, used when accepting connections.
+
+Peers which know its public half can use `Server::connect_pinned` to make sure that they're talking to us.*/
+    pub static_key: Option<[u8; 32]>,
+}
+
+impl Default for EncryptionConfig {
+    fn default()->EncryptionConfig {
+        EncryptionConfig{policy: EncryptionPolicy::Preferred, static_key: None}
+    }
+}
+
+impl EncryptionConfig {
+    ///Make a new secret key suitable for `static_key`.
+    pub fn generate_key()->[u8; 32] {
+        server::generate_key()
+    }
+
+    ///The public half of a secret key, which is what peers pin.
+    pub fn public_key(secret: &[u8; 32])->[u8; 32] {
+        server::public_key(secret)
+    }
+}
+
+/**Limits how much traffic on the connection channel each IP can cause.
+
+The connection channel answers anyone, so without these it could be used to flood third parties with packets.  Limits are per IP rather than per IP and port.
+The defaults are well above what a client needs to connect.*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub status_queries_per_second: u32,
+    ///How many status queries can be answered at once after a quiet period.
+    pub status_query_burst: u32,
+    pub connect_attempts_per_second: u32,
+    pub connect_attempt_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default()->RateLimitConfig {
+        RateLimitConfig {
+            status_queries_per_second: 10,
+            status_query_burst: 20,
+            connect_attempts_per_second: 5,
+            connect_attempt_burst: 10,
+        }
+    }
+}
+
+/**Caps how fast data is sent, in bytes a second.
+
+`None` means no cap.  The per-connection cap applies to each connection separately, and the total cap to all of them together.
+Messages which are over budget wait until there's room, except that unreliable messages are dropped instead if `drop_unreliable` is set; this is usually what's wanted for data which is soon out of date.
+Only data packets count, including resends.  Acks, heartbeats, and the like always go out, since connections break without them.*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BandwidthConfig {
+    pub per_connection_bytes_per_second: Option<u32>,
+    ///How many bytes a connection can send at once after a quiet period.
+    pub per_connection_burst: u32,
+    pub total_bytes_per_second: Option<u32>,
+    pub total_burst: u32,
+    pub drop_unreliable: bool,
+}
+
+impl Default for BandwidthConfig {
+    fn default()->BandwidthConfig {
+        BandwidthConfig {
+            per_connection_bytes_per_second: None,
+            per_connection_burst: 16000,
+            total_bytes_per_second: None,
+            total_burst: 64000,
+            drop_unreliable: false,
+        }
+    }
+}
+
+/**Configures the `fastnet_compression` extension.
+
+Compression is only used if both sides enable it.  Each side then decides for itself which channels to compress.
+The default is disabled, since every message costs an extra byte once it's on.*/
+#[derive(Debug, Clone, Default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    ///The channels to compress messages on.  Messages on other channels are sent as they are.
+    pub channels: collections::HashMap<u16, ChannelCompression>,
+    /**Dictionaries, by id.
+
+A dictionary of data like what's being sent helps a lot with small messages.  It's only used if the peer has a dictionary with the same id and contents.  At most 64 are used.*/
+    pub dictionaries: collections::HashMap<u32, Vec<u8>>,
+}
+
+///How to compress the messages on a channel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChannelCompression {
+    ///Messages smaller than this many bytes aren't worth compressing.
+    pub threshold: usize,
+    ///The id of the dictionary to use, if any.
+    pub dictionary: Option<u32>,
+}
+
+impl Default for ChannelCompression {
+    fn default()->ChannelCompression {
+        ChannelCompression{threshold: 128, dictionary: None}
+    }
+}
+
+/**How a channel delivers the messages it receives.  See `Server::configure_channel_mode`.*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelMode {
+    ///Messages are delivered in the order they were sent.  This is the default.
+    Ordered,
+    /**Only the newest unreliable message matters.
+
+Unreliable messages older than one which was already delivered are thrown away, as are pieces of older ones which were still arriving.  Reliable messages are delivered as on ordered channels.*/
+    LatestOnly,
+    /**Unreliable messages don't wait for reliable ones.
+
+Normally an unreliable message isn't delivered until every reliable message sent before it on the channel has been, so a lost reliable packet holds up everything behind it.
+In this mode unreliable messages are delivered as soon as they arrive, while reliable messages are still delivered in order.*/
+    UnreliableBypass,
+    /**Messages are delivered as soon as they arrive, in any order.
+
+A lost packet only holds up the message it belongs to.  Reliable messages are still delivered exactly once, but streaming doesn't apply.
+This changes what's sent as well as how it's received, so both sides must configure the same channels this way; connections between peers which don't fail with `Error::ChannelModeMismatch`.*/
+    ReliableUnordered,
+}
+
+impl Default for ChannelMode {
+    fn default()->ChannelMode {
+        ChannelMode::Ordered
+    }
+}
+
+///Counters describing what a server has done, from `Server::stats`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    ///Status queries which weren't answered, either because of the rate limit or because the answer would have been much larger than the query.
+    pub status_queries_dropped: u64,
+    ///Connect packets which were ignored because of the rate limit.
+    pub connect_attempts_dropped: u64,
+    ///Unreliable messages which were dropped because they were over the bandwidth cap.  See `BandwidthConfig::drop_unreliable`.
+    pub unreliable_messages_dropped: u64,
+}
+
+/**Identifies a sent message in the handler's `message_acked` and `message_failed`.
+
+Handles are unique per server.*/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageHandle(pub u64);
+
+///Which way an asset transfer is going.  Transfer ids are chosen by the sender, so the same id can be in use in both directions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+///An asset which a peer wants to send us.  See `Server::accept_asset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetOffer {
+    pub transfer: u64,
+    ///Chosen by the sender.  This is not a safe path.
+    pub name: String,
+    pub size: u64,
+    ///The SHA-256 hash of the asset.
+    pub hash: [u8; 32],
+}
+
 /**A Fastnet server.
 
-Fastnet does not distinguish between clients and servers.  This is used both for connecting to other peers and listening for incoming connections.*/
-pub struct Server<H: Handler+Send+'static> {
+Fastnet does not distinguish between clients and servers.  This is used both for connecting to other peers and listening for incoming connections.
+
+A server either runs in a background thread (see `new`) or on the thread of its owner (see `new_polled`).*/
+pub struct Server<H: Handler> {
     server: server::MioServer<H>,
+    next_message_handle: u64,
 }
 
 impl<H: Handler+Send+'static> Server<H> {
+    /**Make a server which runs in a background thread.
+
+The handler is moved to the background thread, and all callbacks happen there.*/
     pub fn new(addr: net::SocketAddr, handler: H)->Result<Server<H>> {
         let s = try!(server::MioServer::new(addr, handler).map_err(Error::IoError));
-        Ok(Server{server: s})
+        Ok(Server{server: s, next_message_handle: 0})
+    }
+}
+
+impl<H: Handler> Server<H> {
+    /**Make a server which runs on the caller's thread.
+
+No background thread is spawned.  Nothing happens until `poll` or `service` is called, and the handler's callbacks are called from inside them.  This is intended for applications with their own main loop, i.e. games.*/
+    pub fn new_polled(addr: net::SocketAddr, handler: H)->Result<Server<H>> {
+        let s = try!(server::MioServer::new_polled(addr, handler).map_err(Error::IoError));
+        Ok(Server{server: s, next_message_handle: 0})
+    }
+
+    /**Run the server, waiting at most `timeout_ms` for something to happen.
+
+This processes incoming packets, fires timers, and calls the handler.  It returns `Error::NotPolled` if the server was made with `new`.*/
+    pub fn poll(&mut self, timeout_ms: u64)->Result<()> {
+        if self.server.is_polled() == false {return Err(Error::NotPolled);}
+        self.server.poll(timeout_ms)
+    }
+
+    /**Do any pending work without blocking.
+
+Equivalent to `poll(0)`.  Call this once per frame or more often.  Fastnet's timers have a resolution of 200 MS, so calling it less often than that will delay packets.*/
+    pub fn service(&mut self)->Result<()> {
+        self.poll(0)
     }
 
     /**Schedule a connection request.
 
 This will cause the associated handler to be passed the result with the specified request ID.*/
-    pub fn connect(&mut self, addr: net::SocketAddr, request_id: u64) {
-        self.server.with(move |s| s.connect(addr, request_id));
+    pub fn connect(&mut self, addr: net::SocketAddr, request_id: u64)->Result<()> {
+        self.server.with(move |s| s.connect(addr, request_id, None))
+    }
+
+    /**Schedule a connection request to a server with a known static key.
+
+The connection is always encrypted, and fails with `Error::KeyMismatch` if the server can't prove that it has the secret half of `server_key`.*/
+    pub fn connect_pinned(&mut self, addr: net::SocketAddr, request_id: u64, server_key: [u8; 32])->Result<()> {
+        self.server.with(move |s| s.connect(addr, request_id, Some(server_key)))
+    }
+
+    fn next_handle(&mut self)->MessageHandle {
+        self.next_message_handle += 1;
+        MessageHandle(self.next_message_handle)
+    }
+
+    /**Send a message to a peer.
+
+Reliable messages are resent until they arrive, and are delivered in the order they were sent on their channel.  Unreliable messages may not arrive at all.
+The handler's `message_acked` is called with the returned handle once the peer has acknowledged all of a reliable message, or `message_failed` if that never happens.
+Unreliable messages are only reported on channels with receipts, see `configure_unreliable_receipts`.*/
+    pub fn send_message(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8], reliable: bool)->Result<MessageHandle> {
+        self.send_message_with_header(id, channel, MessageHeader::default(), payload, reliable)
+    }
+
+    /**Send a message with a header, which the peer gets in `Handler::incoming_message_with_header`.
+
+Peers which don't support headers get just the payload.  Fails with `Error::InvalidHeader` if `header.rpc` is set; use `request` and `respond` instead.*/
+    pub fn send_message_with_header(&mut self, id: uuid::Uuid, channel: u16, header: MessageHeader, payload: &[u8], reliable: bool)->Result<MessageHandle> {
+        if header.rpc.is_some() {return Err(Error::InvalidHeader);}
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        if payload.len()+header.encoded_len() > MAX_MESSAGE_SIZE {return Err(Error::MessageTooLarge);}
+        let payload = payload.to_vec();
+        let handle = self.next_handle();
+        try!(self.server.with(move |s| {
+            if let Err(e) = s.send_message(id, channel, &header, &payload, reliable, Some(handle)) {debug!("Couldn't send to {}: {:?}", id, e);}
+        }));
+        Ok(handle)
+    }
+
+    /**Serialize `message` and send it on the channel it's registered to, with its type id in the header.
+
+Only available with the `serde` feature.  The peer decodes it with a `TypedDispatcher`.  Fails with `Error::UnregisteredType` if the type isn't in `types`.*/
+    #[cfg(feature = "serde")]
+    pub fn send_typed<T: serde::Serialize+'static>(&mut self, id: uuid::Uuid, types: &TypeRegistry, message: &T, reliable: bool)->Result<MessageHandle> {
+        let (channel, header, payload) = try!(types.encode(message));
+        self.send_message_with_header(id, channel, header, &payload, reliable)
+    }
+
+    /**Send a request, which the peer answers from `Handler::incoming_request` or with `respond`.
+
+The request is a reliable message on `channel`, ordered with the rest of the channel's messages, and the response comes back on the same channel.
+The handler's `response_received` is called with the returned handle and the response.  Otherwise `response_failed` is called with `Error::TimedOut` if the response doesn't come within `timeout_ms`, `Error::ConnectionAborted` if the connection closes first, or `Error::IncompatibleVersions` if the peer doesn't support message headers.
+Requests and responses are delivered whole even if the peer turns on streaming for `channel`.*/
+    pub fn request(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8], timeout_ms: u64)->Result<MessageHandle> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        let header = MessageHeader{rpc: Some(Rpc::Request(0)), ..MessageHeader::default()};
+        if payload.len()+header.encoded_len() > MAX_MESSAGE_SIZE {return Err(Error::MessageTooLarge);}
+        let payload = payload.to_vec();
+        let timeout = time::Duration::from_millis(timeout_ms);
+        let handle = self.next_handle();
+        try!(self.server.with(move |s| s.send_request(id, channel, &payload, timeout, handle)));
+        Ok(handle)
+    }
+
+    /**Answer a request which `Handler::incoming_request` didn't answer right away.
+
+`channel` and `request_id` are the ones the handler was given.*/
+    pub fn respond(&mut self, id: uuid::Uuid, channel: u16, request_id: u64, payload: &[u8])->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        let header = MessageHeader{rpc: Some(Rpc::Response(request_id)), ..MessageHeader::default()};
+        if payload.len()+header.encoded_len() > MAX_MESSAGE_SIZE {return Err(Error::MessageTooLarge);}
+        let payload = payload.to_vec();
+        self.server.with(move |s| {
+            if let Err(e) = s.send_response(id, channel, request_id, &payload) {debug!("Couldn't respond to {}: {:?}", id, e);}
+        })
+    }
+
+    ///Stop waiting for the response to a request.  The handler isn't told anything more about it.
+    pub fn cancel_request(&mut self, id: uuid::Uuid, handle: MessageHandle)->Result<()> {
+        self.server.with(move |s| s.cancel_request(id, handle))
+    }
+
+    /**Send a reliable message which is only worth delivering for `ttl_ms` milliseconds.
+
+If the peer hasn't acknowledged all of it by then, it stops being resent, the peer is told to skip it so that later messages on the channel aren't held up, and the handler's `message_failed` is called with `Error::MessageExpired`.
+An expired message may still have arrived.*/
+    pub fn send_message_with_ttl(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8], ttl_ms: u64)->Result<MessageHandle> {
+        self.send_message_with_header_and_ttl(id, channel, MessageHeader::default(), payload, ttl_ms)
+    }
+
+    /**Send a reliable message with a header which is only worth delivering for `ttl_ms` milliseconds.
+
+This is `send_message_with_ttl` with a header, as for `send_message_with_header`.  Fails with `Error::InvalidHeader` if `header.rpc` is set.*/
+    pub fn send_message_with_header_and_ttl(&mut self, id: uuid::Uuid, channel: u16, header: MessageHeader, payload: &[u8], ttl_ms: u64)->Result<MessageHandle> {
+        if header.rpc.is_some() {return Err(Error::InvalidHeader);}
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        if payload.len()+header.encoded_len() > MAX_MESSAGE_SIZE {return Err(Error::MessageTooLarge);}
+        let payload = payload.to_vec();
+        let ttl = time::Duration::from_millis(ttl_ms);
+        let handle = self.next_handle();
+        try!(self.server.with(move |s| s.send_message_with_ttl(id, channel, &header, &payload, ttl, handle)));
+        Ok(handle)
+    }
+
+    /**Send a reliable message read from `reader`, without holding all of it in memory.
+
+`length` is how many bytes will be read, and can't be more than `MAX_MESSAGE_SIZE`.  The reader is moved to the server's thread and read as the connection has room.
+Messages sent on the same channel afterwards wait for this one, so ordering works as for `send_message`.
+If reading fails or ends early, the rest of the message is zeros, since the peer already knows how long it is, and the handler's `request_failed` is called with `request_id`.
+Peers receive this like any other reliable message unless they turn on streaming for the channel with `configure_streaming`.*/
+    pub fn send_stream<R: io::Read+Send+'static>(&mut self, id: uuid::Uuid, channel: u16, reader: R, length: u64, request_id: u64)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        if length > MAX_MESSAGE_SIZE as u64 {return Err(Error::MessageTooLarge);}
+        self.server.with(move |s| s.send_stream(id, channel, Box::new(reader), length, request_id))
+    }
+
+    /**Offer a file to a peer.
+
+`transfer` identifies the transfer in the handler's asset callbacks and is chosen by the caller; it must not already be in use for an outgoing transfer to this peer.
+The file is read as the connection has room, so it can be much larger than memory.  Offering a file which was only partly sent before a disconnect lets the peer resume where it left off.
+This hashes the whole file before returning, so it may take a while for large files.*/
+    pub fn offer_asset(&mut self, id: uuid::Uuid, transfer: u64, path: &path::Path, name: &str)->Result<()> {
+        let mut file = try!(fs::File::open(path).map_err(Error::IoError));
+        let (size, hash) = try!(server::hash_file(&mut file).map_err(Error::IoError));
+        try!(io::Seek::seek(&mut file, io::SeekFrom::Start(0)).map_err(Error::IoError));
+        let name = name.to_string();
+        self.server.with(move |s| s.offer_asset(id, transfer, file, size, hash, name))
+    }
+
+    /**Accept an offered asset, writing it to `path`.
+
+If `path` already holds the start of the asset, for instance from a transfer which was interrupted, only the rest is sent.
+This hashes what's already there before returning, so it may take a while.  The result is reported to the handler's `asset_completed` or `asset_failed`.*/
+    pub fn accept_asset(&mut self, id: uuid::Uuid, offer: &AssetOffer, path: &path::Path)->Result<()> {
+        let mut file = try!(fs::OpenOptions::new().read(true).write(true).create(true).open(path).map_err(Error::IoError));
+        let existing = try!(file.metadata().map_err(Error::IoError)).len();
+        //Something longer than the asset can't be the start of it.
+        if existing > offer.size {try!(file.set_len(0).map_err(Error::IoError));}
+        let mut hasher = Sha256::new();
+        let offset = try!(server::hash_prefix(&mut file, offer.size, &mut hasher).map_err(Error::IoError));
+        let transfer = offer.transfer;
+        self.server.with(move |s| s.accept_asset(id, transfer, file, offset, hasher))
+    }
+
+    /**Refuse an offered asset, or stop a transfer in either direction.
+
+The peer's handler gets `asset_failed` with `Error::TransferRejected`.  Partly received files are left on disk so that the transfer can be resumed later.*/
+    pub fn cancel_asset(&mut self, id: uuid::Uuid, direction: TransferDirection, transfer: u64)->Result<()> {
+        self.server.with(move |s| s.cancel_asset(id, direction, transfer))
+    }
+
+    /**Disconnect from a peer with the specified ID.
+
+Reliable messages which were already sent are given a chance to arrive first.  The handler's `disconnected` is called with `request_id` once the peer acknowledges or the attempt times out.*/
+    pub fn disconnect(&mut self, id: uuid::Uuid, request_id: u64)->Result<()> {
+        self.server.with(move |s| s.disconnect(id, request_id))
+    }
+
+    /**Stop the server and get the handler back.
+
+If `close_timeout_ms` is provided, every connection is closed gracefully: outstanding reliable messages get a chance to arrive and peers are told that we're going away.  This waits at most `close_timeout_ms`.
+Otherwise, the server stops immediately and peers only find out when they time out.
+
+The handler is told about every connection which ends, including those which were still open when the server stopped.
+Dropping a server is the same as calling this with `None` and dropping the handler.*/
+    pub fn shutdown(self, close_timeout_ms: Option<u64>)->H {
+        self.server.shutdown(close_timeout_ms.map(time::Duration::from_millis))
+    }
+
+    /**Configure encryption.
+
+This only affects connections made afterwards, so call it right after making the server.*/
+    pub fn configure_encryption(&mut self, config: EncryptionConfig)->Result<()> {
+        self.server.with(move |s| s.configure_encryption(config))
+    }
+
+    /**Configure compression.
+
+This only affects connections made afterwards, so call it right after making the server.*/
+    pub fn configure_compression(&mut self, config: CompressionConfig)->Result<()> {
+        self.server.with(move |s| s.configure_compression(config))
+    }
+
+    /**Replace the congestion controller.
+
+`factory` is called once for each new connection, so this only affects connections made afterwards.  The default is `NewRenoController`.*/
+    pub fn configure_congestion_control<F: Fn()->Box<CongestionController>+Send+'static>(&mut self, factory: F)->Result<()> {
+        self.server.with(move |s| s.configure_congestion_control(Box::new(factory)))
+    }
+
+    /**Configure the rate limits for status queries and connection attempts.*/
+    pub fn configure_rate_limits(&mut self, config: RateLimitConfig)->Result<()> {
+        self.server.with(move |s| s.configure_rate_limits(config))
+    }
+
+    /**Configure bandwidth caps.
+
+This applies to existing connections as well as new ones.*/
+    pub fn configure_bandwidth(&mut self, config: BandwidthConfig)->Result<()> {
+        self.server.with(move |s| s.configure_bandwidth(config))
+    }
+
+    /**Set the priority of a channel.
+
+When several channels have data waiting, each gets a share of what's sent in proportion to its priority.  The default is 16, and 0 counts as 1.
+For example, giving input a priority of 255 and bulk transfers a priority of 1 lets input go almost immediately, while the bulk transfers still make progress.
+This applies to existing connections as well as new ones.*/
+    pub fn configure_channel_priority(&mut self, channel: u16, priority: u8)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        self.server.with(move |s| s.configure_channel_priority(channel, priority))
+    }
+
+    /**Turn streaming on or off for incoming reliable messages on a channel.
+
+With streaming on, reliable messages on the channel go to the handler's `incoming_stream_start_with_header`, `incoming_stream_data` and `incoming_stream_end` instead of `incoming_message_with_header`.
+Data is handed over in order as soon as it arrives, so large messages never have to be held in memory.  Unreliable messages, requests and responses are still delivered whole.
+This applies to existing connections as well as new ones, starting with the next message.*/
+    pub fn configure_streaming(&mut self, channel: u16, enabled: bool)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        self.server.with(move |s| s.configure_streaming(channel, enabled))
+    }
+
+    /**Turn receipts on or off for unreliable messages sent on a channel.
+
+With receipts on, the peer reports which unreliable packets on the channel arrived, and the handler's `unreliable_delivered` or `unreliable_lost` is called with the handle from `send_message` for each unreliable message.
+Delivered means every packet of the message reached the peer, not that the peer's handler saw it; `ChannelMode::LatestOnly` can still drop it there.
+A message is lost if it was dropped for the bandwidth caps, if no receipt came within a roundtrip or so, or if the connection closed first.  Lost messages may still have arrived.
+This applies to existing connections as well as new ones, starting with the next message.*/
+    pub fn configure_unreliable_receipts(&mut self, channel: u16, enabled: bool)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        self.server.with(move |s| s.configure_unreliable_receipts(channel, enabled))
+    }
+
+    /**Set how a channel delivers the messages it receives.
+
+`ChannelMode::ReliableUnordered` is agreed with the peer when connecting, so changing a channel to or from it only affects connections made afterwards.
+Other modes only affect what this side receives, and apply to existing connections as well as new ones.*/
+    pub fn configure_channel_mode(&mut self, channel: u16, mode: ChannelMode)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        self.server.with(move |s| s.configure_channel_mode(channel, mode))
+    }
+
+    /**Get the server's counters.
+
+For threaded servers, this waits for the background thread to answer.*/
+    pub fn stats(&mut self)->Result<Stats> {
+        let (sender, receiver) = mpsc::channel();
+        try!(self.server.with(move |s| {let _ = sender.send(s.stats());}));
+        receiver.recv().map_err(|_| Error::ServerStopped)
+    }
+
+    /**Write every datagram the server sends or receives to a pcap file at `path`, replacing any capture in progress.
+
+Datagrams are written with made-up IP and UDP headers, so any pcap tool can open the file, and `fastnet-dump` can decode it.  Encrypted packets are captured encrypted.
+The file is flushed every second.  If writing fails, capturing stops.*/
+    pub fn start_capture(&mut self, path: &path::Path)->Result<()> {
+        let file = try!(fs::File::create(path).map_err(Error::IoError));
+        let writer: Box<io::Write+Send> = Box::new(io::BufWriter::new(file));
+        let writer = try!(PcapWriter::new(writer).map_err(Error::IoError));
+        self.server.with(move |s| s.start_capture(writer))
     }
 
-    /**Disconnect from a peer with the specified ID.*/
-    pub fn disconnect(&mut self, id: uuid::Uuid, request_id: u64) {
-        self.server.with(move |s| s.disconnect(id, request_id));
+    ///Stop capturing and finish writing the file.
+    pub fn stop_capture(&mut self)->Result<()> {
+        self.server.with(move |s| s.stop_capture())
     }
 
     /**Configure the timeout.
     The value to this function is in MS.  Most applications should leave this alone.  The default of 10 seconds is sufficient.*/
-    pub fn configure_timeout(&mut self, timeout_ms: u64) {
-        self.server.with(move |s| s.configure_timeout(timeout_ms));
+    pub fn configure_timeout(&mut self, timeout_ms: u64)->Result<()> {
+        self.server.with(move |s| s.configure_timeout(timeout_ms))
     }
 }
 
 /**An event handler.
 
-The methods in this trait are called in a thread which is running in the background, not on the main thread.  None of them should ever block.*/
+For servers made with `Server::new`, the methods in this trait are called in a thread which is running in the background, not on the main thread.
+For servers made with `Server::new_polled`, they are called from inside `Server::poll` and `Server::service`.
+None of them should ever block.*/
 pub trait Handler {
     fn connected(&mut self, id: uuid::Uuid, request_id: Option<u64>) {
     }
@@ -62,14 +579,109 @@ pub trait Handler {
     fn incoming_message(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8]) {
     }
 
+    /**A message arrived, with its header.
+
+Messages from peers which don't support headers, or which were sent without one, have the default header.  By default, this calls `incoming_message`.*/
+    fn incoming_message_with_header(&mut self, id: uuid::Uuid, channel: u16, header: &MessageHeader, payload: &[u8]) {
+        self.incoming_message(id, channel, payload);
+    }
+
     fn request_failed(&mut self, request_id: u64, error: Error) {
     }
 
+    /**A peer sent a request with `Server::request`.
+
+Return the response to send it right away, or None to answer later with `Server::respond`.  By default, requests aren't answered, so they time out.*/
+    fn incoming_request(&mut self, id: uuid::Uuid, channel: u16, request_id: u64, payload: &[u8])->Option<Vec<u8>> {
+        None
+    }
+
+    ///The response to a request sent with `Server::request`.
+    fn response_received(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle, payload: &[u8]) {
+    }
+
+    ///A request sent with `Server::request` won't get a response.
+    fn response_failed(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle, error: Error) {
+    }
+
+    ///The peer acknowledged every packet of a reliable message.
+    fn message_acked(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle) {
+    }
+
+    /**A reliable message will never be acknowledged.
+
+This is `Error::MessageExpired` for messages whose time to live ran out, `Error::ConnectionAborted` if the connection went away first, and `Error::PeerNotFound` if there was no connection to send it on.*/
+    fn message_failed(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle, error: Error) {
+    }
+
+    ///The peer received every packet of an unreliable message.  See `Server::configure_unreliable_receipts`.
+    fn unreliable_delivered(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle) {
+    }
+
+    ///An unreliable message probably didn't reach the peer.  See `Server::configure_unreliable_receipts`.
+    fn unreliable_lost(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle) {
+    }
+
+    /**A reliable message of `length` bytes started arriving on a streaming channel.  See `Server::configure_streaming`.*/
+    fn incoming_stream_start(&mut self, id: uuid::Uuid, channel: u16, length: u64) {
+    }
+
+    /**A message started arriving on a streaming channel, with its header.
+
+Messages from peers which don't support headers, or which were sent without one, have the default header.  By default, this calls `incoming_stream_start`.*/
+    fn incoming_stream_start_with_header(&mut self, id: uuid::Uuid, channel: u16, header: &MessageHeader, length: u64) {
+        self.incoming_stream_start(id, channel, length);
+    }
+
+    ///The next piece of the message being streamed on a channel.
+    fn incoming_stream_data(&mut self, id: uuid::Uuid, channel: u16, data: &[u8]) {
+    }
+
+    /**The message being streamed on a channel ended.
+
+If `complete` is false, the message was bad or the connection went away, and what was received of it should be thrown away.*/
+    fn incoming_stream_end(&mut self, id: uuid::Uuid, channel: u16, complete: bool) {
+    }
+
     /**Fastnet has completed a roundtrip estimate for a peer.
 
 The time provided to this function is in milliseconds.*/
     fn roundtrip_estimate(&mut self, id: uuid::Uuid, estimate: u32) {
     }
+
+    /**A peer's address changed, probably because of NAT rebinding.
+
+The connection continues as before.  Fastnet only reports this once the peer has proven that it's at the new address.*/
+    fn peer_address_changed(&mut self, id: uuid::Uuid, old_address: net::SocketAddr, new_address: net::SocketAddr) {
+    }
+
+    /**Fastnet hit an error it couldn't recover from.
+
+The server has stopped.  Every connection is reported as disconnected after this is called, and further calls on the server fail with `Error::ServerStopped`.*/
+    fn server_error(&mut self, error: Error) {
+    }
+
+    /**A peer offered us an asset.
+
+Call `Server::accept_asset` or `Server::cancel_asset` to answer.  Until then the offer waits, and a peer with too many waiting offers has the rest refused.*/
+    fn asset_offered(&mut self, id: uuid::Uuid, offer: &AssetOffer) {
+    }
+
+    /**An asset transfer made progress.
+
+For incoming transfers, `bytes` is how much has been received and checked.  For outgoing ones it's how much has been sent, which may not have arrived yet.*/
+    fn asset_progress(&mut self, id: uuid::Uuid, direction: TransferDirection, transfer: u64, bytes: u64, total: u64) {
+    }
+
+    ///An asset transfer finished, and the receiver checked the hash.
+    fn asset_completed(&mut self, id: uuid::Uuid, direction: TransferDirection, transfer: u64) {
+    }
+
+    /**An asset transfer failed.
+
+Transfers fail with `Error::ConnectionAborted` when the connection goes away.  Offer the asset again after reconnecting to resume.*/
+    fn asset_failed(&mut self, id: uuid::Uuid, direction: TransferDirection, transfer: u64, error: Error) {
+    }
 }
 
 ///This will go away.
@@ -102,4 +714,12 @@ impl Handler for PrintingHandler {
     fn roundtrip_estimate(&mut self, id: uuid::Uuid, estimate: u32) {
         println!("Roundtrip estimate: {:?} {:?}", id, estimate);
     }
+
+    fn peer_address_changed(&mut self, id: uuid::Uuid, old_address: net::SocketAddr, new_address: net::SocketAddr) {
+        println!("Address changed: {:?} {:?} {:?}", id, old_address, new_address);
+    }
+
+    fn server_error(&mut self, error: Error) {
+        println!("Server error: {:?}", error);
+    }
 }
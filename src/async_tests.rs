@@ -0,0 +1,359 @@
+/*! Tests which run real servers over loopback.
+
+Polled servers run on the test's thread, between checks for what their handlers were told, so most tests use them.  Threaded ones are polled by their own threads.*/
+use async::*;
+use std::net;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+use uuid;
+
+//How long to wait for something before failing the test.
+const WAIT_MS: u64 = 5000;
+
+///What a `RecordingHandler` was told.  Errors are kept as their debug output, since they can't be compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Connected(uuid::Uuid),
+    Disconnected(uuid::Uuid),
+    Message(u16, Vec<u8>),
+    UnreliableLost(MessageHandle),
+    Request(u16, u64, Vec<u8>),
+    Response(MessageHandle, Vec<u8>),
+    ResponseFailed(MessageHandle, String),
+    ServerError(String),
+    StreamStart(u16, MessageHeader, u64),
+    StreamData(u16, Vec<u8>),
+    StreamEnd(u16, bool),
+}
+
+///Sends everything it hears about to the test.
+pub struct RecordingHandler {
+    events: mpsc::Sender<Event>,
+    //If set, requests are answered right away with their payload reversed.  Otherwise the test answers them.
+    answer_requests: bool,
+}
+
+impl RecordingHandler {
+    pub fn new(events: mpsc::Sender<Event>, answer_requests: bool)->RecordingHandler {
+        RecordingHandler{events: events, answer_requests: answer_requests}
+    }
+
+    fn record(&mut self, event: Event) {
+        //The test may have stopped listening, i.e. while it shuts down.
+        let _ = self.events.send(event);
+    }
+}
+
+impl Handler for RecordingHandler {
+    fn connected(&mut self, id: uuid::Uuid, request_id: Option<u64>) {
+        self.record(Event::Connected(id));
+    }
+
+    fn disconnected(&mut self, id: uuid::Uuid, request_id: Option<u64>) {
+        self.record(Event::Disconnected(id));
+    }
+
+    fn incoming_message(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8]) {
+        self.record(Event::Message(channel, payload.to_vec()));
+    }
+
+    fn unreliable_lost(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle) {
+        self.record(Event::UnreliableLost(handle));
+    }
+
+    fn incoming_stream_start_with_header(&mut self, id: uuid::Uuid, channel: u16, header: &MessageHeader, length: u64) {
+        self.record(Event::StreamStart(channel, *header, length));
+    }
+
+    fn incoming_stream_data(&mut self, id: uuid::Uuid, channel: u16, data: &[u8]) {
+        self.record(Event::StreamData(channel, data.to_vec()));
+    }
+
+    fn incoming_stream_end(&mut self, id: uuid::Uuid, channel: u16, complete: bool) {
+        self.record(Event::StreamEnd(channel, complete));
+    }
+
+    fn server_error(&mut self, error: Error) {
+        self.record(Event::ServerError(format!("{:?}", error)));
+    }
+
+    fn incoming_request(&mut self, id: uuid::Uuid, channel: u16, request_id: u64, payload: &[u8])->Option<Vec<u8>> {
+        self.record(Event::Request(channel, request_id, payload.to_vec()));
+        if self.answer_requests == false {return None;}
+        let mut response = payload.to_vec();
+        response.reverse();
+        Some(response)
+    }
+
+    fn response_received(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle, payload: &[u8]) {
+        self.record(Event::Response(handle, payload.to_vec()));
+    }
+
+    fn response_failed(&mut self, id: uuid::Uuid, channel: u16, handle: MessageHandle, error: Error) {
+        self.record(Event::ResponseFailed(handle, format!("{:?}", error)));
+    }
+}
+
+//An address nothing is using.  Something else could take it before we bind it, but that's unlikely on loopback.
+pub fn free_address()->net::SocketAddr {
+    let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+///A server and what its handler reports.
+pub struct Peer {
+    pub server: Server<RecordingHandler>,
+    pub events: mpsc::Receiver<Event>,
+    pub address: net::SocketAddr,
+}
+
+impl Peer {
+    pub fn polled()->Peer {
+        let (sender, receiver) = mpsc::channel();
+        let address = free_address();
+        let server = Server::new_polled(address, RecordingHandler::new(sender, false)).unwrap();
+        Peer{server: server, events: receiver, address: address}
+    }
+
+    ///A polled peer which answers requests itself.
+    pub fn answering()->Peer {
+        let (sender, receiver) = mpsc::channel();
+        let address = free_address();
+        let server = Server::new_polled(address, RecordingHandler::new(sender, true)).unwrap();
+        Peer{server: server, events: receiver, address: address}
+    }
+
+    pub fn threaded()->Peer {
+        let (sender, receiver) = mpsc::channel();
+        let address = free_address();
+        let server = Server::new(address, RecordingHandler::new(sender, false)).unwrap();
+        Peer{server: server, events: receiver, address: address}
+    }
+}
+
+//Run both peers for a bit.  Threaded peers run themselves, so we just give them time.
+fn step(a: &mut Peer, b: &mut Peer) {
+    let polled = a.server.poll(1).is_ok() | b.server.poll(1).is_ok();
+    if polled == false {thread::sleep(time::Duration::from_millis(1));}
+}
+
+/**Run both peers until `a` reports an event `want` accepts, and return it.
+
+Events before it are discarded.  Panics if nothing matches in time.*/
+pub fn wait_for<F: Fn(&Event)->bool>(a: &mut Peer, b: &mut Peer, want: F)->Event {
+    let deadline = time::Instant::now()+time::Duration::from_millis(WAIT_MS);
+    while time::Instant::now() < deadline {
+        step(a, b);
+        while let Ok(e) = a.events.try_recv() {
+            if want(&e) {return e;}
+        }
+    }
+    panic!("Timed out waiting for an event");
+}
+
+///Run both peers for `ms` and return everything `a` reported.
+pub fn events_during(a: &mut Peer, b: &mut Peer, ms: u64)->Vec<Event> {
+    let deadline = time::Instant::now()+time::Duration::from_millis(ms);
+    while time::Instant::now() < deadline {step(a, b);}
+    a.events.try_iter().collect()
+}
+
+///Connect `a` to `b` and return the connection's id, which both sides share.
+pub fn connect(a: &mut Peer, b: &mut Peer)->uuid::Uuid {
+    let address = b.address;
+    a.server.connect(address, 1).unwrap();
+    let id = match wait_for(a, b, |e| if let Event::Connected(_) = *e {true} else {false}) {
+        Event::Connected(id) => id,
+        _ => unreachable!(),
+    };
+    wait_for(b, a, |e| *e == Event::Connected(id));
+    id
+}
+
+#[test]
+fn test_unreliable_over_burst_on_idle_connection() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    client.server.configure_bandwidth(BandwidthConfig{per_connection_bytes_per_second: Some(1000000), drop_unreliable: true, ..BandwidthConfig::default()}).unwrap();
+    let id = connect(&mut client, &mut server);
+    //Bigger than the default burst of 16000, so it can never fit in the bucket all at once.
+    let payload = vec![7u8; 20000];
+    client.server.send_message(id, 3, &payload, false).unwrap();
+    assert_eq!(wait_for(&mut server, &mut client, |e| if let Event::Message(..) = *e {true} else {false}), Event::Message(3, payload));
+}
+
+#[test]
+fn test_caller_set_rpc_header_rejected() {
+    let mut peer = Peer::polled();
+    let header = MessageHeader{rpc: Some(Rpc::Response(1)), ..MessageHeader::default()};
+    assert!(match peer.server.send_message_with_header(uuid::Uuid::new_v4(), 0, header, b"x", true) {Err(Error::InvalidHeader) => true, _ => false});
+    assert!(match peer.server.send_message_with_header_and_ttl(uuid::Uuid::new_v4(), 0, header, b"x", 1000) {Err(Error::InvalidHeader) => true, _ => false});
+}
+
+fn is_request(e: &Event)->bool {
+    if let Event::Request(..) = *e {true} else {false}
+}
+
+//The response or failure for `handle`.
+fn is_answer(e: &Event, handle: MessageHandle)->bool {
+    match *e {
+        Event::Response(h, _) | Event::ResponseFailed(h, _) => h == handle,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_request_answered_by_handler() {
+    let mut client = Peer::polled();
+    let mut server = Peer::answering();
+    let id = connect(&mut client, &mut server);
+    let first = client.server.request(id, 4, b"abc", 5000).unwrap();
+    let second = client.server.request(id, 4, b"hello", 5000).unwrap();
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, first)), Event::Response(first, b"cba".to_vec()));
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, second)), Event::Response(second, b"olleh".to_vec()));
+}
+
+#[test]
+fn test_responses_matched_by_id() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let id = connect(&mut client, &mut server);
+    let first = client.server.request(id, 4, b"first", 5000).unwrap();
+    let second = client.server.request(id, 4, b"second", 5000).unwrap();
+    let mut requests = Vec::default();
+    for _ in 0..2 {
+        if let Event::Request(channel, request_id, payload) = wait_for(&mut server, &mut client, is_request) {requests.push((channel, request_id, payload));}
+    }
+    assert_eq!((requests[0].0, &requests[0].2[..], requests[1].0, &requests[1].2[..]), (4, &b"first"[..], 4, &b"second"[..]));
+    //Answering out of order still reaches the right requests.
+    server.server.respond(id, 4, requests[1].1, b"to second").unwrap();
+    server.server.respond(id, 4, requests[0].1, b"to first").unwrap();
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, second)), Event::Response(second, b"to second".to_vec()));
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, first)), Event::Response(first, b"to first".to_vec()));
+}
+
+#[test]
+fn test_request_timeout() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let id = connect(&mut client, &mut server);
+    let handle = client.server.request(id, 0, b"anyone?", 100).unwrap();
+    let request_id = match wait_for(&mut server, &mut client, is_request) {
+        Event::Request(_, r, _) => r,
+        _ => unreachable!(),
+    };
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, handle)), Event::ResponseFailed(handle, "TimedOut".to_string()));
+    //Answering too late does nothing.
+    server.server.respond(id, 0, request_id, b"sorry").unwrap();
+    assert!(events_during(&mut client, &mut server, 300).iter().any(|e| is_answer(e, handle)) == false);
+}
+
+#[test]
+fn test_request_on_streaming_channel() {
+    let mut client = Peer::polled();
+    let mut server = Peer::answering();
+    client.server.configure_streaming(2, true).unwrap();
+    server.server.configure_streaming(2, true).unwrap();
+    let id = connect(&mut client, &mut server);
+    let handle = client.server.request(id, 2, b"streamed?", 5000).unwrap();
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, handle)), Event::Response(handle, b"?demaerts".to_vec()));
+    //Other messages on the channel are still streamed.
+    client.server.send_message(id, 2, b"plain", true).unwrap();
+    assert_eq!(wait_for(&mut server, &mut client, |e| if let Event::StreamData(..) = *e {true} else {false}), Event::StreamData(2, b"plain".to_vec()));
+}
+
+#[test]
+fn test_cancel_request() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let id = connect(&mut client, &mut server);
+    let cancelled = client.server.request(id, 0, b"never mind", 5000).unwrap();
+    let request_id = match wait_for(&mut server, &mut client, is_request) {
+        Event::Request(_, r, _) => r,
+        _ => unreachable!(),
+    };
+    client.server.cancel_request(id, cancelled).unwrap();
+    server.server.respond(id, 0, request_id, b"too late").unwrap();
+    //Responses on a channel arrive in order, so once this one is in, the late one was dropped.
+    let next = client.server.request(id, 0, b"this one", 5000).unwrap();
+    let next_id = match wait_for(&mut server, &mut client, is_request) {
+        Event::Request(_, r, _) => r,
+        _ => unreachable!(),
+    };
+    server.server.respond(id, 0, next_id, b"answered").unwrap();
+    let mut seen = Vec::default();
+    while seen.last().map_or(true, |e| is_answer(e, next) == false) {
+        seen.push(wait_for(&mut client, &mut server, |e| is_answer(e, next) || is_answer(e, cancelled)));
+    }
+    assert_eq!(seen, vec![Event::Response(next, b"answered".to_vec())]);
+}
+
+#[test]
+fn test_request_aborted_on_close() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let id = connect(&mut client, &mut server);
+    let handle = client.server.request(id, 0, b"hold on", 5000).unwrap();
+    wait_for(&mut server, &mut client, is_request);
+    client.server.disconnect(id, 2).unwrap();
+    assert_eq!(wait_for(&mut client, &mut server, |e| is_answer(e, handle)), Event::ResponseFailed(handle, "ConnectionAborted".to_string()));
+}
+
+#[test]
+fn test_polled_mode() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let address = server.address;
+    client.server.connect(address, 1).unwrap();
+    //Nothing happens until the servers are polled.
+    thread::sleep(time::Duration::from_millis(50));
+    assert!(client.events.try_recv().is_err() && server.events.try_recv().is_err());
+    let id = match wait_for(&mut client, &mut server, |e| if let Event::Connected(_) = *e {true} else {false}) {
+        Event::Connected(id) => id,
+        _ => unreachable!(),
+    };
+    client.server.send_message(id, 1, b"polled", true).unwrap();
+    //service never blocks, so the test has to keep calling it.
+    let deadline = time::Instant::now()+time::Duration::from_millis(WAIT_MS);
+    let mut received = None;
+    while received.is_none() && time::Instant::now() < deadline {
+        client.server.service().unwrap();
+        server.server.service().unwrap();
+        received = server.events.try_iter().find(|e| if let Event::Message(..) = *e {true} else {false});
+    }
+    assert_eq!(received, Some(Event::Message(1, b"polled".to_vec())));
+    let mut threaded = Peer::threaded();
+    assert!(match threaded.server.poll(0) {Err(Error::NotPolled) => true, _ => false});
+    assert!(match threaded.server.service() {Err(Error::NotPolled) => true, _ => false});
+}
+
+#[test]
+fn test_graceful_shutdown() {
+    let mut client = Peer::threaded();
+    let mut server = Peer::threaded();
+    let id = connect(&mut client, &mut server);
+    let Peer{server: client_server, events: client_events, ..} = client;
+    let mut handler = client_server.shutdown(Some(2000));
+    //The handler comes back after being told about the connection it had.
+    assert!(client_events.try_iter().any(|e| e == Event::Disconnected(id)));
+    handler.record(Event::Message(0, b"still mine".to_vec()));
+    assert_eq!(client_events.try_recv(), Ok(Event::Message(0, b"still mine".to_vec())));
+    //The peer was told, so it doesn't have to wait for a timeout.
+    assert_eq!(server.events.recv_timeout(time::Duration::from_millis(1000)), Ok(Event::Disconnected(id)));
+}
+
+#[test]
+fn test_immediate_shutdown_and_drop() {
+    let mut client = Peer::polled();
+    let mut server = Peer::polled();
+    let id = connect(&mut client, &mut server);
+    let Peer{server: client_server, events: client_events, ..} = client;
+    client_server.shutdown(None);
+    assert_eq!(client_events.try_recv(), Ok(Event::Disconnected(id)));
+    //Dropping a threaded server stops its thread, which drops the handler and frees the address.
+    let Peer{server: threaded, events: threaded_events, address} = Peer::threaded();
+    drop(threaded);
+    assert_eq!(threaded_events.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    assert!(net::UdpSocket::bind(address).is_ok());
+}
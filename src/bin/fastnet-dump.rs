@@ -0,0 +1,89 @@
+/*! Pretty-prints the Fastnet packets in pcap files.
+
+Usage: `fastnet-dump [--flat] <file.pcap>...`
+
+Packets are grouped by the pair of addresses they went between, and then by channel.  Most packets don't carry a connection id, so a connection which moved to a new address shows up as two groups.  With `--flat`, they're printed in the order they were captured instead.
+Each line has the time since the first datagram in the file, which way it went, its size, whether the checksum was valid, and the packet.*/
+#![allow(deprecated, clippy::redundant_field_names)]
+extern crate fastnet;
+
+use std::collections;
+use std::env;
+use std::fs;
+use std::io;
+use std::net;
+use std::process;
+
+struct Line {
+    channel: Option<i16>,
+    connection: (net::SocketAddr, net::SocketAddr),
+    text: String,
+}
+
+fn format_channel(channel: Option<i16>)->String {
+    match channel {
+        Some(c) => format!("channel {}", c),
+        None => "no channel".to_string(),
+    }
+}
+
+fn read_lines(path: &str)->io::Result<Vec<Line>> {
+    let file = try!(fs::File::open(path));
+    let mut reader = try!(fastnet::PcapReader::new(io::BufReader::new(file)));
+    let mut lines = Vec::new();
+    let mut start = None;
+    while let Some(datagram) = try!(reader.read_datagram()) {
+        let start = *start.get_or_insert(datagram.timestamp);
+        let elapsed = datagram.timestamp.checked_sub(start).unwrap_or_default();
+        let dissected = fastnet::dissect_datagram(&datagram.data);
+        let checksum = if dissected.checksum_valid {"ok"} else {"BAD"};
+        let text = format!("{}.{:06} {} -> {} {} bytes, checksum {}, {}: {}", elapsed.as_secs(), elapsed.subsec_micros(), datagram.source, datagram.destination, datagram.data.len(), checksum, format_channel(dissected.channel), dissected.description);
+        let connection = if datagram.source <= datagram.destination {(datagram.source, datagram.destination)} else {(datagram.destination, datagram.source)};
+        lines.push(Line{channel: dissected.channel, connection: connection, text: text});
+    }
+    Ok(lines)
+}
+
+fn print_grouped(lines: &[Line]) {
+    let mut groups: collections::BTreeMap<_, collections::BTreeMap<_, Vec<&str>>> = collections::BTreeMap::new();
+    for l in lines {
+        groups.entry(l.connection).or_insert_with(Default::default).entry(l.channel).or_insert_with(Default::default).push(&l.text);
+    }
+    for (connection, channels) in groups {
+        println!("{} <-> {}", connection.0, connection.1);
+        for (channel, texts) in channels {
+            println!("  {}", format_channel(channel));
+            for t in texts {println!("    {}", t);}
+        }
+    }
+}
+
+fn main() {
+    let mut flat = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        if arg == "--flat" {flat = true;}
+        else {paths.push(arg);}
+    }
+    if paths.is_empty() {
+        eprintln!("Usage: fastnet-dump [--flat] <file.pcap>...");
+        process::exit(2);
+    }
+    let mut failed = false;
+    for path in paths {
+        let lines = match read_lines(&path) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            },
+        };
+        println!("{}: {} datagrams", path, lines.len());
+        if flat {
+            for l in &lines {println!("  {}", l.text);}
+        }
+        else {print_grouped(&lines);}
+    }
+    if failed {process::exit(1);}
+}
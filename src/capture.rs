@@ -0,0 +1,393 @@
+/*! Packet capture in the pcap format.
+
+`Server::start_capture` writes every datagram the server sends or receives with a `PcapWriter`.
+Pcap files hold link-layer frames rather than datagrams, so each datagram gets a made-up IP and UDP header which says where it came from and where it went.  Wireshark and tcpdump can open the result.
+
+`PcapReader` goes the other way, for captures made by us or by other tools, and `dissect_datagram` decodes what's inside.  The `fastnet-dump` binary puts the two together.*/
+use byteorder::{BigEndian, LittleEndian, ByteOrder};
+use crc::crc32;
+use packets::{self, Packet};
+use std::io;
+use std::net;
+use std::time;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+//The same, but for files whose timestamps are in nanoseconds.
+const PCAP_NANOSECOND_MAGIC: u32 = 0xa1b23c4d;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const SNAPLEN: u32 = 65535;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const UDP_PROTOCOL: u8 = 17;
+const UDP_HEADER_SIZE: usize = 8;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+//How much of a payload to show in dissections.
+const PAYLOAD_PREVIEW: usize = 16;
+
+/**A datagram read from a capture.*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedDatagram {
+    ///Since the Unix epoch.
+    pub timestamp: time::Duration,
+    pub source: net::SocketAddr,
+    pub destination: net::SocketAddr,
+    ///The whole datagram, checksum included.
+    pub data: Vec<u8>,
+}
+
+/**Writes datagrams to a pcap file.
+
+The header is written by `new`, so that a capture with nothing in it is still a valid file.*/
+pub struct PcapWriter<W: io::Write> {
+    writer: W,
+}
+
+impl<W: io::Write> PcapWriter<W> {
+    pub fn new(mut writer: W)->io::Result<PcapWriter<W>> {
+        let mut header = [0u8; PCAP_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..], PCAP_MAGIC);
+        LittleEndian::write_u16(&mut header[4..], 2);
+        LittleEndian::write_u16(&mut header[6..], 4);
+        //The time zone and timestamp accuracy are always 0.
+        LittleEndian::write_u32(&mut header[16..], SNAPLEN);
+        LittleEndian::write_u32(&mut header[20..], LINKTYPE_RAW);
+        try!(writer.write_all(&header));
+        Ok(PcapWriter{writer: writer})
+    }
+
+    /**Write one datagram.
+
+If one address is IPv4 and the other IPv6, the IPv4 one is written as an IPv4-mapped IPv6 address.*/
+    pub fn write_datagram(&mut self, timestamp: time::SystemTime, source: net::SocketAddr, destination: net::SocketAddr, data: &[u8])->io::Result<()> {
+        let since_epoch = timestamp.duration_since(time::UNIX_EPOCH).unwrap_or(time::Duration::from_secs(0));
+        let frame = match (source, destination) {
+            (net::SocketAddr::V4(s), net::SocketAddr::V4(d)) => try!(ipv4_frame(s, d, data)),
+            (s, d) => try!(ipv6_frame(as_ipv6(s), as_ipv6(d), data)),
+        };
+        let mut header = [0u8; PCAP_RECORD_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..], since_epoch.as_secs() as u32);
+        LittleEndian::write_u32(&mut header[4..], since_epoch.subsec_micros());
+        LittleEndian::write_u32(&mut header[8..], frame.len() as u32);
+        LittleEndian::write_u32(&mut header[12..], frame.len() as u32);
+        try!(self.writer.write_all(&header));
+        self.writer.write_all(&frame)
+    }
+
+    pub fn flush(&mut self)->io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self)->W {
+        self.writer
+    }
+}
+
+fn as_ipv6(address: net::SocketAddr)->net::SocketAddrV6 {
+    match address {
+        net::SocketAddr::V4(a) => net::SocketAddrV6::new(a.ip().to_ipv6_mapped(), a.port(), 0, 0),
+        net::SocketAddr::V6(a) => a,
+    }
+}
+
+//The ones' complement sum used by IP and UDP checksums, before it's inverted.
+fn ones_complement_sum(mut sum: u32, data: &[u8])->u32 {
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {BigEndian::read_u16(chunk)} else {(chunk[0] as u16) << 8};
+        sum += word as u32;
+    }
+    while sum > 0xffff {sum = (sum & 0xffff)+(sum >> 16);}
+    sum
+}
+
+fn udp_header(source_port: u16, destination_port: u16, data: &[u8])->io::Result<[u8; UDP_HEADER_SIZE]> {
+    //The IPv4 header's length field has to hold the UDP header as well.
+    if data.len() > 0xffff-UDP_HEADER_SIZE-IPV4_HEADER_SIZE {return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too large to capture"));}
+    let mut header = [0u8; UDP_HEADER_SIZE];
+    BigEndian::write_u16(&mut header[0..], source_port);
+    BigEndian::write_u16(&mut header[2..], destination_port);
+    BigEndian::write_u16(&mut header[4..], (UDP_HEADER_SIZE+data.len()) as u16);
+    Ok(header)
+}
+
+fn ipv4_frame(source: net::SocketAddrV4, destination: net::SocketAddrV4, data: &[u8])->io::Result<Vec<u8>> {
+    //The UDP checksum is optional over IPv4, so it's left as 0.
+    let udp = try!(udp_header(source.port(), destination.port(), data));
+    let mut frame = vec![0u8; IPV4_HEADER_SIZE];
+    frame[0] = 0x45;
+    BigEndian::write_u16(&mut frame[2..], (IPV4_HEADER_SIZE+UDP_HEADER_SIZE+data.len()) as u16);
+    //Don't fragment.
+    frame[6] = 0x40;
+    frame[8] = 64;
+    frame[9] = UDP_PROTOCOL;
+    frame[12..16].copy_from_slice(&source.ip().octets());
+    frame[16..20].copy_from_slice(&destination.ip().octets());
+    let checksum = !ones_complement_sum(0, &frame) as u16;
+    BigEndian::write_u16(&mut frame[10..], checksum);
+    frame.extend_from_slice(&udp);
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+fn ipv6_frame(source: net::SocketAddrV6, destination: net::SocketAddrV6, data: &[u8])->io::Result<Vec<u8>> {
+    let mut udp = try!(udp_header(source.port(), destination.port(), data));
+    let length = (UDP_HEADER_SIZE+data.len()) as u16;
+    let mut frame = vec![0u8; IPV6_HEADER_SIZE];
+    frame[0] = 0x60;
+    BigEndian::write_u16(&mut frame[4..], length);
+    frame[6] = UDP_PROTOCOL;
+    frame[7] = 64;
+    frame[8..24].copy_from_slice(&source.ip().octets());
+    frame[24..40].copy_from_slice(&destination.ip().octets());
+    //IPv6 requires the UDP checksum, which covers a pseudo-header as well as the datagram.
+    let mut sum = ones_complement_sum(0, &frame[8..40]);
+    sum += length as u32+UDP_PROTOCOL as u32;
+    sum = ones_complement_sum(sum, &udp);
+    sum = ones_complement_sum(sum, data);
+    let checksum = match !sum as u16 {
+        0 => 0xffff,
+        c@_ => c,
+    };
+    BigEndian::write_u16(&mut udp[6..], checksum);
+    frame.extend_from_slice(&udp);
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+/**Reads UDP datagrams from a pcap file.
+
+Besides our own captures, this understands files from tcpdump and Wireshark on Ethernet, loopback, and Linux "any" interfaces.  Frames which aren't UDP over IP are skipped.
+Pcapng files aren't supported.*/
+pub struct PcapReader<R: io::Read> {
+    reader: R,
+    big_endian: bool,
+    nanoseconds: bool,
+    link_type: u32,
+}
+
+fn invalid_data(message: &str)->io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<R: io::Read> PcapReader<R> {
+    pub fn new(mut reader: R)->io::Result<PcapReader<R>> {
+        let mut header = [0u8; PCAP_HEADER_SIZE];
+        try!(reader.read_exact(&mut header));
+        let (big_endian, nanoseconds) = match (LittleEndian::read_u32(&header), BigEndian::read_u32(&header)) {
+            (PCAP_MAGIC, _) => (false, false),
+            (PCAP_NANOSECOND_MAGIC, _) => (false, true),
+            (_, PCAP_MAGIC) => (true, false),
+            (_, PCAP_NANOSECOND_MAGIC) => (true, true),
+            _ => return Err(invalid_data("not a pcap file")),
+        };
+        let mut result = PcapReader{reader: reader, big_endian: big_endian, nanoseconds: nanoseconds, link_type: 0};
+        //The top bits may hold an FCS length, which we don't need.
+        result.link_type = result.read_u32(&header[20..]) & 0xffff;
+        match result.link_type {
+            LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL | LINKTYPE_IPV4 | LINKTYPE_IPV6 | LINKTYPE_LINUX_SLL2 => Ok(result),
+            _ => Err(invalid_data("unsupported link type")),
+        }
+    }
+
+    fn read_u32(&self, buffer: &[u8])->u32 {
+        if self.big_endian {BigEndian::read_u32(buffer)} else {LittleEndian::read_u32(buffer)}
+    }
+
+    ///The next UDP datagram, or None at the end of the file.
+    pub fn read_datagram(&mut self)->io::Result<Option<CapturedDatagram>> {
+        loop {
+            let mut header = [0u8; PCAP_RECORD_HEADER_SIZE];
+            //A file which ends between records is fine.  One which ends inside one is truncated.
+            let got = try!(read_fully(&mut self.reader, &mut header));
+            if got == 0 {return Ok(None);}
+            if got < header.len() {return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record header"));}
+            let seconds = self.read_u32(&header[0..]) as u64;
+            let fraction = self.read_u32(&header[4..]);
+            let captured_length = self.read_u32(&header[8..]) as usize;
+            if captured_length > SNAPLEN as usize*4 {return Err(invalid_data("record too large"));}
+            let mut frame = vec![0u8; captured_length];
+            try!(self.reader.read_exact(&mut frame));
+            let nanoseconds = if self.nanoseconds {fraction} else {fraction.saturating_mul(1000)};
+            let timestamp = time::Duration::new(seconds, 0)+time::Duration::from_nanos(nanoseconds as u64);
+            if let Some(d) = self.parse_frame(&frame, timestamp) {return Ok(Some(d));}
+        }
+    }
+
+    //Find the IP packet in a frame from the link layer.
+    fn parse_frame(&self, frame: &[u8], timestamp: time::Duration)->Option<CapturedDatagram> {
+        let ip = match self.link_type {
+            LINKTYPE_NULL => frame.get(4..),
+            LINKTYPE_ETHERNET => {
+                let mut offset = 12;
+                while frame.len() >= offset+2 && BigEndian::read_u16(&frame[offset..]) == ETHERTYPE_VLAN {offset += 4;}
+                frame.get(offset+2..)
+            },
+            LINKTYPE_LINUX_SLL => frame.get(16..),
+            LINKTYPE_LINUX_SLL2 => frame.get(20..),
+            _ => Some(frame),
+        };
+        ip.and_then(|ip| parse_ip(ip, timestamp))
+    }
+}
+
+//Like read_exact, but returns how much was read instead of failing at the end of the file.
+fn read_fully<R: io::Read>(reader: &mut R, buffer: &mut [u8])->io::Result<usize> {
+    let mut got = 0;
+    while got < buffer.len() {
+        match reader.read(&mut buffer[got..]) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(got)
+}
+
+fn parse_ip(ip: &[u8], timestamp: time::Duration)->Option<CapturedDatagram> {
+    let (source, destination, udp) = match ip.first().map(|b| b >> 4) {
+        Some(4) if ip.len() >= IPV4_HEADER_SIZE => {
+            let header_length = (ip[0] & 0xf) as usize*4;
+            let total_length = BigEndian::read_u16(&ip[2..]) as usize;
+            //Fragments can't be put back together one frame at a time.
+            let fragmented = BigEndian::read_u16(&ip[6..]) & 0x3fff != 0;
+            if ip[9] != UDP_PROTOCOL || fragmented || header_length < IPV4_HEADER_SIZE || total_length < header_length || total_length > ip.len() {return None;}
+            let source = net::IpAddr::V4(net::Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+            let destination = net::IpAddr::V4(net::Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+            (source, destination, &ip[header_length..total_length])
+        },
+        //Extension headers aren't followed; they don't show up on UDP traffic in practice.
+        Some(6) if ip.len() >= IPV6_HEADER_SIZE => {
+            let payload_length = BigEndian::read_u16(&ip[4..]) as usize;
+            if ip[6] != UDP_PROTOCOL || IPV6_HEADER_SIZE+payload_length > ip.len() {return None;}
+            let mut source = [0u8; 16];
+            let mut destination = [0u8; 16];
+            source.copy_from_slice(&ip[8..24]);
+            destination.copy_from_slice(&ip[24..40]);
+            (net::IpAddr::V6(source.into()), net::IpAddr::V6(destination.into()), &ip[IPV6_HEADER_SIZE..IPV6_HEADER_SIZE+payload_length])
+        },
+        _ => return None,
+    };
+    if udp.len() < UDP_HEADER_SIZE {return None;}
+    let length = BigEndian::read_u16(&udp[4..]) as usize;
+    if length < UDP_HEADER_SIZE || length > udp.len() {return None;}
+    Some(CapturedDatagram {
+        timestamp: timestamp,
+        source: net::SocketAddr::new(source, BigEndian::read_u16(&udp[0..])),
+        destination: net::SocketAddr::new(destination, BigEndian::read_u16(&udp[2..])),
+        data: udp[UDP_HEADER_SIZE..length].to_vec(),
+    })
+}
+
+/**What's in a datagram, for people to read.*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DissectedDatagram {
+    pub checksum_valid: bool,
+    ///The channel from the packet's header, if the datagram is long enough to have one.
+    pub channel: Option<i16>,
+    ///The decoded packet, or why it couldn't be decoded.
+    pub description: String,
+}
+
+/**Decode a datagram as it appears on the wire.
+
+Datagrams with bad checksums are still decoded as far as possible.  Encrypted packets are only described, since decrypting them needs the session's keys.*/
+pub fn dissect_datagram(data: &[u8])->DissectedDatagram {
+    if data.len() < 4 {
+        return DissectedDatagram{checksum_valid: false, channel: None, description: "too short for a checksum".to_string()};
+    }
+    let checksum_valid = crc32::checksum_castagnoli(&data[4..]) == BigEndian::read_u32(&data[..4]);
+    let channel = if data.len() >= 6 {Some(BigEndian::read_i16(&data[4..]))} else {None};
+    let description = match packets::decode_packet(&data[4..]) {
+        Ok(p) => describe_packet(&p),
+        Err(e) => format!("undecodable: {:?}", e),
+    };
+    DissectedDatagram{checksum_valid: checksum_valid, channel: channel, description: description}
+}
+
+fn describe_bytes(bytes: &[u8])->String {
+    let preview: Vec<String> = bytes.iter().take(PAYLOAD_PREVIEW).map(|b| format!("{:02x}", b)).collect();
+    let more = if bytes.len() > PAYLOAD_PREVIEW {" ..."} else {""};
+    format!("{} bytes [{}{}]", bytes.len(), preview.join(" "), more)
+}
+
+fn describe_extensions(extensions: &[packets::Extension])->String {
+    let names: Vec<String> = extensions.iter().map(|e| format!("{} ({} bytes)", e.name, e.data.len())).collect();
+    names.join(", ")
+}
+
+//Debug is fine for most packets, but not for the ones carrying blobs.
+fn describe_packet(packet: &Packet)->String {
+    match *packet {
+        Packet::Data{chan, ref packet} => {
+            let mut flags = Vec::default();
+            if packet.is_frame_start() {flags.push("start");}
+            if packet.is_frame_end() {flags.push("end");}
+            if packet.is_reliable() {flags.push("reliable");}
+            if packet.wants_receipt() {flags.push("receipt");}
+            let header = match packet.borrow_header() {
+                Some(h) => format!(" frame of {} bytes, last reliable frame {},", h.length, h.last_reliable_frame),
+                None => "".to_string(),
+            };
+            format!("Data on channel {}: sequence number {} [{}]{} payload {}", chan, packet.sequence_number(), flags.join(" "), header, describe_bytes(packet.borrow_payload()))
+        },
+        Packet::Connect{id, ref extensions} => format!("Connect from {}: extensions {}", id, describe_extensions(extensions)),
+        Packet::Connected{id, ref extensions} => format!("Connected to {}: extensions {}", id, describe_extensions(extensions)),
+        Packet::Cookie{id, ref cookie} => format!("Cookie for {}: {}", id, describe_bytes(cookie)),
+        Packet::Encrypted{tag, counter, ref ciphertext} => format!("Encrypted with tag {:x}, counter {}: {}", tag, counter, describe_bytes(ciphertext)),
+        ref p@_ => format!("{:?}", p),
+    }
+}
+
+#[test]
+fn test_pcap_roundtrip() {
+    let v4: net::SocketAddr = "127.0.0.1:11000".parse().unwrap();
+    let other_v4: net::SocketAddr = "10.0.0.2:11001".parse().unwrap();
+    let v6: net::SocketAddr = "[::1]:11002".parse().unwrap();
+    let start = time::UNIX_EPOCH+time::Duration::new(1000, 5000);
+    let mut writer = PcapWriter::new(Vec::default()).unwrap();
+    writer.write_datagram(start, v4, other_v4, &[1, 2, 3]).unwrap();
+    writer.write_datagram(start+time::Duration::from_millis(1), v6, v4, &[4, 5]).unwrap();
+    writer.write_datagram(start, v4, other_v4, &[]).unwrap();
+    let file = writer.into_inner();
+    let mut reader = PcapReader::new(&file[..]).unwrap();
+    let first = reader.read_datagram().unwrap().unwrap();
+    assert_eq!(first, CapturedDatagram{timestamp: time::Duration::new(1000, 5000), source: v4, destination: other_v4, data: vec![1, 2, 3]});
+    //The IPv4 side comes back mapped.
+    let second = reader.read_datagram().unwrap().unwrap();
+    assert_eq!((second.source, second.destination.ip(), second.data), (v6, net::IpAddr::V6(net::Ipv4Addr::new(127, 0, 0, 1).to_ipv6_mapped()), vec![4, 5]));
+    assert_eq!(reader.read_datagram().unwrap().unwrap().data, Vec::<u8>::new());
+    assert_eq!(reader.read_datagram().unwrap(), None);
+    //A checksum over a correct IPv6 datagram, including its own, comes out as all ones.
+    let v6_frame = ipv6_frame(as_ipv6(v6), as_ipv6(v4), &[4, 5, 6]).unwrap();
+    let mut sum = ones_complement_sum(0, &v6_frame[8..40]);
+    sum += (v6_frame.len()-IPV6_HEADER_SIZE) as u32+UDP_PROTOCOL as u32;
+    assert_eq!(ones_complement_sum(sum, &v6_frame[40..]), 0xffff);
+    assert!(PcapReader::new(&file[..PCAP_HEADER_SIZE+4]).unwrap().read_datagram().is_err());
+    assert!(PcapReader::new(&[0u8; PCAP_HEADER_SIZE][..]).is_err());
+}
+
+#[test]
+fn test_dissect_datagram() {
+    let packet = Packet::Ack{chan: 3, sequence_number: 7};
+    let mut data = vec![0u8; 100];
+    let size = packets::encode_packet(&packet, &mut data[4..]).unwrap();
+    data.truncate(4+size);
+    let checksum = crc32::checksum_castagnoli(&data[4..]);
+    BigEndian::write_u32(&mut data[..4], checksum);
+    assert_eq!(dissect_datagram(&data), DissectedDatagram{checksum_valid: true, channel: Some(3), description: format!("{:?}", packet)});
+    data[0] ^= 1;
+    let bad = dissect_datagram(&data);
+    assert!(bad.checksum_valid == false && bad.channel == Some(3));
+    assert_eq!(dissect_datagram(&data[..5]).channel, None);
+    assert!(dissect_datagram(&[1, 2]).checksum_valid == false);
+}
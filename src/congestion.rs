@@ -0,0 +1,170 @@
+/*! Congestion control.
+
+Every connection has a congestion controller, which decides how much reliable data may be waiting for acks and how fast data may be sent.
+Applications with unusual needs can replace the default with `Server::configure_congestion_control`.*/
+use std::cmp;
+use std::fmt;
+use std::time;
+
+///The largest packet Fastnet sends, which is the unit windows are measured in.
+pub const MAX_SEGMENT_SIZE: usize = 1000;
+
+/**Decides how fast a connection may send.
+
+Connections consult the controller before sending data packets: reliable packets wait while the window is full, and all data packets are paced at `pacing_rate`.
+Control packets such as acks and heartbeats are never held back.*/
+pub trait CongestionController: fmt::Debug+Send {
+    ///How many bytes of reliable data may be waiting for acks.
+    fn window(&self)->usize;
+
+    ///`bytes` of reliable data were acked.
+    fn on_ack(&mut self, bytes: usize, now: time::Instant);
+
+    ///A reliable packet wasn't acked in time and is being resent.
+    fn on_loss(&mut self, now: time::Instant);
+
+    ///The roundtrip estimator measured a roundtrip.
+    fn on_rtt_sample(&mut self, rtt: time::Duration, now: time::Instant);
+
+    /**How many bytes a second to send, given the smoothed roundtrip time.
+
+The default sends a window a little faster than once per roundtrip, so that pacing never stops the window from being used.*/
+    fn pacing_rate(&self, smoothed_rtt: time::Duration)->f64 {
+        let rtt = duration_secs(smoothed_rtt).max(0.001);
+        self.window() as f64/rtt*1.25
+    }
+}
+
+fn duration_secs(d: time::Duration)->f64 {
+    d.as_secs() as f64+d.subsec_nanos() as f64/1e9
+}
+
+const INITIAL_WINDOW: usize = 10*MAX_SEGMENT_SIZE;
+const MINIMUM_WINDOW: usize = 2*MAX_SEGMENT_SIZE;
+//Queueing delay past this fraction of the minimum roundtrip counts as congestion, if the delay is also growing.
+const DELAY_THRESHOLD: f64 = 0.5;
+//Below this, changes in the roundtrip are just noise.
+const MIN_QUEUEING_DELAY: f64 = 0.005;
+//The minimum roundtrip is forgotten this often, in case the route changed.
+const MIN_RTT_LIFETIME_SECS: u64 = 10;
+
+/**The default controller.
+
+This is NewReno-like: the window doubles every roundtrip in slow start, then grows by a packet every roundtrip, and halves at most once a roundtrip when packets are lost.
+It also watches the roundtrip time.  If it rises well above the lowest seen and is still rising, queues are building somewhere, so slow start ends and the window stops growing until the delay goes away.*/
+#[derive(Debug, Clone)]
+pub struct NewRenoController {
+    window: usize,
+    slow_start_threshold: usize,
+    //Losses before this don't shrink the window again, since they're from the same roundtrip.
+    recovery_until: Option<time::Instant>,
+    min_rtt: Option<(time::Duration, time::Instant)>,
+    last_rtt: Option<time::Duration>,
+    delay_growing: bool,
+}
+
+impl NewRenoController {
+    pub fn new()->NewRenoController {
+        NewRenoController {
+            window: INITIAL_WINDOW,
+            slow_start_threshold: usize::max_value(),
+            recovery_until: None,
+            min_rtt: None,
+            last_rtt: None,
+            delay_growing: false,
+        }
+    }
+
+    fn in_recovery(&self, now: time::Instant)->bool {
+        self.recovery_until.map_or(false, |t| now < t)
+    }
+}
+
+impl Default for NewRenoController {
+    fn default()->NewRenoController {
+        NewRenoController::new()
+    }
+}
+
+impl CongestionController for NewRenoController {
+    fn window(&self)->usize {
+        self.window
+    }
+
+    fn on_ack(&mut self, bytes: usize, now: time::Instant) {
+        if self.delay_growing || self.in_recovery(now) {return;}
+        if self.window < self.slow_start_threshold {
+            self.window += bytes;
+        }
+        else {
+            self.window += cmp::max(MAX_SEGMENT_SIZE*bytes/self.window, 1);
+        }
+    }
+
+    fn on_loss(&mut self, now: time::Instant) {
+        if self.in_recovery(now) {return;}
+        self.slow_start_threshold = cmp::max(self.window/2, MINIMUM_WINDOW);
+        self.window = self.slow_start_threshold;
+        let rtt = self.last_rtt.unwrap_or(time::Duration::from_millis(100));
+        self.recovery_until = Some(now+rtt);
+    }
+
+    fn on_rtt_sample(&mut self, rtt: time::Duration, now: time::Instant) {
+        let min = match self.min_rtt {
+            Some((m, at)) if m <= rtt && now.duration_since(at).as_secs() < MIN_RTT_LIFETIME_SECS => m,
+            _ => {
+                self.min_rtt = Some((rtt, now));
+                rtt
+            },
+        };
+        let queueing = duration_secs(rtt)-duration_secs(min);
+        let rising = self.last_rtt.map_or(false, |last| rtt > last);
+        let threshold = (duration_secs(min)*DELAY_THRESHOLD).max(MIN_QUEUEING_DELAY);
+        self.delay_growing = queueing > threshold && rising;
+        if self.delay_growing && self.window < self.slow_start_threshold {
+            self.slow_start_threshold = self.window;
+        }
+        self.last_rtt = Some(rtt);
+    }
+}
+
+#[test]
+fn test_new_reno_window() {
+    let mut controller = NewRenoController::new();
+    let now = time::Instant::now();
+    controller.on_rtt_sample(time::Duration::from_millis(50), now);
+    //Slow start grows by what was acked.
+    controller.on_ack(INITIAL_WINDOW, now);
+    assert_eq!(controller.window(), 2*INITIAL_WINDOW);
+    //A loss halves it, but only once per roundtrip.
+    controller.on_loss(now);
+    assert_eq!(controller.window(), INITIAL_WINDOW);
+    controller.on_loss(now+time::Duration::from_millis(10));
+    assert_eq!(controller.window(), INITIAL_WINDOW);
+    //Then growth is by one packet per window.
+    let later = now+time::Duration::from_millis(100);
+    controller.on_ack(INITIAL_WINDOW, later);
+    assert_eq!(controller.window(), INITIAL_WINDOW+MAX_SEGMENT_SIZE);
+    controller.on_loss(later);
+    controller.on_loss(later+time::Duration::from_secs(1));
+    controller.on_loss(later+time::Duration::from_secs(2));
+    assert_eq!(controller.window(), MINIMUM_WINDOW);
+}
+
+#[test]
+fn test_new_reno_delay() {
+    let mut controller = NewRenoController::new();
+    let now = time::Instant::now();
+    controller.on_rtt_sample(time::Duration::from_millis(50), now);
+    controller.on_rtt_sample(time::Duration::from_millis(60), now);
+    controller.on_ack(MAX_SEGMENT_SIZE, now);
+    assert_eq!(controller.window(), INITIAL_WINDOW+MAX_SEGMENT_SIZE);
+    //Well above the minimum and rising, so growth stops and slow start is over.
+    controller.on_rtt_sample(time::Duration::from_millis(100), now);
+    controller.on_ack(MAX_SEGMENT_SIZE, now);
+    assert_eq!(controller.window(), INITIAL_WINDOW+MAX_SEGMENT_SIZE);
+    //Once the delay stops rising, growth resumes, but slowly.
+    controller.on_rtt_sample(time::Duration::from_millis(90), now);
+    controller.on_ack(INITIAL_WINDOW+MAX_SEGMENT_SIZE, now);
+    assert_eq!(controller.window(), INITIAL_WINDOW+2*MAX_SEGMENT_SIZE);
+}
@@ -0,0 +1,271 @@
+/*! A futures-based API for use with tokio.
+
+This module is only available with the `tokio` feature.
+
+The protocol runs on the same event loop as `Server`, in Fastnet's background thread.
+An endpoint turns the callbacks of that event loop into futures and streams:
+
+- `Endpoint::bind` gives an endpoint and a stream of peers which connect to it.
+- `Endpoint::connect` is a future which resolves to a peer.
+- A peer is a stream of the messages it sends, and `Peer::send` is a future which resolves once the message has been handed to the protocol.
+
+None of the futures here need a particular runtime, but the channels used internally are tokio's.*/
+use async::{Handler, Error, Result, MessageHeader};
+use server;
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+use std::collections;
+use std::future::Future;
+use std::net;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use uuid;
+use {MAX_CHANNEL, MAX_MESSAGE_SIZE};
+
+type SharedServer = Arc<Mutex<server::MioServer<EndpointHandler>>>;
+//What the handler hands to the futures side when a connection is established.
+type NewPeer = (uuid::Uuid, mpsc::UnboundedReceiver<Message>);
+
+///A message received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: u16,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct EndpointState {
+    pending_connections: collections::HashMap<u64, oneshot::Sender<Result<NewPeer>>>,
+    peers: collections::HashMap<uuid::Uuid, mpsc::UnboundedSender<Message>>,
+    incoming: Option<mpsc::UnboundedSender<NewPeer>>,
+}
+
+//Runs in the background thread and forwards everything to the channels.
+struct EndpointHandler {
+    state: Arc<Mutex<EndpointState>>,
+}
+
+impl Handler for EndpointHandler {
+    fn connected(&mut self, id: uuid::Uuid, request_id: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state.peers.insert(id, sender);
+        match request_id {
+            Some(r) => {
+                if let Some(pending) = state.pending_connections.remove(&r) {
+                    let _ = pending.send(Ok((id, receiver)));
+                }
+            },
+            None => {
+                if let Some(ref incoming) = state.incoming {
+                    let _ = incoming.send((id, receiver));
+                }
+            },
+        }
+    }
+
+    fn disconnected(&mut self, id: uuid::Uuid, request_id: Option<u64>) {
+        //Dropping the sender ends the peer's stream.
+        self.state.lock().unwrap().peers.remove(&id);
+    }
+
+    fn incoming_message(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8]) {
+        if let Some(sender) = self.state.lock().unwrap().peers.get(&id) {
+            let _ = sender.send(Message{channel: channel, payload: payload.to_vec()});
+        }
+    }
+
+    fn request_failed(&mut self, request_id: u64, error: Error) {
+        if let Some(pending) = self.state.lock().unwrap().pending_connections.remove(&request_id) {
+            let _ = pending.send(Err(error));
+        }
+    }
+}
+
+/**A Fastnet endpoint driven by futures.
+
+Endpoints are cheap to clone; all clones share the same socket and connections.*/
+#[derive(Clone)]
+pub struct Endpoint {
+    server: SharedServer,
+    state: Arc<Mutex<EndpointState>>,
+    next_request_id: Arc<AtomicU64>,
+}
+
+impl Endpoint {
+    /**Bind an endpoint to the specified address.
+
+Also returns the stream of peers which connect to this endpoint.  If it is dropped, incoming connections are still accepted but nothing can talk to them.*/
+    pub fn bind(addr: net::SocketAddr)->Result<(Endpoint, Incoming)> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let state = Arc::new(Mutex::new(EndpointState::default()));
+        state.lock().unwrap().incoming = Some(sender);
+        let handler = EndpointHandler{state: state.clone()};
+        let s = try!(server::MioServer::new(addr, handler).map_err(Error::IoError));
+        let server = Arc::new(Mutex::new(s));
+        let endpoint = Endpoint {
+            server: server.clone(),
+            state: state,
+            next_request_id: Arc::new(AtomicU64::new(0)),
+        };
+        Ok((endpoint, Incoming{server: server, receiver: receiver}))
+    }
+
+    /**Connect to a peer.
+
+Use as `endpoint.connect(addr).await`.  Resolves to the peer once the handshake completes, or to the error which stopped it.*/
+    pub fn connect(&self, addr: net::SocketAddr)->Connecting {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.state.lock().unwrap().pending_connections.insert(request_id, sender);
+        let submitted = self.server.lock().unwrap().with(move |s| s.connect(addr, request_id, None));
+        if let Err(e) = submitted {
+            //The future resolves to the error.
+            if let Some(pending) = self.state.lock().unwrap().pending_connections.remove(&request_id) {
+                let _ = pending.send(Err(e));
+            }
+        }
+        Connecting{server: self.server.clone(), receiver: receiver}
+    }
+}
+
+fn make_peer(server: &SharedServer, new_peer: NewPeer)->Peer {
+    Peer {
+        id: new_peer.0,
+        server: server.clone(),
+        receiver: new_peer.1,
+    }
+}
+
+///The future returned by `Endpoint::connect`.
+pub struct Connecting {
+    server: SharedServer,
+    receiver: oneshot::Receiver<Result<NewPeer>>,
+}
+
+impl Future for Connecting {
+    type Output = Result<Peer>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context)->Poll<Result<Peer>> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Ok(new_peer))) => Poll::Ready(Ok(make_peer(&self.server, new_peer))),
+            Poll::Ready(Ok(Err(e))) => Poll::Ready(Err(e)),
+            //The handler went away without answering, so the background thread is gone.
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::ConnectionAborted)),
+        }
+    }
+}
+
+///A stream of peers which connected to an endpoint.
+pub struct Incoming {
+    server: SharedServer,
+    receiver: mpsc::UnboundedReceiver<NewPeer>,
+}
+
+impl Stream for Incoming {
+    type Item = Peer;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)->Poll<Option<Peer>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(maybe_peer) => Poll::Ready(maybe_peer.map(|p| make_peer(&self.server, p))),
+        }
+    }
+}
+
+/**A connected peer.
+
+This is a stream of the messages the peer sends, which ends when the peer disconnects.*/
+pub struct Peer {
+    id: uuid::Uuid,
+    server: SharedServer,
+    receiver: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Peer {
+    pub fn id(&self)->uuid::Uuid {
+        self.id
+    }
+
+    /**Send a message to this peer.
+
+Use as `peer.send(channel, payload, reliable).await`.  Resolves once the message has been handed to the protocol, not when it arrives.*/
+    pub fn send(&self, channel: u16, payload: &[u8], reliable: bool)->Sending {
+        if channel > MAX_CHANNEL {return Sending{receiver: None, error: Some(Error::InvalidChannel)};}
+        if payload.len() > MAX_MESSAGE_SIZE {return Sending{receiver: None, error: Some(Error::MessageTooLarge)};}
+        let (sender, receiver) = oneshot::channel();
+        let id = self.id;
+        let payload = payload.to_vec();
+        let submitted = self.server.lock().unwrap().with(move |s| {
+            let _ = sender.send(s.send_message(id, channel, &MessageHeader::default(), &payload, reliable, None));
+        });
+        match submitted {
+            Ok(_) => Sending{receiver: Some(receiver), error: None},
+            Err(e) => Sending{receiver: None, error: Some(e)},
+        }
+    }
+}
+
+impl Stream for Peer {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)->Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+///The future returned by `Peer::send`.
+pub struct Sending {
+    receiver: Option<oneshot::Receiver<Result<()>>>,
+    error: Option<Error>,
+}
+
+impl Future for Sending {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context)->Poll<Result<()>> {
+        if let Some(e) = self.error.take() {return Poll::Ready(Err(e));}
+        let result = match self.receiver {
+            Some(ref mut r) => Pin::new(r).poll(cx),
+            None => return Poll::Ready(Ok(())),
+        };
+        match result {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(r)) => Poll::Ready(r),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::ConnectionAborted)),
+        }
+    }
+}
+
+#[cfg(test)]
+fn next<S: Stream+Unpin>(stream: &mut S)->impl Future<Output=Option<S::Item>>+'_ {
+    ::std::future::poll_fn(move |cx| Pin::new(&mut *stream).poll_next(cx))
+}
+
+#[test]
+fn test_endpoint_loopback() {
+    use async_tests::free_address;
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use std::time;
+    //Nothing here has a timeout of its own, so don't let a lost message hang the test run.
+    let (done, finished) = std_mpsc::channel();
+    thread::spawn(move || {
+        let runtime = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (address1, address2) = (free_address(), free_address());
+        let (_listener, mut incoming) = Endpoint::bind(address1).unwrap();
+        let (connector, _) = Endpoint::bind(address2).unwrap();
+        let mut client = runtime.block_on(connector.connect(address1)).unwrap();
+        let mut server_side = runtime.block_on(next(&mut incoming)).unwrap();
+        runtime.block_on(client.send(3, b"hello", true)).unwrap();
+        assert_eq!(runtime.block_on(next(&mut server_side)), Some(Message{channel: 3, payload: b"hello".to_vec()}));
+        runtime.block_on(server_side.send(4, b"hi", true)).unwrap();
+        assert_eq!(runtime.block_on(next(&mut client)), Some(Message{channel: 4, payload: b"hi".to_vec()}));
+        assert!(match runtime.block_on(client.send(MAX_CHANNEL+1, b"", true)) {Err(Error::InvalidChannel) => true, _ => false});
+        done.send(()).unwrap();
+    });
+    finished.recv_timeout(time::Duration::from_secs(10)).unwrap();
+}
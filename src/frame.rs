@@ -3,6 +3,8 @@ use std::iter;
 use std::cmp;
 use std::convert::From;
 use std::borrow;
+use std::fmt;
+use std::io;
 
 //This has to be small enough to leave room for the header.
 const CHUNK_SIZE: usize = 500;
@@ -13,6 +15,7 @@ pub struct FrameEncoder<'A, T: 'A> {
     sn: u64,
     last_reliable_frame: u64,
     reliable: bool,
+    wants_receipt: bool,
     first: bool,
     iter: &'A mut T,
     workspace: Vec<u8>,
@@ -28,11 +31,18 @@ Vec<u8>: iter::Extend<Q> {
             sn: starting_sequence_number,
             last_reliable_frame: last_reliable_frame,
             reliable: reliable,
+            wants_receipt: false,
             first: true,
             iter: iter,
             workspace: Vec::with_capacity(CHUNK_SIZE),
         }
     }
+
+    ///Ask the receiver to report which of the packets arrived.
+    pub fn set_wants_receipt(mut self, wants_receipt: bool)->Self {
+        self.wants_receipt = wants_receipt;
+        self
+    }
 }
 
 impl<'A, T, Q> iter::Iterator for FrameEncoder<'A, T>
@@ -55,6 +65,7 @@ Vec<u8>: iter::Extend<Q> {
         .set_reliable(self.reliable)
         .set_frame_start(self.first)
         .set_frame_end(self.iter.len() == 0)
+        .set_wants_receipt(self.wants_receipt)
         .build();
         self.first = false;
         self.sn += 1;
@@ -74,6 +85,93 @@ Vec<u8>: iter::Extend<Q> {
     }
 }
 
+/**Like `FrameEncoder`, but reads the payload from an `io::Read` as packets are asked for, so the frame never has to be in memory.
+
+The whole frame is always reliable.  The length has to be known up front, since it goes in the header.
+If the reader fails or runs out early, the rest of the frame is zeros: the receiver has already been told how long it is.  The error is kept for `take_error`.*/
+pub struct StreamEncoder {
+    channel: i16,
+    sn: u64,
+    last_reliable_frame: u64,
+    first: bool,
+    //Payload bytes still to be read, including the prefix.
+    remaining: u64,
+    length: u64,
+    prefix: Vec<u8>,
+    reader: Box<io::Read+Send>,
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for StreamEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter)->fmt::Result {
+        write!(f, "StreamEncoder {{ channel: {}, sn: {}, remaining: {} }}", self.channel, self.sn, self.remaining)
+    }
+}
+
+impl StreamEncoder {
+    /**`prefix` is sent before what's read, and counted in the length.  It must fit in the first packet.*/
+    pub fn new(reader: Box<io::Read+Send>, length: u64, prefix: Vec<u8>, channel: i16, starting_sequence_number: u64, last_reliable_frame: u64)->StreamEncoder {
+        let length = length+prefix.len() as u64;
+        StreamEncoder {
+            channel: channel,
+            sn: starting_sequence_number,
+            last_reliable_frame: last_reliable_frame,
+            first: true,
+            remaining: length,
+            length: length,
+            prefix: prefix,
+            reader: reader,
+            error: None,
+        }
+    }
+
+    ///How many packets the whole frame takes, which is how many sequence numbers it uses.
+    pub fn packet_count(&self)->u64 {
+        cmp::max((self.length+CHUNK_SIZE as u64-1)/CHUNK_SIZE as u64, 1)
+    }
+
+    pub fn is_finished(&self)->bool {
+        self.first == false && self.remaining == 0
+    }
+
+    pub fn take_error(&mut self)->Option<io::Error> {
+        self.error.take()
+    }
+
+    fn read_chunk(&mut self, size: usize)->Vec<u8> {
+        let mut chunk = vec![0u8; size];
+        let mut filled = 0;
+        if self.first {
+            filled = self.prefix.len();
+            chunk[..filled].copy_from_slice(&self.prefix);
+        }
+        while filled < size && self.error.is_none() {
+            match self.reader.read(&mut chunk[filled..]) {
+                Ok(0) => self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, "the stream ended before its length")),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => self.error = Some(e),
+            }
+        }
+        chunk
+    }
+
+    pub fn next_packet(&mut self)->Option<packets::Packet> {
+        if self.is_finished() {return None;}
+        let header = if self.first {Some(packets::FrameHeader{last_reliable_frame: self.last_reliable_frame, length: (packets::FRAME_HEADER_SIZE as u64+self.length) as u32})} else {None};
+        let size = cmp::min(self.remaining, CHUNK_SIZE as u64) as usize;
+        let payload = self.read_chunk(size);
+        self.remaining -= size as u64;
+        let dp = packets::DataPacketBuilder::with_payload_and_header(self.sn, payload, header)
+        .set_reliable(true)
+        .set_frame_start(self.first)
+        .set_frame_end(self.remaining == 0)
+        .build();
+        self.first = false;
+        self.sn += 1;
+        Some(packets::Packet::Data{chan: self.channel, packet: dp})
+    }
+}
 
 #[test]
 fn test_frame_encoding() {
@@ -114,4 +212,26 @@ fn test_frame_exact_size_iterator() {
     lentest!(501, 2);
     lentest!(1000, 2);
     lentest!(2001, 5);
-}
\ No newline at end of file
+}
+#[test]
+fn test_stream_encoding() {
+    let test_data: Vec<u8> = (0..1200).map(|i| i as u8).collect();
+    let mut stream = StreamEncoder::new(Box::new(io::Cursor::new(test_data.clone())), 1200, vec![9, 8], 100, 3, 1);
+    assert_eq!(stream.packet_count(), 3);
+    let mut got_packets = Vec::default();
+    while let Some(p) = stream.next_packet() {got_packets.push(p);}
+    assert!(stream.take_error().is_none());
+    //It matches what FrameEncoder makes for the same bytes.
+    let mut prefixed = vec![9u8, 8];
+    prefixed.extend_from_slice(&test_data);
+    let expected_packets = FrameEncoder::new(&mut prefixed.iter(), 100, 3, 1, true).collect::<Vec<_>>();
+    assert_eq!(got_packets, expected_packets);
+    //Running out early pads with zeros.
+    let mut short = StreamEncoder::new(Box::new(io::Cursor::new(vec![1u8; 10])), 20, Vec::default(), 0, 0, 0);
+    match short.next_packet() {
+        Some(packets::Packet::Data{packet, ..}) => assert_eq!(packet.borrow_payload()[9..], [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        _ => panic!("Expected a data packet."),
+    }
+    assert!(short.is_finished());
+    assert!(short.take_error().is_some());
+}
@@ -10,6 +10,21 @@ extern crate uuid;
 #[macro_use]
 extern crate log;
 extern crate time;
+extern crate x25519_dalek;
+extern crate chacha20poly1305;
+extern crate hkdf;
+extern crate hmac;
+extern crate sha2;
+extern crate rand_core;
+extern crate lz4_flex;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate bincode;
 
 mod constants;
 mod packets;
@@ -17,5 +32,24 @@ mod server;
 mod status_translator;
 mod async;
 mod frame;
+mod congestion;
+mod capture;
+#[cfg(test)]
+mod async_tests;
+#[cfg(feature = "tokio")]
+mod endpoint;
+#[cfg(feature = "serde")]
+mod typed;
 
 pub use async::*;
+pub use congestion::*;
+pub use capture::*;
+#[cfg(feature = "tokio")]
+pub use endpoint::*;
+#[cfg(feature = "serde")]
+pub use typed::*;
+
+///The highest channel the application may use.  Negative channels are reserved for Fastnet.
+pub const MAX_CHANNEL: u16 = 32767;
+///The largest message which fits in a frame.
+pub const MAX_MESSAGE_SIZE: usize = ::std::u32::MAX as usize-packets::FRAME_HEADER_SIZE;
@@ -58,17 +58,49 @@ impl Decodable for Packet {
                 match code {
                     STATUS_REQUEST_SPECIFIER => {return Ok(StatusRequest(try!(super::StatusRequest::decode(source))));},
                     STATUS_RESPONSE_SPECIFIER => {return Ok(StatusResponse(try!(super::StatusResponse::decode(source))));},
-                    CONNECT_SPECIFIER => {return Ok(Connect(try!(uuid::Uuid::decode(source))));},
-                    CONNECTED_SPECIFIER => {return Ok(Connected(try!(uuid::Uuid::decode(source))));},
+                    CONNECT_SPECIFIER => {
+                        let id = try!(uuid::Uuid::decode(source));
+                        return Ok(Connect{id: id, extensions: try!(decode_extensions(source))});
+                    },
+                    CONNECTED_SPECIFIER => {
+                        let id = try!(uuid::Uuid::decode(source));
+                        return Ok(Connected{id: id, extensions: try!(decode_extensions(source))});
+                    },
                     ABORTED_SPECIFIER => {return Ok(Aborted(try!(String::decode(source))));},
+                    CLOSE_SPECIFIER => {return Ok(Close(try!(uuid::Uuid::decode(source))));},
+                    CLOSED_SPECIFIER => {return Ok(Closed(try!(uuid::Uuid::decode(source))));},
+                    PATH_CHALLENGE_SPECIFIER => {
+                        let id = try!(uuid::Uuid::decode(source));
+                        let challenge = try!(uuid::Uuid::decode(source));
+                        return Ok(PathChallenge{id: id, challenge: challenge});
+                    },
+                    PATH_RESPONSE_SPECIFIER => {
+                        let id = try!(uuid::Uuid::decode(source));
+                        let challenge = try!(uuid::Uuid::decode(source));
+                        return Ok(PathResponse{id: id, challenge: challenge});
+                    },
+                    COOKIE_SPECIFIER => {
+                        let id = try!(uuid::Uuid::decode(source));
+                        let cookie = source.slice[source.index..].to_vec();
+                        source.index = source.slice.len();
+                        return Ok(Cookie{id: id, cookie: cookie});
+                    },
                     _ => {return Err(Invalid);},
                 }
             },
             HEARTBEAT_CHANNEL => {
+                let id = try!(uuid::Uuid::decode(source));
                 let count = try!(u64::decode(source));
                 let sent_packets = try!(u64::decode(source));
                 let received_packets = try!(u64::decode(source));
-                return Ok(Heartbeat{counter: count, sent: sent_packets, received: received_packets});
+                return Ok(Heartbeat{id: id, counter: count, sent: sent_packets, received: received_packets});
+            },
+            ENCRYPTED_CHANNEL => {
+                let tag = try!(u64::decode(source));
+                let counter = try!(u64::decode(source));
+                let ciphertext = source.slice[source.index..].to_vec();
+                source.index = source.slice.len();
+                return Ok(Encrypted{tag: tag, counter: counter, ciphertext: ciphertext});
             },
             ECHO_CHANNEL => {
                 let endpoint = try!(uuid::Uuid::decode(source));
@@ -87,6 +119,21 @@ impl Decodable for Packet {
                         let sn = try!(source.read_u64::<BigEndian>().or(Err(TooSmall)));
                         return Ok(Packet::Ack{chan: chan, sequence_number: sn});
                     },
+                    SKIP_PACKET_SPECIFIER => {
+                        let first = try!(u64::decode(source));
+                        let last = try!(u64::decode(source));
+                        let last_reliable_frame = try!(u64::decode(source));
+                        return Ok(Packet::Skip{chan: chan, first: first, last: last, last_reliable_frame: last_reliable_frame});
+                    },
+                    SKIP_ACK_PACKET_SPECIFIER => {
+                        let sn = try!(u64::decode(source));
+                        return Ok(Packet::SkipAck{chan: chan, sequence_number: sn});
+                    },
+                    RECEIPT_PACKET_SPECIFIER => {
+                        let sn = try!(u64::decode(source));
+                        let received = try!(u64::decode(source));
+                        return Ok(Packet::Receipt{chan: chan, sequence_number: sn, received: received});
+                    },
                     _ => {
                         return Err(Invalid)
                     },
@@ -262,6 +309,28 @@ impl Decodable for DataPacket {
     }
 }
 
+impl Decodable for Extension {
+    type Output = Extension;
+
+    fn decode(source: &mut PacketReader)->Result<Extension, PacketDecodingError> {
+        let name = try!(String::decode(source));
+        let length = try!(u16::decode(source)) as usize;
+        if source.available() < length {return Err(PacketDecodingError::TooSmall);}
+        let data = source.slice[source.index..source.index+length].to_vec();
+        source.index += length;
+        Ok(Extension{name: name, data: data})
+    }
+}
+
+//Extensions run to the end of the packet.
+fn decode_extensions(source: &mut PacketReader)->Result<Vec<Extension>, PacketDecodingError> {
+    let mut extensions = Vec::default();
+    while source.available() > 0 {
+        extensions.push(try!(Extension::decode(source)));
+    }
+    Ok(extensions)
+}
+
 impl Decodable for FrameHeader {
     type Output = FrameHeader;
 
@@ -273,6 +342,34 @@ impl Decodable for FrameHeader {
     }
 }
 
+impl Decodable for MessageHeader {
+    type Output = MessageHeader;
+
+    fn decode(source: &mut PacketReader)->Result<MessageHeader, PacketDecodingError> {
+        let version = try!(u8::decode(source));
+        if version != MESSAGE_HEADER_VERSION {return Err(PacketDecodingError::Invalid);}
+        let flags = try!(u8::decode(source));
+        let known = (1 << MESSAGE_TYPE_BIT) | (1 << MESSAGE_TIMESTAMP_BIT) | (1 << MESSAGE_REQUEST_BIT) | (1 << MESSAGE_RESPONSE_BIT);
+        if flags & !known != 0 {return Err(PacketDecodingError::Invalid);}
+        let request = (flags & (1 << MESSAGE_REQUEST_BIT)) > 0;
+        let response = (flags & (1 << MESSAGE_RESPONSE_BIT)) > 0;
+        if request && response {return Err(PacketDecodingError::Invalid);}
+        let mut header = MessageHeader::default();
+        if (flags & (1 << MESSAGE_TYPE_BIT)) > 0 {header.message_type = Some(try!(u32::decode(source)));}
+        if (flags & (1 << MESSAGE_TIMESTAMP_BIT)) > 0 {header.timestamp = Some(try!(u64::decode(source)));}
+        if request {header.rpc = Some(Rpc::Request(try!(u64::decode(source))));}
+        if response {header.rpc = Some(Rpc::Response(try!(u64::decode(source))));}
+        Ok(header)
+    }
+}
+
+///Split a message into its header and payload.
+pub fn decode_message(message: &[u8])->Result<(MessageHeader, &[u8]), PacketDecodingError> {
+    let mut reader = PacketReader::new(message);
+    let header = try!(MessageHeader::decode(&mut reader));
+    Ok((header, &message[reader.read_count()..]))
+}
+
 pub fn decode_packet(buffer: &[u8])->Result<Packet, PacketDecodingError> {
     let mut reader = PacketReader::new(&buffer);
     Packet::decode(&mut reader)
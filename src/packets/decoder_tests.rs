@@ -111,23 +111,68 @@ Packet::StatusResponse(StatusResponse::ExtensionResponse{name: "a_b".to_string()
 decoder_test!(test_decode_connect_packet, Packet,
 [255u8, 255, 2,
 0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
-Packet::Connect(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+Packet::Connect{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), extensions: vec![]});
+
+decoder_test!(test_decode_connect_packet_with_extensions, Packet,
+[255u8, 255, 2,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+b'a', 0, 0, 2, 5, 10, //Extension "a" with 2 bytes of data.
+b'b', 0, 0, 0], //And "b" with none.
+Packet::Connect{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    extensions: vec![Extension{name: "a".to_string(), data: vec![5, 10]}, Extension{name: "b".to_string(), data: vec![]}],
+});
 
 decoder_test!(test_decode_connected_packet, Packet,
 [255u8, 255, 3,
 0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
-Packet::Connected(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+Packet::Connected{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), extensions: vec![]});
 
 decoder_test!(test_decode_aborted_packet, Packet,
 [255u8, 255, 4, b'e', b'r', b'r', 0],
 Packet::Aborted("err".to_string()));
 
+decoder_test!(test_decode_close_packet, Packet,
+[255u8, 255, 5,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
+Packet::Close(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+
+decoder_test!(test_decode_closed_packet, Packet,
+[255u8, 255, 6,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
+Packet::Closed(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+
+decoder_test!(test_decode_path_challenge_packet, Packet,
+[255u8, 255, 7,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4],
+Packet::PathChallenge{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    challenge: uuid::Uuid::from_bytes(&[0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4]).unwrap()
+});
+
+decoder_test!(test_decode_path_response_packet, Packet,
+[255u8, 255, 8,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4],
+Packet::PathResponse{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    challenge: uuid::Uuid::from_bytes(&[0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4]).unwrap()
+});
+
+decoder_test!(test_decode_cookie_packet, Packet,
+[255u8, 255, 9,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+1, 2, 3], //The cookie.
+Packet::Cookie{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), cookie: vec![1, 2, 3]});
+
 decoder_test!(test_decode_heartbeat_packet, Packet,
 [255u8, 254,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
 0, 0, 0, 0, 0, 0, 0, 1,
 0, 0, 0, 0, 0, 0, 0, 5,
 0, 0, 0, 0, 0, 0, 0, 10],
-Packet::Heartbeat{counter: 1, sent: 5, received: 10});
+Packet::Heartbeat{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), counter: 1, sent: 5, received: 10});
 
 decoder_test!(test_decode_echo_packet, Packet,
 [255u8, 253,
@@ -170,6 +215,63 @@ Packet::Data{
     }
 });
 
+#[test]
+fn test_data_packet_flags() {
+    //The flags are bit indices, not masks.
+    let packet = DataPacket{sequence_number: 1, flags: 6, payload: Vec::default(), header: None};
+    assert!(packet.is_reliable() && packet.is_frame_end() && packet.is_frame_start() == false);
+    let packet = DataPacketBuilder::new(1).set_reliable(true).set_header(Some(FrameHeader::new(0, 5))).build();
+    assert!(packet.is_reliable() && packet.is_frame_start() && packet.is_frame_end() == false);
+    //A header only marks the start of a frame.
+    let packet = DataPacketBuilder::new(1).set_header(Some(FrameHeader::new(0, 5))).build();
+    assert!(packet.is_reliable() == false && packet.is_frame_start());
+}
+
 decoder_test!(test_decode_ack_packet, Packet,
 [0u8, 5, 1, 0, 0, 0, 0, 0, 0, 0, 1],
 Packet::Ack{chan: 5, sequence_number: 1});
+
+decoder_test!(test_decode_skip_packet, Packet,
+[0u8, 5, 2,
+0, 0, 0, 0, 0, 0, 0, 3, //first.
+0, 0, 0, 0, 0, 0, 0, 4, //last.
+0, 0, 0, 0, 0, 0, 0, 1], //last reliable frame.
+Packet::Skip{chan: 5, first: 3, last: 4, last_reliable_frame: 1});
+
+decoder_test!(test_decode_skip_ack_packet, Packet,
+[0u8, 5, 3, 0, 0, 0, 0, 0, 0, 0, 3],
+Packet::SkipAck{chan: 5, sequence_number: 3});
+
+decoder_test!(test_decode_message_header, MessageHeader,
+[0u8, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 9],
+MessageHeader{message_type: Some(1), timestamp: Some(9), rpc: None});
+
+decoder_test!(test_decode_rpc_message_header, MessageHeader,
+[0u8, 9, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3],
+MessageHeader{message_type: Some(1), timestamp: None, rpc: Some(Rpc::Response(3))});
+
+#[test]
+fn test_decode_message() {
+    let (header, payload) = decode_message(&[0, 1, 0, 0, 0, 7, 5, 6]).unwrap();
+    assert_eq!(header, MessageHeader{message_type: Some(7), timestamp: None, rpc: None});
+    assert_eq!(payload, &[5, 6]);
+    //Unknown versions and flags, and truncated headers.
+    assert!(decode_message(&[1, 0]).is_err());
+    assert!(decode_message(&[0, 16]).is_err());
+    //A message can't be both a request and a response.
+    assert!(decode_message(&[0, 12, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    assert!(decode_message(&[0, 1, 0, 0]).is_err());
+}
+
+decoder_test!(test_decode_receipt_packet, Packet,
+[0u8, 5, 4,
+0, 0, 0, 0, 0, 0, 0, 9, //sequence number.
+0, 0, 0, 0, 0, 0, 0, 5], //received.
+Packet::Receipt{chan: 5, sequence_number: 9, received: 5});
+
+decoder_test!(test_decode_encrypted_packet, Packet,
+[255u8, 252,
+0, 0, 0, 0, 0, 0, 0, 5, //tag.
+0, 0, 0, 0, 0, 0, 0, 1, //counter.
+1, 2, 3],
+Packet::Encrypted{tag: 5, counter: 1, ciphertext: vec![1, 2, 3]});
@@ -66,23 +66,54 @@ impl Encodable for Packet {
                 try!(STATUS_RESPONSE_SPECIFIER.encode(destination));
                 try!(resp.encode(destination));
             },
-            Packet::Connect(id) => {
+            Packet::Connect{id, ref extensions} => {
                 try!(CONNECTION_CHANNEL.encode(destination));
                 try!(CONNECT_SPECIFIER.encode(destination));
                 try!(id.encode(destination));
+                for e in extensions.iter() {try!(e.encode(destination));}
             },
-            Packet::Connected(id) => {
+            Packet::Connected{id, ref extensions} => {
                 try!(CONNECTION_CHANNEL.encode(destination));
                 try!(CONNECTED_SPECIFIER.encode(destination));
                 try!(id.encode(destination));
+                for e in extensions.iter() {try!(e.encode(destination));}
             },
             Packet::Aborted(ref msg) => {
                 try!(CONNECTION_CHANNEL.encode(destination));
                 try!(ABORTED_SPECIFIER.encode(destination));
                 try!(msg.encode(destination));
             },
-            Packet::Heartbeat{counter, sent, received} => {
+            Packet::Close(id) => {
+                try!(CONNECTION_CHANNEL.encode(destination));
+                try!(CLOSE_SPECIFIER.encode(destination));
+                try!(id.encode(destination));
+            },
+            Packet::Closed(id) => {
+                try!(CONNECTION_CHANNEL.encode(destination));
+                try!(CLOSED_SPECIFIER.encode(destination));
+                try!(id.encode(destination));
+            },
+            Packet::PathChallenge{id, challenge} => {
+                try!(CONNECTION_CHANNEL.encode(destination));
+                try!(PATH_CHALLENGE_SPECIFIER.encode(destination));
+                try!(id.encode(destination));
+                try!(challenge.encode(destination));
+            },
+            Packet::PathResponse{id, challenge} => {
+                try!(CONNECTION_CHANNEL.encode(destination));
+                try!(PATH_RESPONSE_SPECIFIER.encode(destination));
+                try!(id.encode(destination));
+                try!(challenge.encode(destination));
+            },
+            Packet::Cookie{id, ref cookie} => {
+                try!(CONNECTION_CHANNEL.encode(destination));
+                try!(COOKIE_SPECIFIER.encode(destination));
+                try!(id.encode(destination));
+                try!(destination.write_all(cookie).or(Err(TooLarge)));
+            },
+            Packet::Heartbeat{id, counter, sent, received} => {
                 try!(HEARTBEAT_CHANNEL.encode(destination));
+                try!(id.encode(destination));
                 try!(counter.encode(destination));
                 try!(sent.encode(destination));
                 try!(received.encode(destination));
@@ -102,6 +133,30 @@ impl Encodable for Packet {
                 try!(ACK_PACKET_SPECIFIER.encode(destination));
                 try!(sequence_number.encode(destination));
             },
+            Packet::Skip{chan, first, last, last_reliable_frame} => {
+                try!(chan.encode(destination));
+                try!(SKIP_PACKET_SPECIFIER.encode(destination));
+                try!(first.encode(destination));
+                try!(last.encode(destination));
+                try!(last_reliable_frame.encode(destination));
+            },
+            Packet::SkipAck{chan, sequence_number} => {
+                try!(chan.encode(destination));
+                try!(SKIP_ACK_PACKET_SPECIFIER.encode(destination));
+                try!(sequence_number.encode(destination));
+            },
+            Packet::Receipt{chan, sequence_number, received} => {
+                try!(chan.encode(destination));
+                try!(RECEIPT_PACKET_SPECIFIER.encode(destination));
+                try!(sequence_number.encode(destination));
+                try!(received.encode(destination));
+            },
+            Packet::Encrypted{tag, counter, ref ciphertext} => {
+                try!(ENCRYPTED_CHANNEL.encode(destination));
+                try!(tag.encode(destination));
+                try!(counter.encode(destination));
+                try!(destination.write_all(ciphertext).or(Err(TooLarge)));
+            },
         }
     Ok(())
     }
@@ -259,6 +314,17 @@ impl Encodable for DataPacket {
     }
 }
 
+impl Encodable for Extension {
+    fn encode(&self, destination: &mut PacketWriter)->Result<(), PacketEncodingError> {
+        use self::PacketEncodingError::*;
+        if self.data.len() > u16::max_value() as usize {return Err(Invalid);}
+        try!(self.name.encode(destination));
+        try!((self.data.len() as u16).encode(destination));
+        try!(destination.write_all(&self.data).or(Err(TooLarge)));
+        Ok(())
+    }
+}
+
 impl Encodable for FrameHeader {
     fn encode(&self, destination: &mut PacketWriter)->Result<(), PacketEncodingError> {
         use self::PacketEncodingError::*;
@@ -268,6 +334,33 @@ impl Encodable for FrameHeader {
     }
 }
 
+impl Encodable for MessageHeader {
+    fn encode(&self, destination: &mut PacketWriter)->Result<(), PacketEncodingError> {
+        let type_flag = (self.message_type.is_some() as u8) << MESSAGE_TYPE_BIT;
+        let timestamp_flag = (self.timestamp.is_some() as u8) << MESSAGE_TIMESTAMP_BIT;
+        let (rpc_flag, rpc_id) = match self.rpc {
+            Some(Rpc::Request(id)) => (1 << MESSAGE_REQUEST_BIT, Some(id)),
+            Some(Rpc::Response(id)) => (1 << MESSAGE_RESPONSE_BIT, Some(id)),
+            None => (0, None),
+        };
+        try!(MESSAGE_HEADER_VERSION.encode(destination));
+        try!((type_flag | timestamp_flag | rpc_flag).encode(destination));
+        if let Some(t) = self.message_type {try!(t.encode(destination));}
+        if let Some(t) = self.timestamp {try!(t.encode(destination));}
+        if let Some(id) = rpc_id {try!(id.encode(destination));}
+        Ok(())
+    }
+}
+
+///Put a header in front of a message's payload.
+pub fn encode_message(header: &MessageHeader, payload: &[u8])->Vec<u8> {
+    let header_length = header.encoded_len();
+    let mut message = vec![0u8; header_length+payload.len()];
+    header.encode(&mut PacketWriter::new(&mut message[..header_length])).expect("The buffer is the size of the header");
+    message[header_length..].copy_from_slice(payload);
+    message
+}
+
 pub fn encode_packet<P: Borrow<Packet>>(packet: P, buffer: &mut [u8])->Result<usize, PacketEncodingError> {
     let mut writer = PacketWriter::new(buffer);
     packet.borrow().encode(&mut writer).map(|_| writer.written())
@@ -91,20 +91,66 @@ Packet::StatusResponse(StatusResponse::FastnetResponse(true)));
 encoder_test!(test_encode_connect_packet,
 [255, 255, 2,
 0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
-Packet::Connect(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+Packet::Connect{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), extensions: vec![]});
+
+encoder_test!(test_encode_connect_packet_with_extensions,
+[255, 255, 2,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+b'a', 0, 0, 2, 5, 10, //Extension "a" with 2 bytes of data.
+b'b', 0, 0, 0], //And "b" with none.
+Packet::Connect{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    extensions: vec![Extension{name: "a".to_string(), data: vec![5, 10]}, Extension{name: "b".to_string(), data: vec![]}],
+});
 
 encoder_test!(test_encode_connected_packet,
 [255, 255, 3,
 0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
-Packet::Connected(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+Packet::Connected{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), extensions: vec![]});
 
 encoder_test!(test_encode_aborted_packet,
 [255, 255, 4, b'f', b'a', b'i', b'l', 0], //aborted with message "fail".
 Packet::Aborted("fail".to_string()));
 
+encoder_test!(test_encode_close_packet,
+[255, 255, 5,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
+Packet::Close(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+
+encoder_test!(test_encode_closed_packet,
+[255, 255, 6,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f],
+Packet::Closed(uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap()));
+
+encoder_test!(test_encode_path_challenge_packet,
+[255, 255, 7,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4],
+Packet::PathChallenge{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    challenge: uuid::Uuid::from_bytes(&[0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4]).unwrap()
+});
+
+encoder_test!(test_encode_path_response_packet,
+[255, 255, 8,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4],
+Packet::PathResponse{
+    id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(),
+    challenge: uuid::Uuid::from_bytes(&[0x6b, 0x1f, 0x0e, 0x44, 0x93, 0x5a, 0x4c, 0x21, 0x8d, 0x02, 0x7e, 0xc5, 0x11, 0x38, 0xa9, 0xd4]).unwrap()
+});
+
+encoder_test!(test_encode_cookie_packet,
+[255, 255, 9,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+1, 2, 3], //The cookie.
+Packet::Cookie{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), cookie: vec![1, 2, 3]});
+
 encoder_test!(test_encode_heartbeat_packet,
-[255, 254, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 15],
-Packet::Heartbeat{counter: 5, sent: 10, received: 15});
+[255, 254,
+0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f,
+0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 15],
+Packet::Heartbeat{id: uuid::Uuid::from_bytes(&[0x2d, 0x83, 0x36, 0x9c, 0xc2, 0x26, 0x4a, 0x37, 0x97, 0x97, 0x32, 0x06, 0xf5, 0xb9, 0x50, 0x2f]).unwrap(), counter: 5, sent: 10, received: 15});
 
 encoder_test!(test_encode_echo_packet,
 [255u8, 253,
@@ -150,3 +196,43 @@ Packet::Data{
 encoder_test!(test_encode_ack_packet,
 [0u8, 5, 1, 0, 0, 0, 0, 0, 0, 0, 1],
 Packet::Ack{chan: 5, sequence_number: 1});
+
+encoder_test!(test_encode_skip_packet,
+[0u8, 5, 2,
+0, 0, 0, 0, 0, 0, 0, 3, //first.
+0, 0, 0, 0, 0, 0, 0, 4, //last.
+0, 0, 0, 0, 0, 0, 0, 1], //last reliable frame.
+Packet::Skip{chan: 5, first: 3, last: 4, last_reliable_frame: 1});
+
+encoder_test!(test_encode_skip_ack_packet,
+[0u8, 5, 3, 0, 0, 0, 0, 0, 0, 0, 3],
+Packet::SkipAck{chan: 5, sequence_number: 3});
+
+encoder_test!(test_encode_message_header,
+[0u8, 0, //Version and no flags.
+0, 1, 0, 0, 0, 7, //Message type.
+0, 2, 0, 0, 0, 0, 0, 0, 1, 0, //Timestamp.
+0, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 9], //Both.
+MessageHeader::default(),
+MessageHeader{message_type: Some(7), timestamp: None, rpc: None},
+MessageHeader{message_type: None, timestamp: Some(256), rpc: None},
+MessageHeader{message_type: Some(1), timestamp: Some(9), rpc: None});
+
+encoder_test!(test_encode_rpc_message_header,
+[0u8, 4, 0, 0, 0, 0, 0, 0, 0, 3, //Request.
+0, 9, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3], //Typed response.
+MessageHeader{message_type: None, timestamp: None, rpc: Some(Rpc::Request(3))},
+MessageHeader{message_type: Some(1), timestamp: None, rpc: Some(Rpc::Response(3))});
+
+encoder_test!(test_encode_receipt_packet,
+[0u8, 5, 4,
+0, 0, 0, 0, 0, 0, 0, 9, //sequence number.
+0, 0, 0, 0, 0, 0, 0, 5], //received.
+Packet::Receipt{chan: 5, sequence_number: 9, received: 5});
+
+encoder_test!(test_encode_encrypted_packet,
+[255, 252,
+0, 0, 0, 0, 0, 0, 0, 5, //tag.
+0, 0, 0, 0, 0, 0, 0, 1, //counter.
+1, 2, 3],
+Packet::Encrypted{tag: 5, counter: 1, ciphertext: vec![1, 2, 3]});
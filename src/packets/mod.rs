@@ -18,17 +18,56 @@ pub enum Packet {
     StatusResponse(StatusResponse),
 
     //Connection handshake (also channel -1).
-    Connect(uuid::Uuid),
-    Connected(uuid::Uuid),
+    Connect{id: uuid::Uuid, extensions: Vec<Extension>},
+    Connected{id: uuid::Uuid, extensions: Vec<Extension>},
     Aborted(String),
+
+    //Connection closing (also channel -1).
+    Close(uuid::Uuid),
+    Closed(uuid::Uuid),
+
+    //Address validation (also channel -1).
+    PathChallenge{id: uuid::Uuid, challenge: uuid::Uuid},
+    PathResponse{id: uuid::Uuid, challenge: uuid::Uuid},
+
+    //Return-routability check before connecting (also channel -1).
+    Cookie{id: uuid::Uuid, cookie: Vec<u8>},
     
     //Heartbeat (channel -2).
-    Heartbeat{counter: u64, sent: u64, received: u64},
+    Heartbeat{id: uuid::Uuid, counter: u64, sent: u64, received: u64},
 
     Echo{endpoint: uuid::Uuid, uuid: uuid::Uuid},
     
     Data{chan: i16, packet: DataPacket},
-    Ack{chan: i16, sequence_number: u64}
+    Ack{chan: i16, sequence_number: u64},
+    //The reliable frame from first to last expired and won't be sent.  last_reliable_frame is from its header.
+    Skip{chan: i16, first: u64, last: u64, last_reliable_frame: u64},
+    //Acknowledges the skip of the frame starting at sequence_number.
+    SkipAck{chan: i16, sequence_number: u64},
+    //The newest unreliable packet received which asked for a receipt.  Bit n of received is set if sequence_number-n-1 also arrived.
+    Receipt{chan: i16, sequence_number: u64, received: u64},
+
+    //Another packet, encrypted (channel -4).
+    Encrypted{tag: u64, counter: u64, ciphertext: Vec<u8>},
+}
+
+impl Packet {
+    /**True for the packets which are exchanged before a connection is established.
+
+These are never encrypted.*/
+    pub fn is_handshake(&self)->bool {
+        match *self {
+            Packet::StatusRequest(_) | Packet::StatusResponse(_) | Packet::Connect{..} | Packet::Connected{..} | Packet::Aborted(_) | Packet::Cookie{..} => true,
+            _ => false,
+        }
+    }
+}
+
+/**Data for an extension, carried by the connect and connected packets.*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Extension {
+    pub name: String,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -48,27 +87,45 @@ pub enum StatusResponse {
 pub const CONNECTION_CHANNEL: i16 = -1;
 pub const HEARTBEAT_CHANNEL: i16 = -2;
 pub const ECHO_CHANNEL: i16 = -3;
+pub const ENCRYPTED_CHANNEL: i16 = -4;
 
 pub const STATUS_REQUEST_SPECIFIER: u8 = 0;
 pub const STATUS_RESPONSE_SPECIFIER: u8 = 1;
 pub const CONNECT_SPECIFIER: u8 = 2;
 pub const CONNECTED_SPECIFIER: u8 = 3;
 pub const ABORTED_SPECIFIER: u8 = 4;
+pub const CLOSE_SPECIFIER: u8 = 5;
+pub const CLOSED_SPECIFIER: u8 = 6;
+pub const PATH_CHALLENGE_SPECIFIER: u8 = 7;
+pub const PATH_RESPONSE_SPECIFIER: u8 = 8;
+pub const COOKIE_SPECIFIER: u8 = 9;
 
 //These are used both for query and response.
 pub const STATUS_FASTNET_SPECIFIER: u8 = 0;
 pub const STATUS_VERSION_SPECIFIER: u8 = 1;
 pub const STATUS_EXTENSION_SPECIFIER: u8 = 2;
 
+pub const MESSAGE_HEADER_VERSION: u8 = 0;
+//Flag bits for message headers.
+pub const MESSAGE_TYPE_BIT: u8 = 0;
+pub const MESSAGE_TIMESTAMP_BIT: u8 = 1;
+pub const MESSAGE_REQUEST_BIT: u8 = 2;
+pub const MESSAGE_RESPONSE_BIT: u8 = 3;
+
 //Flag bits for data packets, used in the impl of the struct.
 pub const DATA_FRAME_START_BIT: u8 = 0;
 pub const DATA_FRAME_END_BIT: u8 = 1;
 pub const DATA_RELIABLE_BIT: u8 = 2;
+pub const DATA_RECEIPT_BIT: u8 = 3;
 
 pub const DATA_PACKET_SPECIFIER: u8 = 0;
 pub const ACK_PACKET_SPECIFIER: u8 = 1;
+pub const SKIP_PACKET_SPECIFIER: u8 = 2;
+pub const SKIP_ACK_PACKET_SPECIFIER: u8 = 3;
+pub const RECEIPT_PACKET_SPECIFIER: u8 = 4;
 
 pub const FRAME_HEADER_SIZE: usize = 12; //64-bit sequence number and 32-bit length.
+pub const MAX_MESSAGE_HEADER_SIZE: usize = 22; //Version, flags, type, timestamp, and request or response id.
 
 /**Represents the part of a data packet that a channel must use to assemble packets.
 
@@ -83,6 +140,26 @@ pub struct DataPacket {
     header: Option<FrameHeader>
 }
 
+/**The header at the start of every message, once both sides have agreed to use them.
+
+Both fields are up to the application.  Messages sent without a header get the default, which has neither.*/
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct MessageHeader {
+    ///What kind of message this is.
+    pub message_type: Option<u32>,
+    ///When the message was made, in whatever units the application likes.
+    pub timestamp: Option<u64>,
+    ///Set for requests and responses.  Only Fastnet sets this, see `Server::request`.
+    pub rpc: Option<Rpc>,
+}
+
+///Marks a message as a request or the response to one, with the id which ties them together.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Rpc {
+    Request(u64),
+    Response(u64),
+}
+
 //It would be nice to put this somewhere else, but we unfortunately can't.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Default)]
 pub struct FrameHeader {
@@ -96,6 +173,7 @@ pub struct DataPacketBuilder {
     is_reliable: bool,
     is_frame_start: bool,
     is_frame_end: bool,
+    wants_receipt: bool,
     payload: Vec<u8>,
     header: Option<FrameHeader>,
 }
@@ -120,6 +198,7 @@ If a header is provided, the packet automatically has its first flag set.*/
             is_reliable: false,
             is_frame_start: header.is_some(),
             is_frame_end: false,
+            wants_receipt: false,
             payload: payload,
             header: header,
         }
@@ -132,7 +211,7 @@ If a header is provided, the packet automatically has its first flag set.*/
 
     pub fn set_header(mut self, header: Option<FrameHeader>)->Self {
         self.header = header;
-        self.is_reliable = header.is_some();
+        self.is_frame_start = header.is_some();
         self
     }
 
@@ -151,6 +230,12 @@ If a header is provided, the packet automatically has its first flag set.*/
         self
     }
 
+    ///Ask the receiver to report that the packet arrived.  Only meaningful for unreliable packets.
+    pub fn set_wants_receipt(mut self, wants_receipt: bool)->Self {
+        self.wants_receipt = wants_receipt;
+        self
+    }
+
     pub fn set_sequence_number(mut self, sequence_number: u64)->Self {
         self.sequence_number = sequence_number;
         self
@@ -164,7 +249,8 @@ If a header is provided, the packet automatically has its first flag set.*/
         let start_flag  = (self.is_frame_start as u8) << DATA_FRAME_START_BIT;
         let end_flag = (self.is_frame_end as u8) << DATA_FRAME_END_BIT;
         let reliable_flag = (self.is_reliable as u8) << DATA_RELIABLE_BIT;
-        let flags = start_flag | end_flag | reliable_flag;
+        let receipt_flag = (self.wants_receipt as u8) << DATA_RECEIPT_BIT;
+        let flags = start_flag | end_flag | reliable_flag | receipt_flag;
         DataPacket {
             sequence_number: self.sequence_number,
             flags: flags,
@@ -176,15 +262,19 @@ If a header is provided, the packet automatically has its first flag set.*/
 
 impl DataPacket {
     pub fn is_reliable(&self)->bool {
-        (self.flags & DATA_RELIABLE_BIT) > 0
+        (self.flags & (1<<DATA_RELIABLE_BIT)) > 0
     }
 
     pub fn is_frame_start(&self)->bool {
-        (self.flags & DATA_FRAME_START_BIT) > 0
+        (self.flags & (1<<DATA_FRAME_START_BIT)) > 0
     }
 
     pub fn is_frame_end(&self)->bool {
-        (self.flags & DATA_FRAME_END_BIT) > 0
+        (self.flags & (1<<DATA_FRAME_END_BIT)) > 0
+    }
+
+    pub fn wants_receipt(&self)->bool {
+        (self.flags & (1<<DATA_RECEIPT_BIT)) > 0
     }
 
     pub fn sequence_number(&self)->u64 {
@@ -206,6 +296,18 @@ impl DataPacket {
     pub fn into_payload(self)->Vec<u8> {
         self.payload
     }
+
+    ///The length of the data packet once encoded: the sequence number, flags, header, and payload.
+    pub fn encoded_len(&self)->usize {
+        9+self.header.map_or(0, |_| FRAME_HEADER_SIZE)+self.payload.len()
+    }
+}
+
+impl MessageHeader {
+    ///The version, flags, and whichever fields are present.
+    pub fn encoded_len(&self)->usize {
+        2+self.message_type.map_or(0, |_| 4)+self.timestamp.map_or(0, |_| 8)+self.rpc.map_or(0, |_| 8)
+    }
 }
 
 impl FrameHeader {
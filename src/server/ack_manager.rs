@@ -1,5 +1,7 @@
 use std::collections;
 use std::iter;
+use std::mem;
+use async;
 use packets;
 use time;
 
@@ -9,18 +11,50 @@ const INITIAL_DURATION_MULTIPLIER: f64 = 1.1;
 #[derive(Debug, Clone, PartialEq)]
 struct AckRecord {
     packet: packets::Packet,
+    size: usize,
     next_time: u64,
     duration_multiplier: f64,
 }
 
+//A reliable frame whose sender wants to know when it's been acked.
+#[derive(Debug, Clone, PartialEq)]
+struct TrackedFrame {
+    first: u64,
+    //Packets which haven't been acked yet.
+    remaining: u64,
+    handle: async::MessageHandle,
+}
+
 #[derive(Debug)]
 pub struct AckManager {
     packets: collections::BTreeMap<(i16, u64), AckRecord>,
+    //Keyed by channel and the frame's last sequence number.
+    frames: collections::BTreeMap<(i16, u64), TrackedFrame>,
+    //Frames which were completely acked since the last call to drain_acked_frames.
+    acked_frames: Vec<(i16, async::MessageHandle)>,
+    //The total size of the packets, for congestion control.
+    bytes_in_flight: usize,
+    //And per channel, for flow control.
+    channel_bytes_in_flight: collections::HashMap<i16, usize>,
+    //Added to the size of every packet, i.e. for encryption.
+    packet_overhead: usize,
 }
 
 impl AckManager {
     pub fn new()->AckManager {
-        AckManager {packets: collections::BTreeMap::default()}
+        AckManager {
+            packets: collections::BTreeMap::default(),
+            frames: collections::BTreeMap::default(),
+            acked_frames: Vec::default(),
+            bytes_in_flight: 0,
+            channel_bytes_in_flight: collections::HashMap::default(),
+            packet_overhead: 0,
+        }
+    }
+
+    ///Count `overhead` more bytes for each packet submitted from now on.
+    pub fn set_packet_overhead(&mut self, overhead: usize) {
+        self.packet_overhead = overhead;
     }
 
     /**Handles either ack or data.
@@ -31,8 +65,7 @@ Returns true if the packet was handled. Otherwise false.*/
         let mut sn = 0u64;
         match packet {
             packets::Packet::Ack{chan, sequence_number} => {
-                //If in the map, kill it.
-                self.packets.remove(&(chan, sequence_number));
+                self.acknowledge(chan, sequence_number);
                 return true;
             },
             packets::Packet::Data{chan, packet: ref p} => {
@@ -43,14 +76,111 @@ Returns true if the packet was handled. Otherwise false.*/
             _ => {return false}
         }
         //If we get here, it's a data packet. Insert and return true.
-        self.packets.insert((channel, sn), AckRecord{
+        let size = packet_size(&packet)+self.packet_overhead;
+        self.add_in_flight(channel, size);
+        let old = self.packets.insert((channel, sn), AckRecord{
             packet: packet,
+            size: size,
             next_time: time::precise_time_ns()+INITIAL_DURATION,
             duration_multiplier: INITIAL_DURATION_MULTIPLIER,
         });
+        if let Some(r) = old {self.remove_in_flight(channel, r.size);}
         return true;
     }
 
+    /**Forget a packet which the other side acked.
+
+Returns its size, or None if we weren't waiting for it.*/
+    pub fn acknowledge(&mut self, channel: i16, sequence_number: u64)->Option<usize> {
+        let record = self.packets.remove(&(channel, sequence_number));
+        if let Some(ref r) = record {
+            self.remove_in_flight(channel, r.size);
+            self.count_frame_ack(channel, sequence_number);
+        }
+        record.map(|r| r.size)
+    }
+
+    /**Watch for every packet of the frame on `channel` from `first` to `last` being acked.
+
+Must be called before any of the frame's packets are submitted.  See `drain_acked_frames`.*/
+    pub fn track_frame(&mut self, channel: i16, first: u64, last: u64, handle: async::MessageHandle) {
+        self.frames.insert((channel, last), TrackedFrame{first: first, remaining: last-first+1, handle: handle});
+    }
+
+    /**Stop watching the frame on `channel` which ends at `last`, returning its handle if it was being watched.*/
+    pub fn untrack_frame(&mut self, channel: i16, last: u64)->Option<async::MessageHandle> {
+        self.frames.remove(&(channel, last)).map(|f| f.handle)
+    }
+
+    ///The channels and handles of tracked frames which have been completely acked, in the order they finished.
+    pub fn drain_acked_frames(&mut self)->Vec<(i16, async::MessageHandle)> {
+        self.acked_frames.drain(..).collect()
+    }
+
+    ///Stop watching every frame, returning those which weren't completely acked.
+    pub fn drain_tracked_frames(&mut self)->Vec<(i16, async::MessageHandle)> {
+        let frames = mem::replace(&mut self.frames, collections::BTreeMap::default());
+        frames.into_iter().map(|(k, f)| (k.0, f.handle)).collect()
+    }
+
+    fn count_frame_ack(&mut self, channel: i16, sequence_number: u64) {
+        let done = match self.frames.range_mut((channel, sequence_number)..=(channel, u64::max_value())).next() {
+            Some((k, ref mut f)) if f.first <= sequence_number => {
+                f.remaining -= 1;
+                if f.remaining == 0 {Some(*k)} else {None}
+            },
+            _ => None,
+        };
+        if let Some(k) = done {
+            let frame = self.frames.remove(&k).unwrap();
+            self.acked_frames.push((k.0, frame.handle));
+        }
+    }
+
+    pub fn bytes_in_flight(&self)->usize {
+        self.bytes_in_flight
+    }
+
+    pub fn bytes_in_flight_on(&self, channel: i16)->usize {
+        self.channel_bytes_in_flight.get(&channel).cloned().unwrap_or(0)
+    }
+
+    ///The lowest sequence number on `channel` which is waiting for an ack.
+    pub fn lowest_unacked_on(&self, channel: i16)->Option<u64> {
+        self.packets.range((channel, 0)..=(channel, u64::max_value())).next().map(|(k, _)| k.1)
+    }
+
+    ///True if any packet on `channel` from `first` to `last` is waiting for an ack.
+    pub fn has_unacked_in(&self, channel: i16, first: u64, last: u64)->bool {
+        self.packets.range((channel, first)..=(channel, last)).next().is_some()
+    }
+
+    /**Stop waiting for the packets on `channel` from `first` to `last`, so that they're never resent.*/
+    pub fn forget_range(&mut self, channel: i16, first: u64, last: u64) {
+        let keys: Vec<(i16, u64)> = self.packets.range((channel, first)..=(channel, last)).map(|(k, _)| *k).collect();
+        for k in keys {self.acknowledge(k.0, k.1);}
+    }
+
+    fn add_in_flight(&mut self, channel: i16, size: usize) {
+        self.bytes_in_flight += size;
+        *self.channel_bytes_in_flight.entry(channel).or_insert(0) += size;
+    }
+
+    fn remove_in_flight(&mut self, channel: i16, size: usize) {
+        self.bytes_in_flight -= size;
+        let empty = {
+            let channel_bytes = self.channel_bytes_in_flight.get_mut(&channel).expect("Packets in flight are counted for their channel");
+            *channel_bytes -= size;
+            *channel_bytes == 0
+        };
+        if empty {self.channel_bytes_in_flight.remove(&channel);}
+    }
+
+    //True if nothing is waiting for an ack.
+    pub fn is_empty(&self)->bool {
+        self.packets.is_empty()
+    }
+
     pub fn iter_needs_ack<'A>(&'A mut self)->Box<iter::Iterator<Item=&'A packets::Packet>+'A> {
         let now = time::precise_time_ns();
         let mut res = self.packets.iter_mut().filter(move |i| {
@@ -64,3 +194,43 @@ Returns true if the packet was handled. Otherwise false.*/
         Box::new(res)
     }
 }
+
+//The size of a packet on the wire, not counting encryption.  See `ENCRYPTION_OVERHEAD`.
+pub fn packet_size(packet: &packets::Packet)->usize {
+    match *packet {
+        //The checksum, channel, and specifier, then the data packet itself.
+        packets::Packet::Data{ref packet, ..} => 7+packet.encoded_len(),
+        _ => 0,
+    }
+}
+
+///A reliable data packet with a 100 byte payload, for tests.
+#[cfg(test)]
+pub fn test_packet(chan: i16, sn: u64)->packets::Packet {
+    packets::Packet::Data{chan: chan, packet: packets::DataPacketBuilder::with_payload(sn, vec![0; 100]).set_reliable(true).build()}
+}
+
+#[test]
+fn test_frame_tracking() {
+    let mut manager = AckManager::new();
+    manager.track_frame(0, 0, 2, async::MessageHandle(1));
+    manager.track_frame(0, 3, 3, async::MessageHandle(2));
+    manager.track_frame(1, 0, 1, async::MessageHandle(3));
+    for sn in 0..4 {manager.submit_packet(test_packet(0, sn));}
+    for sn in 0..2 {manager.submit_packet(test_packet(1, sn));}
+    manager.acknowledge(0, 3);
+    manager.acknowledge(0, 0);
+    //Duplicate acks don't count twice.
+    manager.acknowledge(0, 0);
+    manager.acknowledge(0, 1);
+    assert_eq!(manager.drain_acked_frames(), vec![(0, async::MessageHandle(2))]);
+    manager.acknowledge(0, 2);
+    assert_eq!(manager.drain_acked_frames(), vec![(0, async::MessageHandle(1))]);
+    //Untracked frames are forgotten without being reported.
+    assert_eq!(manager.untrack_frame(1, 1), Some(async::MessageHandle(3)));
+    manager.forget_range(1, 0, 1);
+    assert!(manager.drain_acked_frames().is_empty());
+    assert!(manager.is_empty());
+    manager.track_frame(2, 0, 0, async::MessageHandle(4));
+    assert_eq!(manager.drain_tracked_frames(), vec![(2, async::MessageHandle(4))]);
+}
@@ -0,0 +1,535 @@
+/*! Asset transfer.
+
+Large files are sent on private channel -5, in chunks which are read from disk as the connection has room for them, so they never have to fit in memory.
+The sender offers a file with its SHA-256 hash, and the receiver accepts at the offset it already has, so a transfer broken by a disconnect can be resumed by offering the same file again.
+See the asset transfer section of the specification for the details.*/
+use async;
+use sha2::{Sha256, Digest};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections;
+use std::fs;
+use std::io::{self, Read, Write};
+use uuid;
+
+pub const ASSET_CHANNEL: i16 = -5;
+//Below the default, so that messages go first.
+pub const ASSET_CHANNEL_PRIORITY: u8 = 4;
+pub const CHUNK_SIZE: usize = 16384;
+//How much asset data may be queued or waiting for acks at once.  Well under the peer's memory limit for the channel.
+pub const ASSET_WINDOW: usize = 65536;
+pub const HASH_SIZE: usize = 32;
+//Offers the application hasn't answered yet.  More are refused.
+const MAX_PENDING_OFFERS: usize = 16;
+const MAX_NAME_LENGTH: usize = 1024;
+
+const OFFER: u8 = 0;
+const ACCEPT: u8 = 1;
+const CHUNK: u8 = 2;
+const COMPLETE: u8 = 3;
+const CANCEL: u8 = 4;
+
+pub const CANCEL_REJECTED: u8 = 0;
+pub const CANCEL_CORRUPT: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetMessage {
+    Offer{transfer: u64, size: u64, hash: [u8; HASH_SIZE], name: String},
+    Accept{transfer: u64, offset: u64},
+    Chunk{transfer: u64, offset: u64, hash: [u8; HASH_SIZE], data: Vec<u8>},
+    Complete{transfer: u64},
+    //`outgoing` is from the point of view of the side sending the cancel, since the same id can be used in both directions.
+    Cancel{transfer: u64, reason: u8, outgoing: bool},
+}
+
+impl AssetMessage {
+    pub fn encode(&self)->Vec<u8> {
+        let mut out = Vec::default();
+        //Writing to a vector can't fail.
+        match *self {
+            AssetMessage::Offer{transfer, size, ref hash, ref name} => {
+                out.push(OFFER);
+                out.write_u64::<BigEndian>(transfer).unwrap();
+                out.write_u64::<BigEndian>(size).unwrap();
+                out.extend_from_slice(hash);
+                out.extend_from_slice(name.as_bytes());
+                out.push(0);
+            },
+            AssetMessage::Accept{transfer, offset} => {
+                out.push(ACCEPT);
+                out.write_u64::<BigEndian>(transfer).unwrap();
+                out.write_u64::<BigEndian>(offset).unwrap();
+            },
+            AssetMessage::Chunk{transfer, offset, ref hash, ref data} => {
+                out.push(CHUNK);
+                out.write_u64::<BigEndian>(transfer).unwrap();
+                out.write_u64::<BigEndian>(offset).unwrap();
+                out.extend_from_slice(hash);
+                out.extend_from_slice(data);
+            },
+            AssetMessage::Complete{transfer} => {
+                out.push(COMPLETE);
+                out.write_u64::<BigEndian>(transfer).unwrap();
+            },
+            AssetMessage::Cancel{transfer, reason, outgoing} => {
+                out.push(CANCEL);
+                out.write_u64::<BigEndian>(transfer).unwrap();
+                out.push(reason);
+                out.push(outgoing as u8);
+            },
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8])->Option<AssetMessage> {
+        decode_message(data).ok()
+    }
+}
+
+fn read_hash(reader: &mut &[u8])->io::Result<[u8; HASH_SIZE]> {
+    let mut hash = [0u8; HASH_SIZE];
+    try!(reader.read_exact(&mut hash));
+    Ok(hash)
+}
+
+fn decode_message(data: &[u8])->io::Result<AssetMessage> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid asset message");
+    let mut reader = data;
+    let kind = try!(reader.read_u8());
+    let transfer = try!(reader.read_u64::<BigEndian>());
+    let message = match kind {
+        OFFER => {
+            let size = try!(reader.read_u64::<BigEndian>());
+            let hash = try!(read_hash(&mut reader));
+            //The name is null-terminated and must be the end of the message.
+            let name = match reader.split_last() {
+                Some((&0, name)) if name.len() > 0 && name.len() <= MAX_NAME_LENGTH && name.contains(&0) == false => try!(String::from_utf8(name.to_vec()).map_err(|_| invalid())),
+                _ => return Err(invalid()),
+            };
+            return Ok(AssetMessage::Offer{transfer: transfer, size: size, hash: hash, name: name});
+        },
+        ACCEPT => AssetMessage::Accept{transfer: transfer, offset: try!(reader.read_u64::<BigEndian>())},
+        CHUNK => {
+            let offset = try!(reader.read_u64::<BigEndian>());
+            let hash = try!(read_hash(&mut reader));
+            return Ok(AssetMessage::Chunk{transfer: transfer, offset: offset, hash: hash, data: reader.to_vec()});
+        },
+        COMPLETE => AssetMessage::Complete{transfer: transfer},
+        CANCEL => {
+            let reason = try!(reader.read_u8());
+            let outgoing = match try!(reader.read_u8()) {
+                0 => false,
+                1 => true,
+                _ => return Err(invalid()),
+            };
+            AssetMessage::Cancel{transfer: transfer, reason: reason, outgoing: outgoing}
+        },
+        _ => return Err(invalid()),
+    };
+    if reader.len() > 0 {return Err(invalid());}
+    Ok(message)
+}
+
+/**Hash a file, returning its size and SHA-256 hash.
+
+This reads the whole file.*/
+pub fn hash_file(file: &mut fs::File)->io::Result<(u64, [u8; HASH_SIZE])> {
+    let mut hasher = Sha256::new();
+    let size = try!(hash_prefix(file, u64::max_value(), &mut hasher));
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(&hasher.finalize());
+    Ok((size, hash))
+}
+
+/**Feed at most `length` bytes from the current position of `file` to `hasher`, returning how many there were.*/
+pub fn hash_prefix(file: &mut fs::File, length: u64, hasher: &mut Sha256)->io::Result<u64> {
+    let mut buffer = vec![0u8; CHUNK_SIZE*4];
+    let mut total = 0u64;
+    while total < length {
+        let wanted = ::std::cmp::min(buffer.len() as u64, length-total) as usize;
+        let got = try!(file.read(&mut buffer[..wanted]));
+        if got == 0 {break;}
+        hasher.update(&buffer[..got]);
+        total += got as u64;
+    }
+    Ok(total)
+}
+
+fn chunk_hash(data: &[u8])->[u8; HASH_SIZE] {
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+#[derive(Debug)]
+enum OutgoingState {
+    Offered,
+    Sending,
+    //Everything is sent, and we're waiting for the receiver to check the hash.
+    Sent,
+}
+
+#[derive(Debug)]
+struct OutgoingTransfer {
+    file: fs::File,
+    size: u64,
+    //The next byte to send.
+    offset: u64,
+    state: OutgoingState,
+}
+
+#[derive(Debug)]
+struct Receiving {
+    file: fs::File,
+    received: u64,
+    //Fed with everything written so far, including what was already on disk.
+    hasher: Sha256,
+}
+
+#[derive(Debug)]
+struct IncomingTransfer {
+    offer: async::AssetOffer,
+    //None until the application accepts.
+    receiving: Option<Receiving>,
+}
+
+/**The transfers of one connection, in both directions.
+
+This doesn't send anything itself.  Methods return the messages to send, and the connection puts them on the asset channel.*/
+#[derive(Debug, Default)]
+pub struct AssetManager {
+    outgoing: collections::BTreeMap<u64, OutgoingTransfer>,
+    incoming: collections::BTreeMap<u64, IncomingTransfer>,
+}
+
+impl AssetManager {
+    pub fn new()->AssetManager {
+        AssetManager::default()
+    }
+
+    pub fn is_empty(&self)->bool {
+        self.outgoing.is_empty() && self.incoming.is_empty()
+    }
+
+    /**Offer a file which has already been hashed.*/
+    pub fn offer(&mut self, transfer: u64, file: fs::File, size: u64, hash: [u8; HASH_SIZE], name: String)->Result<AssetMessage, async::Error> {
+        if self.outgoing.contains_key(&transfer) {return Err(async::Error::InvalidTransfer);}
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('\0') {return Err(async::Error::InvalidTransfer);}
+        self.outgoing.insert(transfer, OutgoingTransfer{file: file, size: size, offset: 0, state: OutgoingState::Offered});
+        Ok(AssetMessage::Offer{transfer: transfer, size: size, hash: hash, name: name})
+    }
+
+    /**Accept an offered transfer, continuing from what's already in `file`.
+
+`file` must be positioned at `offset`, and `hasher` must have been fed everything before it.  The receiver checks the hash right away if the file is already complete.*/
+    pub fn accept<H: async::Handler>(&mut self, id: uuid::Uuid, transfer: u64, file: fs::File, offset: u64, hasher: Sha256, handler: &mut H)->Result<Vec<AssetMessage>, async::Error> {
+        match self.incoming.get_mut(&transfer) {
+            Some(t) if t.receiving.is_none() && offset <= t.offer.size => t.receiving = Some(Receiving{file: file, received: offset, hasher: hasher}),
+            _ => return Err(async::Error::InvalidTransfer),
+        }
+        let mut replies = vec![AssetMessage::Accept{transfer: transfer, offset: offset}];
+        replies.extend(self.check_complete(id, transfer, handler));
+        Ok(replies)
+    }
+
+    /**Stop a transfer in either direction.  For incoming transfers which haven't been accepted, this refuses them.*/
+    pub fn cancel(&mut self, direction: async::TransferDirection, transfer: u64)->Result<AssetMessage, async::Error> {
+        let found = match direction {
+            async::TransferDirection::Outgoing => self.outgoing.remove(&transfer).is_some(),
+            async::TransferDirection::Incoming => self.incoming.remove(&transfer).is_some(),
+        };
+        if found == false {return Err(async::Error::InvalidTransfer);}
+        Ok(AssetMessage::Cancel{transfer: transfer, reason: CANCEL_REJECTED, outgoing: direction == async::TransferDirection::Outgoing})
+    }
+
+    /**Handle a message from the asset channel, returning any replies.*/
+    pub fn handle_message<H: async::Handler>(&mut self, id: uuid::Uuid, data: &[u8], handler: &mut H)->Vec<AssetMessage> {
+        let message = match AssetMessage::decode(data) {
+            Some(m) => m,
+            None => {
+                debug!("Ignoring invalid asset message from {}", id);
+                return Vec::default();
+            },
+        };
+        match message {
+            AssetMessage::Offer{transfer, size, hash, name} => {
+                if self.incoming.contains_key(&transfer) {return Vec::default();}
+                let pending = self.incoming.values().filter(|t| t.receiving.is_none()).count();
+                if pending >= MAX_PENDING_OFFERS {return vec![AssetMessage::Cancel{transfer: transfer, reason: CANCEL_REJECTED, outgoing: false}];}
+                let offer = async::AssetOffer{transfer: transfer, name: name, size: size, hash: hash};
+                handler.asset_offered(id, &offer);
+                self.incoming.insert(transfer, IncomingTransfer{offer: offer, receiving: None});
+                Vec::default()
+            },
+            AssetMessage::Accept{transfer, offset} => {
+                let failed = match self.outgoing.get_mut(&transfer) {
+                    Some(t) => match t.state {
+                        OutgoingState::Offered if offset <= t.size => {
+                            if let Err(e) = io::Seek::seek(&mut t.file, io::SeekFrom::Start(offset)) {
+                                Some(async::Error::IoError(e))
+                            }
+                            else {
+                                t.offset = offset;
+                                t.state = if offset == t.size {OutgoingState::Sent} else {OutgoingState::Sending};
+                                None
+                            }
+                        },
+                        _ => Some(async::Error::TransferCorrupt),
+                    },
+                    None => return Vec::default(),
+                };
+                match failed {
+                    Some(e) => self.fail_outgoing(id, transfer, e, handler),
+                    None => Vec::default(),
+                }
+            },
+            AssetMessage::Chunk{transfer, offset, hash, data} => self.handle_chunk(id, transfer, offset, hash, &data, handler),
+            AssetMessage::Complete{transfer} => {
+                if let Some(t) = self.outgoing.remove(&transfer) {
+                    handler.asset_progress(id, async::TransferDirection::Outgoing, transfer, t.size, t.size);
+                    handler.asset_completed(id, async::TransferDirection::Outgoing, transfer);
+                }
+                Vec::default()
+            },
+            AssetMessage::Cancel{transfer, reason, outgoing} => {
+                let error = if reason == CANCEL_CORRUPT {async::Error::TransferCorrupt} else {async::Error::TransferRejected};
+                //What the peer is sending, we're receiving.
+                if outgoing {
+                    if self.incoming.remove(&transfer).is_some() {handler.asset_failed(id, async::TransferDirection::Incoming, transfer, error);}
+                }
+                else if self.outgoing.remove(&transfer).is_some() {
+                    handler.asset_failed(id, async::TransferDirection::Outgoing, transfer, error);
+                }
+                Vec::default()
+            },
+        }
+    }
+
+    fn handle_chunk<H: async::Handler>(&mut self, id: uuid::Uuid, transfer: u64, offset: u64, hash: [u8; HASH_SIZE], data: &[u8], handler: &mut H)->Vec<AssetMessage> {
+        let result = match self.incoming.get_mut(&transfer) {
+            Some(&mut IncomingTransfer{ref offer, receiving: Some(ref mut r)}) => {
+                if offset != r.received || r.received+data.len() as u64 > offer.size || chunk_hash(data) != hash {
+                    Err(async::Error::TransferCorrupt)
+                }
+                else if let Err(e) = r.file.write_all(data) {
+                    Err(async::Error::IoError(e))
+                }
+                else {
+                    r.hasher.update(data);
+                    r.received += data.len() as u64;
+                    Ok((r.received, offer.size))
+                }
+            },
+            _ => return Vec::default(),
+        };
+        match result {
+            Ok((received, size)) => {
+                handler.asset_progress(id, async::TransferDirection::Incoming, transfer, received, size);
+                self.check_complete(id, transfer, handler)
+            },
+            Err(e) => self.fail_incoming(id, transfer, e, handler),
+        }
+    }
+
+    //If everything has arrived, check the hash and finish.
+    fn check_complete<H: async::Handler>(&mut self, id: uuid::Uuid, transfer: u64, handler: &mut H)->Vec<AssetMessage> {
+        let verified = match self.incoming.get(&transfer) {
+            Some(&IncomingTransfer{ref offer, receiving: Some(ref r)}) if r.received == offer.size => r.hasher.clone().finalize()[..] == offer.hash[..],
+            _ => return Vec::default(),
+        };
+        if verified == false {return self.fail_incoming(id, transfer, async::Error::TransferCorrupt, handler);}
+        let t = self.incoming.remove(&transfer).unwrap();
+        if let Some(mut r) = t.receiving {
+            if let Err(e) = r.file.flush() {
+                return self.fail_incoming(id, transfer, async::Error::IoError(e), handler);
+            }
+        }
+        handler.asset_completed(id, async::TransferDirection::Incoming, transfer);
+        vec![AssetMessage::Complete{transfer: transfer}]
+    }
+
+    fn fail_incoming<H: async::Handler>(&mut self, id: uuid::Uuid, transfer: u64, error: async::Error, handler: &mut H)->Vec<AssetMessage> {
+        let reason = if let async::Error::TransferCorrupt = error {CANCEL_CORRUPT} else {CANCEL_REJECTED};
+        if let Some(t) = self.incoming.remove(&transfer) {
+            //Resuming from bad data would only fail again.
+            if let (Some(r), CANCEL_CORRUPT) = (t.receiving, reason) {let _ = r.file.set_len(0);}
+        }
+        handler.asset_failed(id, async::TransferDirection::Incoming, transfer, error);
+        vec![AssetMessage::Cancel{transfer: transfer, reason: reason, outgoing: false}]
+    }
+
+    fn fail_outgoing<H: async::Handler>(&mut self, id: uuid::Uuid, transfer: u64, error: async::Error, handler: &mut H)->Vec<AssetMessage> {
+        let reason = if let async::Error::TransferCorrupt = error {CANCEL_CORRUPT} else {CANCEL_REJECTED};
+        self.outgoing.remove(&transfer);
+        handler.asset_failed(id, async::TransferDirection::Outgoing, transfer, error);
+        vec![AssetMessage::Cancel{transfer: transfer, reason: reason, outgoing: true}]
+    }
+
+    /**Read the next chunk to send, if any transfer has one.
+
+Failing to read the file cancels the transfer, in which case the cancel is returned instead.*/
+    pub fn next_chunk<H: async::Handler>(&mut self, id: uuid::Uuid, handler: &mut H)->Option<AssetMessage> {
+        let transfer = match self.outgoing.iter().find(|&(_, t)| if let OutgoingState::Sending = t.state {true} else {false}) {
+            Some((&transfer, _)) => transfer,
+            None => return None,
+        };
+        let result = {
+            let t = self.outgoing.get_mut(&transfer).unwrap();
+            let length = ::std::cmp::min(CHUNK_SIZE as u64, t.size-t.offset) as usize;
+            let mut data = vec![0u8; length];
+            match t.file.read_exact(&mut data) {
+                Ok(()) => {
+                    let offset = t.offset;
+                    t.offset += length as u64;
+                    if t.offset == t.size {t.state = OutgoingState::Sent;}
+                    Ok((AssetMessage::Chunk{transfer: transfer, offset: offset, hash: chunk_hash(&data), data: data}, t.offset, t.size))
+                },
+                Err(e) => Err(e),
+            }
+        };
+        match result {
+            Ok((chunk, sent, size)) => {
+                handler.asset_progress(id, async::TransferDirection::Outgoing, transfer, sent, size);
+                Some(chunk)
+            },
+            Err(e) => self.fail_outgoing(id, transfer, async::Error::IoError(e), handler).pop(),
+        }
+    }
+
+    /**The connection is gone, so every transfer has failed.*/
+    pub fn abort_all<H: async::Handler>(&mut self, id: uuid::Uuid, handler: &mut H) {
+        for (&transfer, _) in self.outgoing.iter() {handler.asset_failed(id, async::TransferDirection::Outgoing, transfer, async::Error::ConnectionAborted);}
+        for (&transfer, _) in self.incoming.iter() {handler.asset_failed(id, async::TransferDirection::Incoming, transfer, async::Error::ConnectionAborted);}
+        self.outgoing.clear();
+        self.incoming.clear();
+    }
+}
+
+#[test]
+fn test_asset_messages() {
+    let messages = vec![
+        AssetMessage::Offer{transfer: 1, size: 100000, hash: [7; HASH_SIZE], name: "map.dat".to_string()},
+        AssetMessage::Accept{transfer: 1, offset: 16384},
+        AssetMessage::Chunk{transfer: 1, offset: 16384, hash: [8; HASH_SIZE], data: vec![1, 2, 3]},
+        AssetMessage::Complete{transfer: 1},
+        AssetMessage::Cancel{transfer: 1, reason: CANCEL_CORRUPT, outgoing: true},
+    ];
+    for m in messages {
+        assert_eq!(AssetMessage::decode(&m.encode()), Some(m));
+    }
+    //Truncated, trailing garbage, and an unterminated name.
+    assert_eq!(AssetMessage::decode(&[ACCEPT, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+    let mut complete = AssetMessage::Complete{transfer: 1}.encode();
+    complete.push(0);
+    assert_eq!(AssetMessage::decode(&complete), None);
+    let mut offer = AssetMessage::Offer{transfer: 1, size: 1, hash: [0; HASH_SIZE], name: "x".to_string()}.encode();
+    offer.pop();
+    assert_eq!(AssetMessage::decode(&offer), None);
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct TestHandler {
+    offers: Vec<async::AssetOffer>,
+    completed: Vec<(async::TransferDirection, u64)>,
+    failed: Vec<(async::TransferDirection, u64)>,
+}
+
+#[cfg(test)]
+impl async::Handler for TestHandler {
+    fn asset_offered(&mut self, _: uuid::Uuid, offer: &async::AssetOffer) {
+        self.offers.push(offer.clone());
+    }
+
+    fn asset_completed(&mut self, _: uuid::Uuid, direction: async::TransferDirection, transfer: u64) {
+        self.completed.push((direction, transfer));
+    }
+
+    fn asset_failed(&mut self, _: uuid::Uuid, direction: async::TransferDirection, transfer: u64, _: async::Error) {
+        self.failed.push((direction, transfer));
+    }
+}
+
+#[cfg(test)]
+fn temp_file(name: &str, contents: &[u8])->(::std::path::PathBuf, fs::File) {
+    let path = ::std::env::temp_dir().join(format!("fastnet_{}_{}", name, uuid::Uuid::new_v4()));
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+    file.write_all(contents).unwrap();
+    io::Seek::seek(&mut file, io::SeekFrom::Start(0)).unwrap();
+    (path, file)
+}
+
+//Pass messages between two managers until neither has anything to say.
+#[cfg(test)]
+fn run_transfer(sender: &mut AssetManager, receiver: &mut AssetManager, first: Vec<AssetMessage>, sender_handler: &mut TestHandler, receiver_handler: &mut TestHandler) {
+    let id = uuid::Uuid::new_v4();
+    let mut to_receiver = first;
+    loop {
+        let mut to_sender = Vec::default();
+        for m in to_receiver.drain(..) {to_sender.extend(receiver.handle_message(id, &m.encode(), receiver_handler));}
+        for m in to_sender {to_receiver.extend(sender.handle_message(id, &m.encode(), sender_handler));}
+        while let Some(chunk) = sender.next_chunk(id, sender_handler) {to_receiver.push(chunk);}
+        if to_receiver.is_empty() {break;}
+    }
+}
+
+#[test]
+fn test_asset_transfer_and_resume() {
+    let id = uuid::Uuid::new_v4();
+    let contents: Vec<u8> = (0..CHUNK_SIZE*3+100).map(|i| (i%251) as u8).collect();
+    let (source_path, mut source) = temp_file("source", &contents);
+    let (size, hash) = hash_file(&mut source).unwrap();
+    assert_eq!(size, contents.len() as u64);
+    io::Seek::seek(&mut source, io::SeekFrom::Start(0)).unwrap();
+    //The receiver already has the first chunk and a bit, from an earlier attempt.
+    let (destination_path, mut destination) = temp_file("destination", &contents[..CHUNK_SIZE+10]);
+    let mut sender = AssetManager::new();
+    let mut receiver = AssetManager::new();
+    let mut sender_handler = TestHandler::default();
+    let mut receiver_handler = TestHandler::default();
+    let offer = sender.offer(3, source, size, hash, "level".to_string()).unwrap();
+    assert!(sender.offer(3, fs::File::open(&source_path).unwrap(), size, hash, "level".to_string()).is_err());
+    run_transfer(&mut sender, &mut receiver, vec![offer], &mut sender_handler, &mut receiver_handler);
+    assert_eq!(receiver_handler.offers[0].name, "level");
+    let mut hasher = Sha256::new();
+    let offset = hash_prefix(&mut destination, size, &mut hasher).unwrap();
+    assert_eq!(offset, CHUNK_SIZE as u64+10);
+    let accept = receiver.accept(id, 3, destination, offset, hasher, &mut receiver_handler).unwrap();
+    let replies: Vec<AssetMessage> = accept.iter().flat_map(|m| sender.handle_message(id, &m.encode(), &mut sender_handler)).collect();
+    run_transfer(&mut sender, &mut receiver, replies, &mut sender_handler, &mut receiver_handler);
+    assert_eq!(receiver_handler.completed, vec![(async::TransferDirection::Incoming, 3)]);
+    assert_eq!(sender_handler.completed, vec![(async::TransferDirection::Outgoing, 3)]);
+    assert!(sender.is_empty() && receiver.is_empty());
+    let mut received = Vec::default();
+    fs::File::open(&destination_path).unwrap().read_to_end(&mut received).unwrap();
+    assert!(received == contents);
+    let _ = fs::remove_file(source_path);
+    let _ = fs::remove_file(destination_path);
+}
+
+#[test]
+fn test_asset_corruption() {
+    let id = uuid::Uuid::new_v4();
+    let contents = vec![5u8; 1000];
+    let (source_path, mut source) = temp_file("corrupt_source", &contents);
+    let (size, hash) = hash_file(&mut source).unwrap();
+    io::Seek::seek(&mut source, io::SeekFrom::Start(0)).unwrap();
+    //What the receiver has doesn't match, but it only finds out at the end.
+    let (destination_path, mut destination) = temp_file("corrupt_destination", &[6u8; 500]);
+    let mut sender = AssetManager::new();
+    let mut receiver = AssetManager::new();
+    let mut sender_handler = TestHandler::default();
+    let mut receiver_handler = TestHandler::default();
+    let offer = sender.offer(1, source, size, hash, "bad".to_string()).unwrap();
+    receiver.handle_message(id, &offer.encode(), &mut receiver_handler);
+    let mut hasher = Sha256::new();
+    let offset = hash_prefix(&mut destination, size, &mut hasher).unwrap();
+    let accept = receiver.accept(id, 1, destination, offset, hasher, &mut receiver_handler).unwrap();
+    let replies: Vec<AssetMessage> = accept.iter().flat_map(|m| sender.handle_message(id, &m.encode(), &mut sender_handler)).collect();
+    run_transfer(&mut sender, &mut receiver, replies, &mut sender_handler, &mut receiver_handler);
+    assert_eq!(receiver_handler.failed, vec![(async::TransferDirection::Incoming, 1)]);
+    assert_eq!(sender_handler.failed, vec![(async::TransferDirection::Outgoing, 1)]);
+    //The bad data is thrown away so that trying again starts over.
+    assert_eq!(fs::metadata(&destination_path).unwrap().len(), 0);
+    let _ = fs::remove_file(source_path);
+    let _ = fs::remove_file(destination_path);
+}
@@ -0,0 +1,58 @@
+/*! The `fastnet_unordered_channels` extension.
+
+Reliable unordered channels put something different in frame headers, so both sides have to agree on which channels they are.
+The client lists its unordered channels in the connect packet, and the server refuses the connection if its list is different.*/
+use async;
+use packets::Extension;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections;
+
+pub const UNORDERED_CHANNELS_EXTENSION: &'static str = "fastnet_unordered_channels";
+///The message in the aborted packet when the lists don't match.
+pub const CHANNEL_MODE_MISMATCH: &'static str = "unordered channels differ";
+
+fn unordered_channels(modes: &collections::HashMap<i16, async::ChannelMode>)->Vec<u16> {
+    let mut channels: Vec<u16> = modes.iter().filter(|m| *m.1 == async::ChannelMode::ReliableUnordered).map(|m| *m.0 as u16).collect();
+    channels.sort();
+    channels
+}
+
+/**The extension for the client's connect packet, or None if there are no unordered channels.*/
+pub fn unordered_channels_extension(modes: &collections::HashMap<i16, async::ChannelMode>)->Option<Extension> {
+    let channels = unordered_channels(modes);
+    if channels.is_empty() {return None;}
+    let mut data = vec![0u8; channels.len()*2];
+    for (i, &c) in channels.iter().enumerate() {
+        BigEndian::write_u16(&mut data[i*2..], c);
+    }
+    Some(Extension{name: UNORDERED_CHANNELS_EXTENSION.to_string(), data: data})
+}
+
+/**True if the unordered channels in a connect packet's extensions are the same as ours.  No extension means no unordered channels.*/
+pub fn unordered_channels_match(modes: &collections::HashMap<i16, async::ChannelMode>, extensions: &[Extension])->bool {
+    let theirs = match extensions.iter().find(|e| e.name == UNORDERED_CHANNELS_EXTENSION) {
+        Some(e) if e.data.len()%2 == 0 => e.data.chunks(2).map(BigEndian::read_u16).collect(),
+        Some(_) => return false,
+        None => Vec::default(),
+    };
+    theirs == unordered_channels(modes)
+}
+
+#[test]
+fn test_unordered_channels_extension() {
+    let mut modes = collections::HashMap::new();
+    modes.insert(3, async::ChannelMode::ReliableUnordered);
+    modes.insert(1, async::ChannelMode::ReliableUnordered);
+    modes.insert(2, async::ChannelMode::LatestOnly);
+    let extension = unordered_channels_extension(&modes).unwrap();
+    assert_eq!(extension.data, vec![0, 1, 0, 3]);
+    assert!(unordered_channels_match(&modes, &[extension.clone()]));
+    //Other modes don't need to match.
+    let mut other = modes.clone();
+    other.remove(&2);
+    assert!(unordered_channels_match(&other, &[extension.clone()]));
+    other.remove(&3);
+    assert!(unordered_channels_match(&other, &[extension]) == false);
+    assert!(unordered_channels_match(&collections::HashMap::new(), &[]));
+    assert!(unordered_channels_match(&modes, &[]) == false);
+}
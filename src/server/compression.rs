@@ -0,0 +1,198 @@
+/*! The `fastnet_compression` extension.
+
+Each side lists the dictionaries it has in the connect and connected packets, and only those both have are used.
+Once negotiated, every message starts with a byte saying whether and how it is compressed, so that each side can decide per channel what to compress.
+See the compression section of the specification for the details.*/
+use async;
+use packets::Extension;
+use crc::crc32;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use lz4_flex::block;
+use std::sync::Arc;
+
+pub const COMPRESSION_EXTENSION: &'static str = "fastnet_compression";
+//Keeps the list of dictionaries well inside a connected packet.
+const MAX_DICTIONARIES: usize = 64;
+//Each dictionary is listed as its id and the checksum of its contents.
+const DICTIONARY_ENTRY_SIZE: usize = 8;
+const RAW: u8 = 0;
+///The first byte of messages which aren't compressed.
+pub const UNCOMPRESSED_PREFIX: u8 = RAW;
+const LZ4: u8 = 1;
+const LZ4_DICTIONARY: u8 = 2;
+//LZ4 can't do better than this, so claiming a larger uncompressed size means the message is bad.
+const MAX_RATIO: usize = 255;
+
+//The dictionaries we have, as (id, checksum), sorted by id.
+fn dictionary_list(config: &async::CompressionConfig)->Vec<(u32, u32)> {
+    let mut list: Vec<(u32, u32)> = config.dictionaries.iter().map(|(&id, d)| (id, crc32::checksum_castagnoli(d))).collect();
+    list.sort();
+    list.truncate(MAX_DICTIONARIES);
+    list
+}
+
+fn encode_list(list: &[(u32, u32)])->Vec<u8> {
+    let mut data = vec![0u8; list.len()*DICTIONARY_ENTRY_SIZE];
+    for (i, &(id, checksum)) in list.iter().enumerate() {
+        BigEndian::write_u32(&mut data[i*DICTIONARY_ENTRY_SIZE..], id);
+        BigEndian::write_u32(&mut data[i*DICTIONARY_ENTRY_SIZE+4..], checksum);
+    }
+    data
+}
+
+fn decode_list(data: &[u8])->Option<Vec<(u32, u32)>> {
+    if data.len()%DICTIONARY_ENTRY_SIZE != 0 || data.len()/DICTIONARY_ENTRY_SIZE > MAX_DICTIONARIES {return None;}
+    Some(data.chunks(DICTIONARY_ENTRY_SIZE).map(|c| (BigEndian::read_u32(&c[..4]), BigEndian::read_u32(&c[4..]))).collect())
+}
+
+//The ids of the dictionaries in `theirs` which we have too.
+fn shared(config: &async::CompressionConfig, theirs: &[(u32, u32)])->Vec<u32> {
+    let ours = dictionary_list(config);
+    ours.iter().filter(|d| theirs.contains(d)).map(|d| d.0).collect()
+}
+
+/**The extension for the client's connect packet.*/
+pub fn offer(config: &async::CompressionConfig)->Extension {
+    Extension{name: COMPRESSION_EXTENSION.to_string(), data: encode_list(&dictionary_list(config))}
+}
+
+/**The server's side of the negotiation.
+
+Returns the compressor and the extension for the connected packet, or None if the offer is malformed.*/
+pub fn accept(config: &Arc<async::CompressionConfig>, offer: &[u8])->Option<(Compressor, Extension)> {
+    let theirs = match decode_list(offer) {
+        Some(l) => l,
+        None => return None,
+    };
+    let shared = shared(config, &theirs);
+    let list: Vec<(u32, u32)> = dictionary_list(config).into_iter().filter(|d| shared.contains(&d.0)).collect();
+    let extension = Extension{name: COMPRESSION_EXTENSION.to_string(), data: encode_list(&list)};
+    Some((Compressor{config: config.clone(), dictionaries: shared}, extension))
+}
+
+/**Finish the negotiation using the server's extension from the connected packet.*/
+pub fn from_response(config: &Arc<async::CompressionConfig>, response: &[u8])->Option<Compressor> {
+    let theirs = match decode_list(response) {
+        Some(l) => l,
+        None => return None,
+    };
+    Some(Compressor{config: config.clone(), dictionaries: shared(config, &theirs)})
+}
+
+/**Compresses and decompresses the messages of one connection.*/
+#[derive(Debug)]
+pub struct Compressor {
+    config: Arc<async::CompressionConfig>,
+    //The dictionaries both sides have.
+    dictionaries: Vec<u32>,
+}
+
+impl Compressor {
+    /**Encode a message for `channel`, compressing it if the channel is configured for it and that helps.*/
+    pub fn compress(&self, channel: u16, payload: &[u8])->Vec<u8> {
+        let compressed = match self.config.channels.get(&channel) {
+            Some(c) if payload.len() >= c.threshold => {
+                let dictionary = c.dictionary.and_then(|id| if self.dictionaries.contains(&id) {self.config.dictionaries.get(&id).map(|d| (id, d))} else {None});
+                let mut encoded = Vec::with_capacity(payload.len()/2+9);
+                match dictionary {
+                    Some((id, d)) => {
+                        encoded.push(LZ4_DICTIONARY);
+                        encoded.write_u32::<BigEndian>(id).unwrap();
+                        encoded.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+                        encoded.extend_from_slice(&block::compress_with_dict(payload, d));
+                    },
+                    None => {
+                        encoded.push(LZ4);
+                        encoded.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+                        encoded.extend_from_slice(&block::compress(payload));
+                    },
+                }
+                Some(encoded)
+            },
+            _ => None,
+        };
+        match compressed {
+            Some(c) if c.len() < payload.len()+1 => c,
+            _ => {
+                let mut raw = Vec::with_capacity(payload.len()+1);
+                raw.push(RAW);
+                raw.extend_from_slice(payload);
+                raw
+            },
+        }
+    }
+
+    /**Decode a message.  Returns None if it's malformed or uses a dictionary we don't share.*/
+    pub fn decompress(&self, message: &[u8])->Option<Vec<u8>> {
+        if message.is_empty() {return None;}
+        let (dictionary, rest): (&[u8], &[u8]) = match message[0] {
+            RAW => return Some(message[1..].to_vec()),
+            LZ4 => (&[], &message[1..]),
+            LZ4_DICTIONARY if message.len() >= 5 => {
+                let id = BigEndian::read_u32(&message[1..5]);
+                match self.config.dictionaries.get(&id) {
+                    Some(d) if self.dictionaries.contains(&id) => (d, &message[5..]),
+                    _ => return None,
+                }
+            },
+            _ => return None,
+        };
+        if rest.len() < 4 {return None;}
+        let length = BigEndian::read_u32(&rest[..4]) as usize;
+        let block = &rest[4..];
+        if length > block.len()*MAX_RATIO {return None;}
+        let mut decompressed = vec![0u8; length];
+        match block::decompress_into_with_dict(block, &mut decompressed, dictionary) {
+            Ok(size) if size == length => Some(decompressed),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_compression_negotiation() {
+    let mut client_config = async::CompressionConfig::default();
+    client_config.dictionaries.insert(1, b"shared".to_vec());
+    client_config.dictionaries.insert(2, b"client's version".to_vec());
+    client_config.dictionaries.insert(3, b"only the client".to_vec());
+    let mut server_config = async::CompressionConfig::default();
+    server_config.dictionaries.insert(1, b"shared".to_vec());
+    server_config.dictionaries.insert(2, b"server's version".to_vec());
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(server_config);
+    let (server, response) = accept(&server_config, &offer(&client_config).data).unwrap();
+    let client = from_response(&client_config, &response.data).unwrap();
+    //Dictionaries with the same id but different contents don't count.
+    assert_eq!(server.dictionaries, vec![1]);
+    assert_eq!(client.dictionaries, vec![1]);
+    assert!(accept(&server_config, &[0; 7]).is_none());
+}
+
+#[test]
+fn test_compression() {
+    let dictionary = b"position velocity orientation health".to_vec();
+    let mut config = async::CompressionConfig::default();
+    config.dictionaries.insert(5, dictionary.clone());
+    config.channels.insert(0, async::ChannelCompression{threshold: 16, dictionary: None});
+    config.channels.insert(1, async::ChannelCompression{threshold: 16, dictionary: Some(5)});
+    let config = Arc::new(config);
+    let compressor = Compressor{config: config.clone(), dictionaries: vec![5]};
+    let snapshot: Vec<u8> = b"position velocity orientation health ".iter().cycle().take(1000).cloned().collect();
+    let compressed = compressor.compress(0, &snapshot);
+    assert_eq!(compressed[0], LZ4);
+    assert!(compressed.len() < snapshot.len()/4);
+    assert_eq!(compressor.decompress(&compressed).unwrap(), snapshot);
+    let with_dictionary = compressor.compress(1, &snapshot);
+    assert_eq!(with_dictionary[0], LZ4_DICTIONARY);
+    assert_eq!(compressor.decompress(&with_dictionary).unwrap(), snapshot);
+    //Small messages, other channels, and things which don't compress are sent as they are.
+    assert_eq!(compressor.compress(0, b"short"), b"\x00short".to_vec());
+    assert_eq!(compressor.compress(2, &snapshot)[0], RAW);
+    let noise: Vec<u8> = (0..200u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    assert_eq!(compressor.decompress(&compressor.compress(0, &noise)).unwrap(), noise);
+    //A peer without the dictionary can't read messages which use it.
+    let other = Compressor{config: config, dictionaries: Vec::default()};
+    assert!(other.decompress(&with_dictionary).is_none());
+    //Claiming an absurd size is refused rather than allocated.
+    assert!(compressor.decompress(&[LZ4, 0xff, 0xff, 0xff, 0xff, 0]).is_none());
+}
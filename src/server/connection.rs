@@ -2,16 +2,26 @@ use super::*;
 use packets::*;
 use async;
 use status_translator;
+use frame;
+use constants;
+use congestion::{CongestionController, NewRenoController};
+use std::collections;
 use std::net;
 use std::borrow::{Borrow};
+use std::fmt;
+use std::cmp;
 use std::time;
+use std::sync::Arc;
+use std::fs;
+use std::io;
+use sha2::Sha256;
 use uuid;
 
 #[derive(Debug, Copy, Clone)]
 pub enum ConnectionState {
-    Establishing{listening: bool, compatible_version: bool, attempts: u32, request_id: Option<u64>},
+    Establishing{listening: bool, compatible_version: bool, encryption_known: bool, attempts: u32, request_id: Option<u64>},
     Established,
-    Closing{request_id: Option<u64>},
+    Closing{request_id: Option<u64>, attempts: u32},
     Closed,
 }
 
@@ -29,10 +39,124 @@ pub struct Connection {
     //For timing out.
     pub last_received_packet_time: time::Instant,
     pub ack_manager: AckManager,
+    pub incoming_channels: collections::HashMap<i16, DataPacketHandler>,
+    pub outgoing_channels: collections::HashMap<i16, OutgoingChannel>,
+    //If the peer might have moved, the address it might have moved to and the challenge we sent there.
+    pub path_challenge: Option<(net::SocketAddr, uuid::Uuid)>,
+    //The policy and pinned key only matter while we're connecting.
+    pub encryption_policy: async::EncryptionPolicy,
+    pub pinned_key: Option<[u8; 32]>,
+    pub handshake: Option<ClientHandshake>,
+    //Once this is set, everything but the handshake is encrypted.
+    pub session: Option<Session>,
+    //The extensions in our connect or connected packet, so that resending it sends the same thing.
+    pub handshake_extensions: Vec<Extension>,
+    //Decides how fast data packets go out.
+    pub congestion: Box<CongestionController>,
+    pub pacer: Pacer,
+    //Data packets waiting for the congestion controller to let them go, by channel.
+    pub send_queue: Scheduler,
+    //The per-connection bandwidth cap.  The total one is in the service provider.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    //Drop unreliable messages which are over the bandwidth caps instead of queueing them.
+    pub drop_unreliable: bool,
+    //Set if we'll offer or accept compression.
+    pub compression_config: Option<Arc<async::CompressionConfig>>,
+    //Once compression is negotiated, every message goes through this.
+    pub compressor: Option<Compressor>,
+    //Set once both sides agree to put headers on messages.
+    pub message_headers: bool,
+    pub assets: AssetManager,
+    //Channels whose reliable frames go to the handler as streams.
+    pub streaming_channels: collections::HashSet<i16>,
+    pub incoming_streams: collections::HashMap<i16, IncomingStreamState>,
+    //Channels which don't use the default mode.
+    pub channel_modes: collections::HashMap<i16, async::ChannelMode>,
+    //Reliable frames which stop being resent at some point.
+    pub expiring_frames: Vec<ExpiringFrame>,
+    //Skip packets for expired frames, by channel and first sequence number, which haven't been acknowledged.
+    pub skips: collections::HashMap<(i16, u64), Packet>,
+    //Channels whose unreliable messages ask for receipts.
+    pub receipt_channels: collections::HashSet<i16>,
+    //What arrived of the peer's unreliable packets which asked for receipts, by channel.
+    pub received_unreliable: collections::HashMap<i16, ReceiptWindow>,
+    //Our unreliable messages which haven't been reported yet.
+    pub receipts: ReceiptTracker,
+    //Requests waiting for responses, by request id.
+    pub pending_requests: collections::HashMap<u64, PendingRequest>,
+}
+
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub chan: i16,
+    pub deadline: time::Instant,
+}
+
+#[derive(Debug)]
+pub struct ExpiringFrame {
+    pub chan: i16,
+    pub first: u64,
+    pub last: u64,
+    //From the frame's header.
+    pub last_reliable_frame: u64,
+    pub deadline: time::Instant,
+    pub handle: async::MessageHandle,
+}
+
+//The sending half of a frame channel.
+#[derive(Debug, Default)]
+pub struct OutgoingChannel {
+    pub next_sequence_number: u64,
+    pub last_reliable_frame: u64,
+    //The frame being read from an `io::Read`, and the request to report failure to.
+    pub stream: Option<(frame::StreamEncoder, u64)>,
+    //Sends which came after the stream, in order.
+    pub waiting: collections::VecDeque<PendingSend>,
+}
+
+impl OutgoingChannel {
+    fn is_busy(&self)->bool {
+        self.stream.is_some() || self.waiting.is_empty() == false
+    }
+}
+
+pub enum PendingSend {
+    Message{payload: Vec<u8>, reliable: bool, handle: Option<async::MessageHandle>, deadline: Option<time::Instant>},
+    Stream{reader: Box<io::Read+Send>, length: u64, request_id: u64},
+}
+
+impl fmt::Debug for PendingSend {
+    fn fmt(&self, f: &mut fmt::Formatter)->fmt::Result {
+        match *self {
+            PendingSend::Message{ref payload, reliable, ..} => write!(f, "Message {{ length: {}, reliable: {} }}", payload.len(), reliable),
+            PendingSend::Stream{length, request_id, ..} => write!(f, "Stream {{ length: {}, request_id: {} }}", length, request_id),
+        }
+    }
+}
+
+/**How an incoming stream is being passed to the handler.
+
+Once compression is negotiated, the first byte says whether the frame is compressed.  Uncompressed frames are passed on as they arrive, but compressed ones have to be collected first, up to the memory limit non-streaming channels have.
+The message header, if any, comes next and isn't passed on.  Requests and responses are collected too, since their callbacks take the whole message.*/
+#[derive(Debug)]
+pub enum IncomingStreamState {
+    //The length of the frame, and what's arrived until the compression byte and header are complete.
+    AwaitingPrefix(usize, Vec<u8>),
+    Passing,
+    Collecting(Vec<u8>),
+    //The start was bad, so the rest is ignored.
+    Dropped,
 }
 
 const MAX_STATUS_ATTEMPTS: u32 = 10;
 const MAX_CONNECTION_ATTEMPTS:u32 = 25; //5000 ms divided by 200 ms per attempt, see spec.
+const MAX_CLOSE_ATTEMPTS: u32 = 25; //Also 5000 ms.
+//Used for pacing until the roundtrip estimator has something.
+const DEFAULT_ROUNDTRIP_MS: u64 = 100;
+//Streams are read only while a channel has less than this queued or in flight.
+const STREAM_WINDOW: usize = 65536;
+//Unreliable messages are reported lost if there's no receipt this long after a roundtrip.
+const RECEIPT_GRACE_MS: u64 = 200;
 
 impl Connection {
 
@@ -48,18 +172,102 @@ impl Connection {
             roundtrip_estimator: RoundtripEstimator::new(5),
             last_received_packet_time: time::Instant::now(),
             ack_manager: AckManager::new(),
+            incoming_channels: collections::HashMap::default(),
+            outgoing_channels: collections::HashMap::default(),
+            path_challenge: None,
+            encryption_policy: async::EncryptionPolicy::Preferred,
+            pinned_key: None,
+            handshake: None,
+            session: None,
+            handshake_extensions: Vec::default(),
+            congestion: Box::new(NewRenoController::new()),
+            pacer: Pacer::new(time::Instant::now()),
+            send_queue: Scheduler::with_priority(ASSET_CHANNEL, ASSET_CHANNEL_PRIORITY),
+            bandwidth_limit: None,
+            drop_unreliable: false,
+            compression_config: None,
+            compressor: None,
+            message_headers: false,
+            assets: AssetManager::new(),
+            streaming_channels: collections::HashSet::default(),
+            incoming_streams: collections::HashMap::default(),
+            channel_modes: collections::HashMap::default(),
+            expiring_frames: Vec::default(),
+            skips: collections::HashMap::default(),
+            receipt_channels: collections::HashSet::default(),
+            received_unreliable: collections::HashMap::default(),
+            receipts: ReceiptTracker::new(),
+            pending_requests: collections::HashMap::default(),
         }
     }
 
-    pub fn from_connection_request(address: net::SocketAddr, id: uuid::Uuid)->Connection {
+    /**Make a connection for an incoming connect packet.
+
+Fails with the message for the aborted packet if the client's extensions aren't acceptable.*/
+    pub fn from_connection_request(address: net::SocketAddr, id: uuid::Uuid, extensions: &[Extension], config: &async::EncryptionConfig)->Result<Connection, &'static str> {
         let mut conn = Connection::new(address, id);
+        let offered = extensions.iter().find(|e| e.name == ENCRYPTION_EXTENSION);
+        match (offered, config.policy) {
+            (_, async::EncryptionPolicy::Disabled) => {},
+            (Some(e), _) => {
+                let (session, response) = try!(respond(config, id, &e.data).map_err(|_| "invalid encryption key"));
+                conn.set_session(session);
+                conn.handshake_extensions.push(response);
+            },
+            (None, async::EncryptionPolicy::Required) => return Err("encryption required"),
+            (None, async::EncryptionPolicy::Preferred) => {},
+        }
         conn.state = ConnectionState::Established;
-        conn
+        Ok(conn)
+    }
+
+    /**Accept the compression offered in an incoming connect packet, if we're willing.
+
+Call this before sending the connected packet.*/
+    pub fn accept_compression(&mut self, extensions: &[Extension]) {
+        let offered = extensions.iter().find(|e| e.name == COMPRESSION_EXTENSION);
+        if let (Some(config), Some(e)) = (self.compression_config.as_ref(), offered) {
+            if let Some((compressor, response)) = compression::accept(config, &e.data) {
+                self.compressor = Some(compressor);
+                self.handshake_extensions.push(response);
+            }
+        }
+    }
+
+    /**Agree to message headers if the incoming connect packet offers them.
+
+Call this before sending the connected packet.*/
+    pub fn accept_message_headers(&mut self, extensions: &[Extension]) {
+        let response = extensions.iter().find(|e| e.name == MESSAGE_HEADERS_EXTENSION).and_then(|e| accept_message_headers(&e.data));
+        if let Some(r) = response {
+            self.message_headers = true;
+            self.handshake_extensions.push(r);
+        }
+    }
+
+    /**Whether a packet should be handled by this connection.
+
+`secured_by` is the connection which decrypted the packet, if it was encrypted.  Once a connection is encrypted, only the handshake may be sent in the clear.*/
+    pub fn accepts(&self, packet: &Packet, secured_by: Option<uuid::Uuid>)->bool {
+        match secured_by {
+            Some(id) => id == self.id,
+            None => self.session.is_none() || packet.is_handshake(),
+        }
+    }
+
+    pub fn is_established(&self)->bool {
+        if let ConnectionState::Established = self.state {true}
+        else {false}
+    }
+
+    pub fn is_closed(&self)->bool {
+        if let ConnectionState::Closed = self.state {true}
+        else {false}
     }
 
     pub fn establish<H: async::Handler>(&mut self, request_id: Option<u64>, service: &mut MioServiceProvider<H>) {
         if let ConnectionState::Closed = self.state {
-            self.state = ConnectionState::Establishing{listening: false, compatible_version: false, attempts: 0, request_id: request_id};
+            self.state = ConnectionState::Establishing{listening: false, compatible_version: false, encryption_known: false, attempts: 0, request_id: request_id};
             //get things rolling...
             self.send(Packet::StatusRequest(StatusRequest::FastnetQuery), service);
         }
@@ -67,7 +275,7 @@ impl Connection {
 
     pub fn send<P: Borrow<Packet>, H: async::Handler>(&mut self, packet: P, service: &mut MioServiceProvider<H>)->bool {
         self.sent_packets += 1;
-        service.send(packet, self.address)
+        service.send_secured(packet, self.address, self.session.as_mut())
     }
 
     pub fn handle_incoming_packet<H: async::Handler>(&mut self, packet: &Packet, service: &mut MioServiceProvider<H>)->bool {
@@ -82,34 +290,567 @@ impl Connection {
                 if endpoint != self.endpoint_id {
                     self.send(packet, service);
                 }
-                else {
-                    self.roundtrip_estimator.handle_echo(self.id, uuid, service);
+                else if let Some(rtt) = self.roundtrip_estimator.handle_echo(self.id, uuid, service) {
+                    self.congestion.on_rtt_sample(rtt, time::Instant::now());
+                }
+                true
+            },
+            Packet::Heartbeat{..} => {
+                true
+            },
+            Packet::PathChallenge{id, challenge} => {
+                //Answering from here proves to the other side that we're really at the address we're using.
+                if id == self.id && self.is_established() {
+                    self.send(Packet::PathResponse{id: id, challenge: challenge}, service);
                 }
                 true
             },
-            Packet::Heartbeat{counter: c, sent: s, received: r} => {
+            //Responses only mean something when they come from the address being challenged, which isn't this one.
+            Packet::PathResponse{..} => {
                 true
             },
-            Packet::Connected(id) => {
-                self.handle_connected(id, service);
+            Packet::Connected{id, ref extensions} => {
+                self.handle_connected(id, extensions, service);
                 true
             },
             Packet::Aborted(ref message) => {
                 self.handle_aborted(message, service);
                 true
             },
-            Packet::Ack{..} => {
-                self.ack_manager.submit_packet(packet.clone())
-            }
+            Packet::Cookie{id, ref cookie} => {
+                self.handle_cookie(id, cookie, service);
+                true
+            },
+            Packet::Close(id) => {
+                self.handle_close(id, service);
+                true
+            },
+            Packet::Closed(id) => {
+                self.handle_closed(id, service);
+                true
+            },
+            Packet::Ack{chan, sequence_number} => {
+                if let Some(size) = self.ack_manager.acknowledge(chan, sequence_number) {
+                    self.congestion.on_ack(size, time::Instant::now());
+                    for (c, handle) in self.ack_manager.drain_acked_frames() {service.handler.message_acked(self.id, c as u16, handle);}
+                    //There might be room in the window now.
+                    self.flush_send_queue(service);
+                    if chan == ASSET_CHANNEL {self.send_asset_chunks(service);}
+                    else if self.outgoing_channels.get(&chan).map_or(false, |c| c.stream.is_some()) {self.send_streams(service);}
+                }
+                true
+            },
+            Packet::Data{chan, packet: ref p} => {
+                self.handle_data_packet(chan, p.clone(), service);
+                true
+            },
+            Packet::Skip{chan, first, last, last_reliable_frame} => {
+                self.handle_skip(chan, first, last, last_reliable_frame, service);
+                true
+            },
+            Packet::SkipAck{chan, sequence_number} => {
+                self.skips.remove(&(chan, sequence_number));
+                true
+            },
+            Packet::Receipt{chan, sequence_number, received} => {
+                if chan >= 0 {
+                    for (handle, delivered) in self.receipts.handle_receipt(chan, sequence_number, received) {
+                        if delivered {service.handler.unreliable_delivered(self.id, chan as u16, handle);}
+                        else {service.handler.unreliable_lost(self.id, chan as u16, handle);}
+                    }
+                }
+                true
+            },
             _ => false
         }
     }
 
-    fn handle_connected<H: async::Handler>(&mut self, id: uuid::Uuid, service: &mut MioServiceProvider<H>) {
+    //The handler for an incoming channel, made if this is the first we've heard of it.
+    fn incoming_channel(&mut self, chan: i16)->&mut DataPacketHandler {
+        let streaming = self.streaming_channels.contains(&chan);
+        let mode = self.channel_modes.get(&chan).cloned().unwrap_or_default();
+        self.incoming_channels.entry(chan).or_insert_with(|| {
+            let mut h = DataPacketHandler::new(chan);
+            h.set_streaming(streaming);
+            h.set_mode(mode);
+            h
+        })
+    }
+
+    fn handle_data_packet<H: async::Handler>(&mut self, chan: i16, packet: DataPacket, service: &mut MioServiceProvider<H>) {
+        if self.is_established() == false {return;}
+        //Asset transfer is the only private frame channel.
+        if chan < 0 && chan != ASSET_CHANNEL {return;}
+        if chan >= 0 && packet.is_reliable() == false && packet.wants_receipt() {
+            self.received_unreliable.entry(chan).or_insert_with(ReceiptWindow::new).record(packet.sequence_number());
+        }
+        self.incoming_channel(chan).handle_incoming_packet(packet);
+        self.receive_on(chan, service);
+    }
+
+    fn handle_skip<H: async::Handler>(&mut self, chan: i16, first: u64, last: u64, last_reliable_frame: u64, service: &mut MioServiceProvider<H>) {
+        if self.is_established() == false || chan < 0 {return;}
+        if self.incoming_channel(chan).handle_skip(first, last, last_reliable_frame) {
+            service.send_secured(Packet::SkipAck{chan: chan, sequence_number: first}, self.address, self.session.as_mut());
+        }
+        self.receive_on(chan, service);
+    }
+
+    //Ack and deliver whatever a channel has ready.
+    fn receive_on<H: async::Handler>(&mut self, chan: i16, service: &mut MioServiceProvider<H>) {
+        let id = self.id;
+        let mut asset_messages = Vec::default();
+        let handler = self.incoming_channels.get_mut(&chan).unwrap();
+        handler.do_acks();
+        for sn in handler.drain_acks() {
+            service.send_secured(Packet::Ack{chan: chan, sequence_number: sn}, self.address, self.session.as_mut());
+        }
+        let compressor = self.compressor.as_ref();
+        let headers = self.message_headers;
+        let incoming_streams = &mut self.incoming_streams;
+        let requests = &mut self.pending_requests;
+        let mut responses = Vec::default();
+        handler.deliver_to(|delivery| match delivery {
+            Delivery::Message(message) => match compressor {
+                _ if chan == ASSET_CHANNEL => asset_messages.push(message.to_vec()),
+                Some(c) => match c.decompress(message) {
+                    Some(m) => message_delivery(id, chan, &m, headers, requests, &mut responses, &mut service.handler),
+                    None => debug!("Dropping a message on channel {} which couldn't be decompressed", chan),
+                },
+                None => message_delivery(id, chan, message, headers, requests, &mut responses, &mut service.handler),
+            },
+            d@_ => stream_delivery(id, chan, d, compressor, headers, incoming_streams, requests, &mut responses, &mut service.handler),
+        });
+        for (request_id, response) in responses {
+            self.send_response(chan, request_id, &response, service);
+        }
+        for message in asset_messages {
+            let replies = self.assets.handle_message(id, &message, &mut service.handler);
+            for reply in replies {self.send_asset_message(reply, service);}
+        }
+        //An accept means there's something to send.
+        self.send_asset_chunks(service);
+    }
+
+    /**Send a message on the specified channel.
+
+The packets are queued for the congestion controller.  Reliable messages are remembered by the ack manager and resent until acked, and the handler is told about `handle` once they are.
+Returns false if the message was unreliable and dropped for being over the bandwidth caps.*/
+    pub fn send_message<H: async::Handler>(&mut self, chan: i16, header: &MessageHeader, payload: &[u8], reliable: bool, handle: Option<async::MessageHandle>, service: &mut MioServiceProvider<H>)->bool {
+        let message = self.encode_payload(chan, header, payload);
+        self.queue_message(chan, &message, reliable, handle, None, service)
+    }
+
+    /**Send a reliable message which stops being resent at `deadline`.  The handler is told with `handle` if that happens.*/
+    pub fn send_expiring_message<H: async::Handler>(&mut self, chan: i16, header: &MessageHeader, payload: &[u8], deadline: time::Instant, handle: async::MessageHandle, service: &mut MioServiceProvider<H>) {
+        let message = self.encode_payload(chan, header, payload);
+        self.queue_message(chan, &message, true, Some(handle), Some(deadline), service);
+    }
+
+    /**Send a request which fails if there's no response by `deadline`.
+
+The response or failure goes to the handler with `handle`, which is also the request's id on the wire.*/
+    pub fn send_request<H: async::Handler>(&mut self, chan: i16, payload: &[u8], deadline: time::Instant, handle: async::MessageHandle, service: &mut MioServiceProvider<H>) {
+        //Without headers, there's nowhere to put the request id.
+        if self.message_headers == false {
+            service.handler.response_failed(self.id, chan as u16, handle, async::Error::IncompatibleVersions);
+            return;
+        }
+        let header = MessageHeader{rpc: Some(Rpc::Request(handle.0)), ..MessageHeader::default()};
+        self.pending_requests.insert(handle.0, PendingRequest{chan: chan, deadline: deadline});
+        self.send_message(chan, &header, payload, true, None, service);
+    }
+
+    pub fn send_response<H: async::Handler>(&mut self, chan: i16, request_id: u64, payload: &[u8], service: &mut MioServiceProvider<H>) {
+        //We can't have been sent a request without headers.
+        if self.message_headers == false {return;}
+        let header = MessageHeader{rpc: Some(Rpc::Response(request_id)), ..MessageHeader::default()};
+        self.send_message(chan, &header, payload, true, None, service);
+    }
+
+    ///Stop waiting for a response.  One which arrives anyway is dropped.
+    pub fn cancel_request(&mut self, request_id: u64) {
+        self.pending_requests.remove(&request_id);
+    }
+
+    //Fail requests whose responses didn't come in time.
+    fn expire_requests<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        if self.pending_requests.is_empty() {return;}
+        let now = time::Instant::now();
+        let mut expired: Vec<u64> = self.pending_requests.iter().filter(|r| now >= r.1.deadline).map(|r| *r.0).collect();
+        expired.sort();
+        for request_id in expired {
+            let request = self.pending_requests.remove(&request_id).unwrap();
+            service.handler.response_failed(self.id, request.chan as u16, async::MessageHandle(request_id), async::Error::TimedOut);
+        }
+    }
+
+    //Add the header and compress, if the peer agreed to them.  Only messages get these, not private frames.
+    fn encode_payload(&self, chan: i16, header: &MessageHeader, payload: &[u8])->Vec<u8> {
+        if chan < 0 {return payload.to_vec();}
+        let with_header;
+        let message = if self.message_headers {
+            with_header = encode_message(header, payload);
+            &with_header[..]
+        }
+        else {payload};
+        match self.compressor {
+            Some(ref c) => c.compress(chan as u16, message),
+            None => message.to_vec(),
+        }
+    }
+
+    //Send a message which has already been compressed.
+    fn queue_message<H: async::Handler>(&mut self, chan: i16, payload: &[u8], reliable: bool, handle: Option<async::MessageHandle>, deadline: Option<time::Instant>, service: &mut MioServiceProvider<H>)->bool {
+        if reliable == false && self.drop_unreliable {
+            //Everything already queued goes first, so it counts too.
+            let queued = self.send_queue.queued_bytes();
+            let size: usize = frame::FrameEncoder::new(&mut payload.iter(), chan, 0, 0, false).map(|p| self.wire_size(&p)).sum();
+            if self.bandwidth_allows_backlog(queued+size, service, time::Instant::now()) == false {
+                if let (true, Some(h)) = (self.receipt_channels.contains(&chan), handle) {service.handler.unreliable_lost(self.id, chan as u16, h);}
+                return false;
+            }
+        }
+        {
+            //Wait for the stream ahead of this one.
+            let channel = self.outgoing_channels.entry(chan).or_insert_with(OutgoingChannel::default);
+            if channel.is_busy() {
+                channel.waiting.push_back(PendingSend::Message{payload: payload.to_vec(), reliable: reliable, handle: handle, deadline: deadline});
+                return true;
+            }
+        }
+        self.encode_message(chan, payload, reliable, handle, deadline);
+        self.flush_send_queue(service);
+        true
+    }
+
+    /**What goes in the header of a frame starting at `next`.
+
+Ordered channels use the last reliable frame.  Unordered ones use the lowest sequence number which might not have been acked yet, so that the peer knows what it can stop remembering.*/
+    fn header_sequence_number(&self, chan: i16, next: u64, last_reliable_frame: u64)->u64 {
+        if self.channel_modes.get(&chan) != Some(&async::ChannelMode::ReliableUnordered) {return last_reliable_frame;}
+        let unacked = self.ack_manager.lowest_unacked_on(chan).unwrap_or(next);
+        let queued = self.send_queue.first_sequence_number_on(chan).unwrap_or(next);
+        cmp::min(next, cmp::min(unacked, queued))
+    }
+
+    //Queue the packets for a message which has already been compressed.
+    fn encode_message(&mut self, chan: i16, payload: &[u8], reliable: bool, handle: Option<async::MessageHandle>, deadline: Option<time::Instant>) {
+        let (starting_sequence_number, last_reliable_frame) = {
+            let channel = self.outgoing_channels.entry(chan).or_insert_with(OutgoingChannel::default);
+            (channel.next_sequence_number, channel.last_reliable_frame)
+        };
+        let last_reliable_frame = self.header_sequence_number(chan, starting_sequence_number, last_reliable_frame);
+        let wants_receipt = reliable == false && handle.is_some() && self.receipt_channels.contains(&chan);
+        let packets: Vec<Packet> = frame::FrameEncoder::new(&mut payload.iter(), chan, starting_sequence_number, last_reliable_frame, reliable)
+        .set_wants_receipt(wants_receipt)
+        .collect();
+        let sn = starting_sequence_number+packets.len() as u64;
+        //Tracking has to start before any of the packets can be acked.
+        match handle {
+            Some(h) if reliable => self.ack_manager.track_frame(chan, starting_sequence_number, sn-1, h),
+            Some(h) if wants_receipt => self.receipts.track(chan, starting_sequence_number, sn-1, h, time::Instant::now()),
+            _ => {},
+        }
+        for packet in packets {
+            self.send_queue.push(chan, packet);
+        }
+        {
+            let channel = self.outgoing_channels.get_mut(&chan).unwrap();
+            channel.next_sequence_number = sn;
+            if reliable {channel.last_reliable_frame = starting_sequence_number;}
+        }
+        if let (Some(d), Some(h)) = (deadline, handle) {
+            self.expiring_frames.push(ExpiringFrame{chan: chan, first: starting_sequence_number, last: sn-1, last_reliable_frame: last_reliable_frame, deadline: d, handle: h});
+        }
+    }
+
+    //Give up on frames which weren't acked in time, telling the peer to skip them.
+    fn expire_frames<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        let now = time::Instant::now();
+        let mut index = 0;
+        while index < self.expiring_frames.len() {
+            let (chan, first, last, deadline) = {
+                let f = &self.expiring_frames[index];
+                (f.chan, f.first, f.last, f.deadline)
+            };
+            let queued = self.send_queue.first_sequence_number_on(chan).map_or(false, |sn| sn <= last);
+            let outstanding = queued || self.ack_manager.has_unacked_in(chan, first, last);
+            if outstanding && now < deadline {
+                index += 1;
+                continue;
+            }
+            let frame = self.expiring_frames.remove(index);
+            //Otherwise, it was all acked.
+            if outstanding {
+                //Forgetting the packets counts as acking them, so the frame has to stop being tracked first.
+                self.ack_manager.untrack_frame(chan, last);
+                self.ack_manager.forget_range(chan, first, last);
+                self.send_queue.remove_range(chan, first, last);
+                let skip = Packet::Skip{chan: chan, first: first, last: last, last_reliable_frame: frame.last_reliable_frame};
+                service.send_secured(&skip, self.address, self.session.as_mut());
+                self.skips.insert((chan, first), skip);
+                service.handler.message_failed(self.id, chan as u16, frame.handle, async::Error::MessageExpired);
+            }
+        }
+    }
+
+    //Send receipts for whatever unreliable packets arrived since the last ones.
+    fn send_receipts<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        for (&chan, window) in self.received_unreliable.iter_mut() {
+            if let Some(receipt) = window.take_receipt(chan) {service.send_secured(receipt, self.address, self.session.as_mut());}
+        }
+    }
+
+    //Give up on unreliable messages which should have had a receipt by now.
+    fn expire_receipts<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        if self.receipts.is_empty() {return;}
+        let rtt = self.roundtrip_estimator.get_smoothed_estimate().unwrap_or(time::Duration::from_millis(DEFAULT_ROUNDTRIP_MS));
+        for (chan, handle) in self.receipts.expire(time::Instant::now(), rtt+time::Duration::from_millis(RECEIPT_GRACE_MS)) {
+            service.handler.unreliable_lost(self.id, chan as u16, handle);
+        }
+    }
+
+    /**Send a reliable message read from `reader`.
+
+If the channel is already sending a stream, this waits for it.  Otherwise, packets are made as the channel has room.*/
+    pub fn send_stream<H: async::Handler>(&mut self, chan: i16, reader: Box<io::Read+Send>, length: u64, request_id: u64, service: &mut MioServiceProvider<H>) {
+        {
+            let channel = self.outgoing_channels.entry(chan).or_insert_with(OutgoingChannel::default);
+            if channel.is_busy() {
+                channel.waiting.push_back(PendingSend::Stream{reader: reader, length: length, request_id: request_id});
+                return;
+            }
+        }
+        self.start_stream(chan, reader, length, request_id);
+        self.send_streams(service);
+    }
+
+    fn start_stream(&mut self, chan: i16, reader: Box<io::Read+Send>, length: u64, request_id: u64) {
+        //The peer expects the compression byte, but streams are never compressed.  Nor do they have anything in their headers.
+        let mut prefix = Vec::default();
+        if self.compressor.is_some() {prefix.push(UNCOMPRESSED_PREFIX);}
+        if self.message_headers {prefix.extend_from_slice(&encode_message(&MessageHeader::default(), &[]));}
+        let (next_sequence_number, last_reliable_frame) = {
+            let channel = self.outgoing_channels.entry(chan).or_insert_with(OutgoingChannel::default);
+            (channel.next_sequence_number, channel.last_reliable_frame)
+        };
+        let last_reliable_frame = self.header_sequence_number(chan, next_sequence_number, last_reliable_frame);
+        let channel = self.outgoing_channels.get_mut(&chan).unwrap();
+        let encoder = frame::StreamEncoder::new(reader, length, prefix, chan, channel.next_sequence_number, last_reliable_frame);
+        //Nothing else is sent on the channel until the stream is done, so its sequence numbers can be handed out now.
+        channel.last_reliable_frame = channel.next_sequence_number;
+        channel.next_sequence_number += encoder.packet_count();
+        channel.stream = Some((encoder, request_id));
+    }
+
+    //Make packets for streams until their channels have as much waiting as they're allowed.
+    fn send_streams<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        let channels: Vec<i16> = self.outgoing_channels.iter().filter(|c| c.1.stream.is_some()).map(|c| *c.0).collect();
+        for chan in channels {
+            self.continue_stream(chan, service);
+        }
+        self.flush_send_queue(service);
+    }
+
+    fn continue_stream<H: async::Handler>(&mut self, chan: i16, service: &mut MioServiceProvider<H>) {
+        while self.send_queue.queued_bytes_on(chan)+self.ack_manager.bytes_in_flight_on(chan) < STREAM_WINDOW {
+            let finished = {
+                let channel = self.outgoing_channels.get_mut(&chan).unwrap();
+                let (packet, finished) = match channel.stream {
+                    Some((ref mut s, _)) => (s.next_packet(), s.is_finished()),
+                    None => return,
+                };
+                if let Some(p) = packet {self.send_queue.push(chan, p);}
+                if finished {channel.stream.take()} else {None}
+            };
+            if let Some((mut encoder, request_id)) = finished {
+                if let Some(e) = encoder.take_error() {service.handler.request_failed(request_id, async::Error::IoError(e));}
+                self.send_waiting(chan, service);
+            }
+        }
+    }
+
+    //Send what was waiting for a stream, up to the next stream.
+    fn send_waiting<H: async::Handler>(&mut self, chan: i16, service: &mut MioServiceProvider<H>) {
+        loop {
+            let next = match self.outgoing_channels.get_mut(&chan).and_then(|c| c.waiting.pop_front()) {
+                Some(n) => n,
+                None => return,
+            };
+            match next {
+                //It might have expired while it waited.
+                PendingSend::Message{deadline: Some(d), handle: Some(h), ..} if time::Instant::now() >= d => {
+                    service.handler.message_failed(self.id, chan as u16, h, async::Error::MessageExpired);
+                },
+                PendingSend::Message{payload, reliable, handle, deadline} => self.encode_message(chan, &payload, reliable, handle, deadline),
+                PendingSend::Stream{reader, length, request_id} => {
+                    self.start_stream(chan, reader, length, request_id);
+                    return;
+                },
+            }
+        }
+    }
+
+    fn send_asset_message<H: async::Handler>(&mut self, message: AssetMessage, service: &mut MioServiceProvider<H>) {
+        self.send_message(ASSET_CHANNEL, &MessageHeader::default(), &message.encode(), true, None, service);
+    }
+
+    //Read chunks from disk until the asset channel has as much waiting as it's allowed.
+    fn send_asset_chunks<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        //Closing waits for what's queued, so don't add to it.
+        if self.is_established() == false {return;}
+        let id = self.id;
+        while self.send_queue.queued_bytes_on(ASSET_CHANNEL)+self.ack_manager.bytes_in_flight_on(ASSET_CHANNEL) < ASSET_WINDOW {
+            match self.assets.next_chunk(id, &mut service.handler) {
+                Some(m) => self.send_asset_message(m, service),
+                None => break,
+            }
+        }
+    }
+
+    pub fn offer_asset<H: async::Handler>(&mut self, transfer: u64, file: fs::File, size: u64, hash: [u8; HASH_SIZE], name: String, service: &mut MioServiceProvider<H>)->Result<(), async::Error> {
+        let offer = try!(self.assets.offer(transfer, file, size, hash, name));
+        self.send_asset_message(offer, service);
+        Ok(())
+    }
+
+    pub fn accept_asset<H: async::Handler>(&mut self, transfer: u64, file: fs::File, offset: u64, hasher: Sha256, service: &mut MioServiceProvider<H>)->Result<(), async::Error> {
+        let messages = try!(self.assets.accept(self.id, transfer, file, offset, hasher, &mut service.handler));
+        for m in messages {self.send_asset_message(m, service);}
+        Ok(())
+    }
+
+    pub fn cancel_asset<H: async::Handler>(&mut self, direction: async::TransferDirection, transfer: u64, service: &mut MioServiceProvider<H>)->Result<(), async::Error> {
+        let cancel = try!(self.assets.cancel(direction, transfer));
+        self.send_asset_message(cancel, service);
+        Ok(())
+    }
+
+    //Everything after the handshake is encrypted once there's a session, so data packets get bigger.
+    fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+        self.ack_manager.set_packet_overhead(ENCRYPTION_OVERHEAD);
+        self.send_queue.set_packet_overhead(ENCRYPTION_OVERHEAD);
+    }
+
+    fn packet_overhead(&self)->usize {
+        if self.session.is_some() {ENCRYPTION_OVERHEAD} else {0}
+    }
+
+    //The size of a data packet as it will be sent, which is what the congestion window, pacer, and caps count.
+    fn wire_size(&self, packet: &Packet)->usize {
+        packet_size(packet)+self.packet_overhead()
+    }
+
+    fn bandwidth_allows<H: async::Handler>(&mut self, bytes: usize, service: &mut MioServiceProvider<H>, now: time::Instant)->bool {
+        self.bandwidth_limit.as_mut().map_or(true, |l| l.allows(bytes, now)) && service.bandwidth_limit.as_mut().map_or(true, |l| l.allows(bytes, now))
+    }
+
+    //Like bandwidth_allows, for bytes which may be more than the bursts.
+    fn bandwidth_allows_backlog<H: async::Handler>(&mut self, bytes: usize, service: &mut MioServiceProvider<H>, now: time::Instant)->bool {
+        self.bandwidth_limit.as_mut().map_or(true, |l| l.allows_backlog(bytes, now)) && service.bandwidth_limit.as_mut().map_or(true, |l| l.allows_backlog(bytes, now))
+    }
+
+    fn consume_bandwidth<H: async::Handler>(&mut self, bytes: usize, service: &mut MioServiceProvider<H>, now: time::Instant) {
+        if let Some(ref mut l) = self.bandwidth_limit {l.consume(bytes, now);}
+        if let Some(ref mut l) = service.bandwidth_limit {l.consume(bytes, now);}
+    }
+
+    /**Send as much of the queue as the congestion controller allows.
+
+Reliable packets wait for room in the window, and everything is paced and held to the bandwidth caps.
+A reliable packet also waits if the peer might not have room for it: we assume that the peer uses the default per-channel memory limit, and anything it drops would only have to be resent.
+Packets on the same channel stay in order, but a channel which is waiting doesn't hold up the others.  Which channel goes next is up to the scheduler.*/
+    pub fn flush_send_queue<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        let now = time::Instant::now();
+        let rtt = self.roundtrip_estimator.get_smoothed_estimate().unwrap_or(time::Duration::from_millis(DEFAULT_ROUNDTRIP_MS));
+        self.pacer.refill(self.congestion.pacing_rate(rtt), now);
+        let mut waiting_channels = Vec::default();
+        while let Some(chan) = self.send_queue.next(&waiting_channels) {
+            let (size, reliable) = match self.send_queue.peek(chan) {
+                Some(p) => (self.wire_size(p), if let Packet::Data{packet: ref d, ..} = *p {d.is_reliable()} else {false}),
+                None => break,
+            };
+            if reliable {
+                //Something always has to be in flight, or nothing would ever be acked.
+                let window_full = self.ack_manager.is_empty() == false && self.ack_manager.bytes_in_flight()+size > self.congestion.window();
+                let channel_in_flight = self.ack_manager.bytes_in_flight_on(chan);
+                let channel_full = channel_in_flight > 0 && channel_in_flight+size > constants::PER_CHANNEL_MEMORY_LIMIT_DEFAULT;
+                if window_full || channel_full {
+                    waiting_channels.push(chan);
+                    continue;
+                }
+            }
+            //The caps cover the whole connection, so nothing else can go either.
+            if self.bandwidth_allows(size, service, now) == false {break;}
+            if self.pacer.take(size) == false {break;}
+            self.consume_bandwidth(size, service, now);
+            let packet = self.send_queue.pop(chan).unwrap();
+            if reliable {self.ack_manager.submit_packet(packet.clone());}
+            self.send(packet, service);
+        }
+    }
+
+    //True if every data packet has been sent and acked.
+    fn is_idle(&self)->bool {
+        self.ack_manager.is_empty() && self.send_queue.is_empty() && self.skips.is_empty() && self.outgoing_channels.values().all(|c| c.is_busy() == false)
+    }
+
+    /**Change how a channel delivers what it receives.  Channels can't become or stop being unordered once connected.*/
+    pub fn set_channel_mode(&mut self, chan: i16, mode: async::ChannelMode) {
+        let unordered = async::ChannelMode::ReliableUnordered;
+        if mode == unordered || self.channel_modes.get(&chan) == Some(&unordered) {return;}
+        self.channel_modes.insert(chan, mode);
+        if let Some(h) = self.incoming_channels.get_mut(&chan) {h.set_mode(mode);}
+    }
+
+    /**Turn receipts for unreliable packets sent on a channel on or off.*/
+    pub fn set_unreliable_receipts(&mut self, chan: i16, enabled: bool) {
+        if enabled {self.receipt_channels.insert(chan);}
+        else {self.receipt_channels.remove(&chan);}
+    }
+
+    /**Turn streaming on or off for a channel.*/
+    pub fn set_streaming(&mut self, chan: i16, streaming: bool) {
+        if streaming {self.streaming_channels.insert(chan);}
+        else {self.streaming_channels.remove(&chan);}
+        if let Some(h) = self.incoming_channels.get_mut(&chan) {h.set_streaming(streaming);}
+    }
+
+    //Resend reliable packets which haven't been acked in time.  Losing any of them means we're sending too fast.
+    fn resend_unacked<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        let now = time::Instant::now();
+        //Skips are small and few, so they're resent every time until acknowledged.
+        for skip in self.skips.values() {
+            service.send_secured(skip, self.address, self.session.as_mut());
+        }
+        let mut lost = 0;
+        let overhead = self.packet_overhead();
+        for i in self.ack_manager.iter_needs_ack() {
+            service.send_secured(i, self.address, self.session.as_mut());
+            lost += packet_size(i)+overhead;
+        }
+        if lost > 0 {
+            self.congestion.on_loss(now);
+            //Resends can't wait, but they still count.
+            self.consume_bandwidth(lost, service, now);
+        }
+    }
+
+    fn handle_connected<H: async::Handler>(&mut self, id: uuid::Uuid, extensions: &[Extension], service: &mut MioServiceProvider<H>) {
         //per the spec, ignore any connected packet that doesn't echo our id.
         if id != self.id {return;}
-        if let ConnectionState::Establishing{listening, compatible_version, request_id, ..} = self.state {
-            if listening && compatible_version {
+        if let ConnectionState::Establishing{listening, compatible_version, encryption_known, request_id, ..} = self.state {
+            if listening && compatible_version && encryption_known {
+                if let Err(e) = self.finish_handshake(extensions) {
+                    //Anyone could have sent a bad confirmation, so that only means we keep waiting.
+                    if let Some(e) = e {
+                        if let Some(r) = request_id {service.handler.request_failed(r, e);}
+                        self.state = ConnectionState::Closed;
+                    }
+                    return;
+                }
                 //The spec says that heartbeats don't count any packets that happen before full establishment.
                 self.sent_packets = 0;
                 self.received_packets = 0;
@@ -120,17 +861,158 @@ impl Connection {
         //Otherwise, we shouldn't be receiving this yet so just drop it.
     }
 
+    //Sets up encryption from the server's extensions.
+    //Fails with Some(error) if the connection has to be abandoned, or None if the packet should just be ignored.
+    fn finish_handshake(&mut self, extensions: &[Extension])->Result<(), Option<async::Error>> {
+        //The server only answers with compression if we offered it.
+        let compressor = match (self.compression_config.as_ref(), extensions.iter().find(|e| e.name == COMPRESSION_EXTENSION)) {
+            (Some(config), Some(e)) => match compression::from_response(config, &e.data) {
+                Some(c) => Some(c),
+                None => return Err(None),
+            },
+            _ => None,
+        };
+        let message_headers = extensions.iter().find(|e| e.name == MESSAGE_HEADERS_EXTENSION).map_or(false, |e| message_headers_accepted(&e.data));
+        let required = self.encryption_policy == async::EncryptionPolicy::Required;
+        let response = extensions.iter().find(|e| e.name == ENCRYPTION_EXTENSION);
+        let session = match (self.handshake.as_ref(), response) {
+            (Some(h), Some(r)) => match h.finish(self.id, &r.data) {
+                Ok(s) => s,
+                Err(HandshakeError::KeyMismatch) => return Err(Some(async::Error::KeyMismatch)),
+                Err(HandshakeError::Invalid) => return Err(None),
+            },
+            //The server didn't want to encrypt.
+            (_, None) if required => return Err(Some(async::Error::EncryptionUnavailable)),
+            _ => {
+                self.compressor = compressor;
+                self.message_headers = message_headers;
+                return Ok(());
+            },
+        };
+        self.set_session(session);
+        self.handshake = None;
+        self.compressor = compressor;
+        self.message_headers = message_headers;
+        Ok(())
+    }
+
+    fn handle_cookie<H: async::Handler>(&mut self, id: uuid::Uuid, cookie: &[u8], service: &mut MioServiceProvider<H>) {
+        if id != self.id {return;}
+        if let ConnectionState::Establishing{listening: true, compatible_version: true, encryption_known: true, ..} = self.state {
+            //A newer cookie replaces the old one, which has probably expired.
+            self.handshake_extensions.retain(|e| e.name != COOKIE_EXTENSION);
+            self.handshake_extensions.push(Extension{name: COOKIE_EXTENSION.to_string(), data: cookie.to_vec()});
+            let connect = Packet::Connect{id: self.id, extensions: self.handshake_extensions.clone()};
+            self.send(connect, service);
+        }
+    }
+
     fn handle_aborted<H: async::Handler>(&mut self, message: &str, service: &mut MioServiceProvider<H>) {
         if let ConnectionState::Establishing{listening, compatible_version, request_id, ..} = self.state {
             if listening && compatible_version {
                 self.state = ConnectionState::Closed;
-                if let Some(id) = request_id {service.handler.request_failed(id, async::Error::ConnectionAborted);}
+                let error = if message == CHANNEL_MODE_MISMATCH {async::Error::ChannelModeMismatch} else {async::Error::ConnectionAborted};
+                if let Some(id) = request_id {service.handler.request_failed(id, error);}
             }
         }
     }
 
+    fn handle_close<H: async::Handler>(&mut self, id: uuid::Uuid, service: &mut MioServiceProvider<H>) {
+        if id != self.id {return;}
+        //We always answer, because the other side keeps sending close until it hears from us.
+        self.send(Packet::Closed(id), service);
+        self.set_closed(async::Error::ConnectionAborted, service);
+    }
+
+    fn handle_closed<H: async::Handler>(&mut self, id: uuid::Uuid, service: &mut MioServiceProvider<H>) {
+        if id != self.id {return;}
+        if let ConnectionState::Closing{..} = self.state {
+            self.set_closed(async::Error::ConnectionAborted, service);
+        }
+    }
+
+    /**A heartbeat or, once encrypted, any packet for this connection arrived from another address.
+
+We don't move until the peer proves that it's there, otherwise anyone who knows the id could redirect the connection.*/
+    pub fn challenge_path<H: async::Handler>(&mut self, address: net::SocketAddr, service: &mut MioServiceProvider<H>) {
+        if self.is_established() == false {return;}
+        //Resend the same challenge if we already have one out, so that a slow response still counts.
+        let challenge = match self.path_challenge {
+            Some((a, c)) if a == address => c,
+            _ => uuid::Uuid::new_v4(),
+        };
+        self.path_challenge = Some((address, challenge));
+        service.send_secured(Packet::PathChallenge{id: self.id, challenge: challenge}, address, self.session.as_mut());
+    }
+
+    /**Handle a path response from `address`.
+
+Returns true if it answers our challenge, in which case the connection now uses `address`.*/
+    pub fn handle_path_response(&mut self, address: net::SocketAddr, challenge: uuid::Uuid)->bool {
+        if self.path_challenge != Some((address, challenge)) {return false;}
+        self.path_challenge = None;
+        self.address = address;
+        self.last_received_packet_time = time::Instant::now();
+        true
+    }
+
+    /**Begin closing the connection.
+
+Outstanding reliable packets are given a chance to arrive before the peer is told.  The handler is notified with `request_id` once the peer acknowledges or we give up.*/
+    pub fn close<H: async::Handler>(&mut self, request_id: Option<u64>, service: &mut MioServiceProvider<H>) {
+        if let ConnectionState::Established = self.state {
+            self.state = ConnectionState::Closing{request_id: request_id, attempts: 0};
+            if self.is_idle() {
+                let id = self.id;
+                self.send(Packet::Close(id), service);
+            }
+        }
+    }
+
+    /**Mark the connection closed, telling the handler whatever it needs to know.
+
+`error` is only used if the connection was still being established.  Used for timeouts and shutdown as well as normal closing.*/
+    pub fn set_closed<H: async::Handler>(&mut self, error: async::Error, service: &mut MioServiceProvider<H>) {
+        self.assets.abort_all(self.id, &mut service.handler);
+        //Streams which were started can't be finished.
+        for (chan, state) in self.incoming_streams.drain() {
+            if let IncomingStreamState::Passing = state {service.handler.incoming_stream_end(self.id, chan as u16, false);}
+        }
+        //Messages which were sent and never acked, then those which never got sent.
+        for (chan, handle) in self.ack_manager.drain_tracked_frames() {
+            service.handler.message_failed(self.id, chan as u16, handle, async::Error::ConnectionAborted);
+        }
+        self.expiring_frames.clear();
+        for (chan, handle) in self.receipts.drain() {
+            service.handler.unreliable_lost(self.id, chan as u16, handle);
+        }
+        let mut requests: Vec<(u64, PendingRequest)> = self.pending_requests.drain().collect();
+        requests.sort_by_key(|r| r.0);
+        for (request_id, request) in requests {
+            service.handler.response_failed(self.id, request.chan as u16, async::MessageHandle(request_id), async::Error::ConnectionAborted);
+        }
+        for (chan, channel) in self.outgoing_channels.iter_mut() {
+            if let Some((_, request_id)) = channel.stream.take() {service.handler.request_failed(request_id, async::Error::ConnectionAborted);}
+            for pending in channel.waiting.drain(..) {
+                match pending {
+                    PendingSend::Stream{request_id, ..} => service.handler.request_failed(request_id, async::Error::ConnectionAborted),
+                    PendingSend::Message{reliable: true, handle: Some(h), ..} => service.handler.message_failed(self.id, *chan as u16, h, async::Error::ConnectionAborted),
+                    PendingSend::Message{reliable: false, handle: Some(h), ..} if self.receipt_channels.contains(chan) => service.handler.unreliable_lost(self.id, *chan as u16, h),
+                    _ => {},
+                }
+            }
+        }
+        match self.state {
+            ConnectionState::Establishing{request_id: Some(id), ..} => service.handler.request_failed(id, error),
+            ConnectionState::Established => service.handler.disconnected(self.id, None),
+            ConnectionState::Closing{request_id, ..} => service.handler.disconnected(self.id, request_id),
+            _ => {},
+        }
+        self.state = ConnectionState::Closed;
+    }
+
     fn handle_status_response<H: async::Handler>(&mut self, resp: &StatusResponse, service: &mut MioServiceProvider<H>) {
-        if let ConnectionState::Establishing{mut listening, mut compatible_version, mut attempts, request_id} = self.state {
+        if let ConnectionState::Establishing{mut listening, mut compatible_version, mut encryption_known, attempts, request_id} = self.state {
             match *resp {
                 StatusResponse::FastnetResponse(new_listening) if listening == false => {
                     if new_listening == false {
@@ -148,59 +1030,117 @@ impl Connection {
                         return;
                     }
                     compatible_version = true;
-                }
+                    //No point asking if we aren't going to encrypt.
+                    if self.encryption_policy == async::EncryptionPolicy::Disabled {encryption_known = true;}
+                    else {self.send(Packet::StatusRequest(StatusRequest::ExtensionQuery(ENCRYPTION_EXTENSION.to_string())), service);}
+                },
+                StatusResponse::ExtensionResponse{ref name, supported} if compatible_version && encryption_known == false && name == ENCRYPTION_EXTENSION => {
+                    if supported {
+                        let handshake = ClientHandshake::new(self.pinned_key);
+                        self.handshake_extensions = vec![handshake.extension()];
+                        self.handshake = Some(handshake);
+                    }
+                    else if self.encryption_policy == async::EncryptionPolicy::Required {
+                        if let Some(id) = request_id {service.handler.request_failed(id, async::Error::EncryptionUnavailable);}
+                        self.state = ConnectionState::Closed;
+                        return;
+                    }
+                    encryption_known = true;
+                },
                 _ => {}
             }
-            if listening && compatible_version {
-                let id = self.id;
-                self.send(Packet::Connect(id), service);
+            if listening && compatible_version && encryption_known {
+                //Until the server gives us a cookie, this makes the connect packet at least as large as the server's answer.
+                if self.handshake_extensions.iter().any(|e| e.name == COOKIE_EXTENSION) == false {
+                    self.handshake_extensions.push(Extension{name: COOKIE_EXTENSION.to_string(), data: vec![0; COOKIE_SIZE]});
+                }
+                if let Some(extension) = unordered_channels_extension(&self.channel_modes) {
+                    if self.handshake_extensions.iter().any(|e| e.name == UNORDERED_CHANNELS_EXTENSION) == false {
+                        self.handshake_extensions.push(extension);
+                    }
+                }
+                if self.handshake_extensions.iter().any(|e| e.name == MESSAGE_HEADERS_EXTENSION) == false {
+                    self.handshake_extensions.push(offer_message_headers());
+                }
+                if let Some(ref config) = self.compression_config {
+                    if self.handshake_extensions.iter().any(|e| e.name == COMPRESSION_EXTENSION) == false {
+                        self.handshake_extensions.push(compression::offer(config));
+                    }
+                }
+                let connect = Packet::Connect{id: self.id, extensions: self.handshake_extensions.clone()};
+                self.send(connect, service);
             }
-            self.state = ConnectionState::Establishing{attempts: 0, listening: listening, compatible_version: compatible_version, request_id: request_id};
+            self.state = ConnectionState::Establishing{attempts: 0, listening: listening, compatible_version: compatible_version, encryption_known: encryption_known, request_id: request_id};
         }
     }
 
     pub fn tick1000<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
         if let ConnectionState::Established = self.state {
-            let heartbeat = Packet::Heartbeat{counter: self.heartbeat_counter, sent: self.sent_packets, received: self.received_packets};
+            let heartbeat = Packet::Heartbeat{id: self.id, counter: self.heartbeat_counter, sent: self.sent_packets, received: self.received_packets};
             self.heartbeat_counter += 1;
             self.send(heartbeat, service);
         }
     }
 
+    //Sends whatever pacing held back.
+    pub fn tick10<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
+        match self.state {
+            ConnectionState::Established | ConnectionState::Closing{..} => {
+                self.send_streams(service);
+                self.send_receipts(service);
+                self.expire_requests(service);
+            },
+            _ => {},
+        }
+    }
+
     pub fn tick200<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
         match self.state {
-            ConnectionState::Establishing{mut attempts, listening, compatible_version, request_id} => {
+            ConnectionState::Establishing{mut attempts, listening, compatible_version, encryption_known, request_id} => {
                 attempts += 1;
-                if listening == false {
-                    if attempts > MAX_STATUS_ATTEMPTS {
-                        if let Some(id) = request_id {service.handler.request_failed(id, async::Error::TimedOut);}
-                        self.state = ConnectionState::Closed;
-                        return;
-                    }
-                    service.send(Packet::StatusRequest(StatusRequest::FastnetQuery), self.address);
+                let (max_attempts, packet) = if listening == false {
+                    (MAX_STATUS_ATTEMPTS, Packet::StatusRequest(StatusRequest::FastnetQuery))
                 }
                 else if compatible_version == false {
-                    if attempts > MAX_STATUS_ATTEMPTS {
-                        if let Some(id) = request_id {service.handler.request_failed(id, async::Error::TimedOut);}
-                        self.state = ConnectionState::Closed;
-                        return;
-                    }
-                    service.send(Packet::StatusRequest(StatusRequest::VersionQuery), self.address);
+                    (MAX_STATUS_ATTEMPTS, Packet::StatusRequest(StatusRequest::VersionQuery))
+                }
+                else if encryption_known == false {
+                    (MAX_STATUS_ATTEMPTS, Packet::StatusRequest(StatusRequest::ExtensionQuery(ENCRYPTION_EXTENSION.to_string())))
                 }
                 else {
-                    if attempts > MAX_CONNECTION_ATTEMPTS {
-                        if let Some(id) = request_id {service.handler.request_failed(id, async::Error::TimedOut);}
-                        self.state = ConnectionState::Closed;
-                        return;
-                    }
-                    service.send(Packet::Connect(self.id), self.address);
+                    (MAX_CONNECTION_ATTEMPTS, Packet::Connect{id: self.id, extensions: self.handshake_extensions.clone()})
+                };
+                if attempts > max_attempts {
+                    if let Some(id) = request_id {service.handler.request_failed(id, async::Error::TimedOut);}
+                    self.state = ConnectionState::Closed;
+                    return;
                 }
+                self.state = ConnectionState::Establishing{attempts: attempts, listening: listening, compatible_version: compatible_version, encryption_known: encryption_known, request_id: request_id};
+                service.send(packet, self.address);
             },
             ConnectionState::Established => {
-                self.roundtrip_estimator.tick(self.address, self.endpoint_id, service);
-                //Send the acks.
-                for i in self.ack_manager.iter_needs_ack() {
-                    service.send(i, self.address);
+                let address = self.address;
+                let session = &mut self.session;
+                self.roundtrip_estimator.tick(self.endpoint_id, |p| {service.send_secured(p, address, session.as_mut());});
+                self.expire_frames(service);
+                self.expire_receipts(service);
+                self.resend_unacked(service);
+            },
+            ConnectionState::Closing{request_id, mut attempts} => {
+                attempts += 1;
+                if attempts > MAX_CLOSE_ATTEMPTS {
+                    self.set_closed(async::Error::TimedOut, service);
+                    return;
+                }
+                self.state = ConnectionState::Closing{request_id: request_id, attempts: attempts};
+                //Finish delivering reliable data before telling the peer.
+                if self.is_idle() {
+                    service.send_secured(Packet::Close(self.id), self.address, self.session.as_mut());
+                }
+                else {
+                    self.expire_frames(service);
+                    self.expire_receipts(service);
+                    self.resend_unacked(service);
                 }
             },
             _ => {},
@@ -208,3 +1148,165 @@ impl Connection {
     }
 
 }
+
+//Pass a decompressed message to the handler, taking off the header if there is one.
+//Requests and responses have their own callbacks, and answers to requests are added to `responses`.
+fn message_delivery<H: async::Handler>(id: uuid::Uuid, chan: i16, message: &[u8], headers: bool, requests: &mut collections::HashMap<u64, PendingRequest>, responses: &mut Vec<(u64, Vec<u8>)>, handler: &mut H) {
+    if headers == false {
+        handler.incoming_message_with_header(id, chan as u16, &MessageHeader::default(), message);
+        return;
+    }
+    let (header, payload) = match decode_message(message) {
+        Ok(m) => m,
+        Err(_) => {
+            debug!("Dropping a message on channel {} with a bad header", chan);
+            return;
+        },
+    };
+    match header.rpc {
+        Some(Rpc::Request(request_id)) => {
+            if let Some(r) = handler.incoming_request(id, chan as u16, request_id, payload) {responses.push((request_id, r));}
+        },
+        //Responses to requests which were cancelled or timed out are dropped.
+        Some(Rpc::Response(request_id)) => {
+            if requests.remove(&request_id).is_some() {handler.response_received(id, chan as u16, async::MessageHandle(request_id), payload);}
+        },
+        None => handler.incoming_message_with_header(id, chan as u16, &header, payload),
+    }
+}
+
+//Pass one piece of a stream to the handler.  Compressed frames are collected and delivered as a whole stream at the end, and requests and responses go to message_delivery.
+pub fn stream_delivery<H: async::Handler>(id: uuid::Uuid, chan: i16, delivery: Delivery, compressor: Option<&Compressor>, headers: bool, streams: &mut collections::HashMap<i16, IncomingStreamState>, requests: &mut collections::HashMap<u64, PendingRequest>, responses: &mut Vec<(u64, Vec<u8>)>, handler: &mut H) {
+    let channel = chan as u16;
+    match delivery {
+        Delivery::StreamStart(length) => {
+            if compressor.is_some() || headers {streams.insert(chan, IncomingStreamState::AwaitingPrefix(length, Vec::default()));}
+            else {
+                streams.insert(chan, IncomingStreamState::Passing);
+                handler.incoming_stream_start_with_header(id, channel, &MessageHeader::default(), length as u64);
+            }
+        },
+        Delivery::StreamData(data) => {
+            let state = match streams.get_mut(&chan) {
+                Some(s) => s,
+                None => return,
+            };
+            let next = match *state {
+                IncomingStreamState::AwaitingPrefix(length, ref mut start) => {
+                    start.extend_from_slice(data);
+                    match read_stream_start(start, compressor.is_some(), headers) {
+                        StreamStart::Incomplete => return,
+                        //Well-behaved senders never compress streams, so there's no reason to hold more than a non-streaming channel would.
+                        StreamStart::Whole if length > constants::PER_CHANNEL_MEMORY_LIMIT_DEFAULT => {
+                            debug!("Dropping a stream of {} bytes on channel {} which would have to be collected", length, chan);
+                            IncomingStreamState::Dropped
+                        },
+                        StreamStart::Whole => IncomingStreamState::Collecting(start.clone()),
+                        StreamStart::Uncompressed(header, payload) => {
+                            handler.incoming_stream_start_with_header(id, channel, &header, (length-(start.len()-payload.len())) as u64);
+                            if payload.is_empty() == false {handler.incoming_stream_data(id, channel, payload);}
+                            IncomingStreamState::Passing
+                        },
+                        StreamStart::Bad => {
+                            debug!("Dropping a stream on channel {} with a bad header", chan);
+                            IncomingStreamState::Dropped
+                        },
+                    }
+                },
+                IncomingStreamState::Dropped => return,
+                IncomingStreamState::Passing => {
+                    handler.incoming_stream_data(id, channel, data);
+                    return;
+                },
+                IncomingStreamState::Collecting(ref mut collected) if collected.len()+data.len() <= constants::PER_CHANNEL_MEMORY_LIMIT_DEFAULT => {
+                    collected.extend_from_slice(data);
+                    return;
+                },
+                IncomingStreamState::Collecting(_) => {
+                    debug!("Dropping a collected stream on channel {} which is longer than it said", chan);
+                    IncomingStreamState::Dropped
+                },
+            };
+            *state = next;
+        },
+        Delivery::StreamEnd(complete) => match streams.remove(&chan) {
+            Some(IncomingStreamState::Passing) => handler.incoming_stream_end(id, channel, complete),
+            Some(IncomingStreamState::Collecting(collected)) if complete => {
+                let m = match compressor {
+                    Some(c) => match c.decompress(&collected) {
+                        Some(m) => m,
+                        None => {
+                            debug!("Dropping a message on channel {} which couldn't be decompressed", chan);
+                            return;
+                        },
+                    },
+                    None => collected,
+                };
+                let (header, payload) = match headers {
+                    true => match decode_message(&m) {
+                        Ok(d) => d,
+                        Err(_) => {
+                            debug!("Dropping a message on channel {} with a bad header", chan);
+                            return;
+                        },
+                    },
+                    false => (MessageHeader::default(), &m[..]),
+                };
+                if header.rpc.is_some() {
+                    message_delivery(id, chan, &m, headers, requests, responses, handler);
+                    return;
+                }
+                handler.incoming_stream_start_with_header(id, channel, &header, payload.len() as u64);
+                if payload.is_empty() == false {handler.incoming_stream_data(id, channel, payload);}
+                handler.incoming_stream_end(id, channel, true);
+            },
+            Some(IncomingStreamState::AwaitingPrefix(..)) if complete => debug!("Dropping a stream on channel {} which ended before its header", chan),
+            //The handler was never told about it.
+            _ => {},
+        },
+        Delivery::Message(_) => {},
+    }
+}
+
+//What the start of a stream says about how to deliver it.
+enum StreamStart<'a> {
+    //Not enough has arrived to tell.
+    Incomplete,
+    //Compressed frames, requests and responses.
+    Whole,
+    //The header and the payload after it.
+    Uncompressed(MessageHeader, &'a [u8]),
+    Bad,
+}
+
+//Read the compression byte and message header, which can be split across packets.
+fn read_stream_start(start: &[u8], compression: bool, headers: bool)->StreamStart {
+    let after_prefix = match compression {
+        true if start.is_empty() => return StreamStart::Incomplete,
+        true if start[0] != UNCOMPRESSED_PREFIX => return StreamStart::Whole,
+        true => &start[1..],
+        false => start,
+    };
+    if headers == false {return StreamStart::Uncompressed(MessageHeader::default(), after_prefix);}
+    match decode_message(after_prefix) {
+        Ok((ref header, _)) if header.rpc.is_some() => StreamStart::Whole,
+        Ok((header, payload)) => StreamStart::Uncompressed(header, payload),
+        Err(_) if after_prefix.len() < MAX_MESSAGE_HEADER_SIZE => StreamStart::Incomplete,
+        Err(_) => StreamStart::Bad,
+    }
+}
+
+#[test]
+fn test_request_without_headers() {
+    use async_tests::{Event, RecordingHandler};
+    use std::sync::mpsc;
+    let (sender, events) = mpsc::channel();
+    let socket = mio::net::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut service = MioServiceProvider::new(socket, RecordingHandler::new(sender, false));
+    let mut conn = Connection::new("127.0.0.1:1".parse().unwrap(), uuid::Uuid::new_v4());
+    conn.state = ConnectionState::Established;
+    //Without the extension, there's nowhere to put the request id.
+    conn.send_request(0, b"x", time::Instant::now()+time::Duration::from_secs(5), async::MessageHandle(1), &mut service);
+    assert_eq!(events.try_recv(), Ok(Event::ResponseFailed(async::MessageHandle(1), "IncompatibleVersions".to_string())));
+    assert!(conn.pending_requests.is_empty() && conn.send_queue.is_empty());
+}
@@ -0,0 +1,118 @@
+/*! Stateless handshake cookies.
+
+A server answers a connect packet without a valid cookie with a cookie packet, and only makes a connection once the client echoes the cookie back in the `fastnet_cookie` extension.
+Since the cookie is a MAC over the client's address, only clients which can receive packets at that address can connect, and the server remembers nothing until they do.
+See the connection establishment section of the specification.*/
+use packets::Extension;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rand_core::{OsRng, RngCore};
+use byteorder::{BigEndian, ByteOrder};
+use std::net;
+use std::time;
+use uuid;
+
+pub const COOKIE_EXTENSION: &'static str = "fastnet_cookie";
+const SECRET_SIZE: usize = 32;
+const MAC_SIZE: usize = 16;
+//A timestamp in seconds, then the truncated MAC.
+pub const COOKIE_SIZE: usize = 8+MAC_SIZE;
+//The checksum, channel, specifier, id, and cookie.
+pub const COOKIE_PACKET_SIZE: usize = 4+2+1+16+COOKIE_SIZE;
+//Long enough for a client on a slow link to get through the rest of the handshake.
+const COOKIE_LIFETIME_SECS: u64 = 30;
+
+/**Makes and checks cookies.
+
+The secret only lives as long as the server, so cookies from before a restart are refused and the client just has to get a new one.*/
+pub struct CookieJar {
+    secret: [u8; SECRET_SIZE],
+    start: time::Instant,
+}
+
+impl ::std::fmt::Debug for CookieJar {
+    //The secret stays out of logs.
+    fn fmt(&self, f: &mut ::std::fmt::Formatter)->::std::fmt::Result {
+        write!(f, "CookieJar")
+    }
+}
+
+impl CookieJar {
+    pub fn new()->CookieJar {
+        let mut secret = [0u8; SECRET_SIZE];
+        OsRng.fill_bytes(&mut secret);
+        CookieJar{secret: secret, start: time::Instant::now()}
+    }
+
+    fn now(&self)->u64 {
+        time::Instant::now().duration_since(self.start).as_secs()
+    }
+
+    fn mac(&self, address: net::SocketAddr, id: uuid::Uuid, timestamp: u64)->Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        let mut timestamp_bytes = [0u8; 8];
+        BigEndian::write_u64(&mut timestamp_bytes, timestamp);
+        mac.update(&timestamp_bytes);
+        match address.ip() {
+            net::IpAddr::V4(ip) => mac.update(&ip.octets()),
+            net::IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        let mut port = [0u8; 2];
+        BigEndian::write_u16(&mut port, address.port());
+        mac.update(&port);
+        mac.update(id.as_bytes());
+        mac
+    }
+
+    fn make_at(&self, address: net::SocketAddr, id: uuid::Uuid, timestamp: u64)->Vec<u8> {
+        let tag = self.mac(address, id, timestamp).finalize().into_bytes();
+        let mut cookie = vec![0u8; 8];
+        BigEndian::write_u64(&mut cookie, timestamp);
+        cookie.extend_from_slice(&tag[..MAC_SIZE]);
+        cookie
+    }
+
+    fn check_at(&self, address: net::SocketAddr, id: uuid::Uuid, cookie: &[u8], now: u64)->bool {
+        if cookie.len() != COOKIE_SIZE {return false;}
+        let timestamp = BigEndian::read_u64(&cookie[..8]);
+        if timestamp > now || now-timestamp > COOKIE_LIFETIME_SECS {return false;}
+        self.mac(address, id, timestamp).verify_truncated_left(&cookie[8..]).is_ok()
+    }
+
+    /**Make a cookie for a connect packet with the id `id` from `address`.*/
+    pub fn make(&self, address: net::SocketAddr, id: uuid::Uuid)->Vec<u8> {
+        let now = self.now();
+        self.make_at(address, id, now)
+    }
+
+    /**True if the extensions of a connect packet from `address` contain a cookie we made for it recently.*/
+    pub fn check(&self, address: net::SocketAddr, id: uuid::Uuid, extensions: &[Extension])->bool {
+        match extensions.iter().find(|e| e.name == COOKIE_EXTENSION) {
+            Some(e) => self.check_at(address, id, &e.data, self.now()),
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_cookies() {
+    let jar = CookieJar::new();
+    let address: net::SocketAddr = "127.0.0.1:10000".parse().unwrap();
+    let id = uuid::Uuid::new_v4();
+    let cookie = jar.make_at(address, id, 100);
+    assert_eq!(cookie.len(), COOKIE_SIZE);
+    assert!(jar.check_at(address, id, &cookie, 100));
+    assert!(jar.check_at(address, id, &cookie, 100+COOKIE_LIFETIME_SECS));
+    //Too old, and from the future.
+    assert!(jar.check_at(address, id, &cookie, 101+COOKIE_LIFETIME_SECS) == false);
+    assert!(jar.check_at(address, id, &cookie, 99) == false);
+    //Someone else's address or id.
+    assert!(jar.check_at("127.0.0.1:10001".parse().unwrap(), id, &cookie, 100) == false);
+    assert!(jar.check_at(address, uuid::Uuid::new_v4(), &cookie, 100) == false);
+    //Moving the timestamp forward invalidates the MAC.
+    let mut tampered = cookie.clone();
+    tampered[7] += 1;
+    assert!(jar.check_at(address, id, &tampered, 101) == false);
+    //And another server's cookies are no good.
+    assert!(CookieJar::new().check_at(address, id, &cookie, 100) == false);
+}
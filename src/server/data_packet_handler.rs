@@ -2,189 +2,781 @@ use super::*;
 use packets::*;
 use async;
 use constants;
-use std::net;
 use std::thread;
+use std::vec;
 use std::cell;
+use std::cmp;
+use std::collections;
 use std::ops::{Deref, DerefMut};
 
 
-//These are used by the message delivery logic.
+//Skips which can't be applied yet are remembered, up to this many.  Senders resend the rest.
+const MAX_PENDING_SKIPS: usize = 64;
+
+//This is used by the message delivery logic.
 thread_local!(static message_buffer: cell::RefCell<Vec<u8>> = cell::RefCell::new(Vec::default()));
-thread_local!(static index_buffer: cell::RefCell<Vec<usize>> = cell::RefCell::new(Vec::default()));
+
+/**What `deliver_to` hands to its destination.
+
+On streaming channels, reliable frames arrive as a start, their payload in order as it becomes contiguous, and an end.
+The end says whether the frame was complete; if it wasn't, the data delivered so far should be thrown away.*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery<'a> {
+    Message(&'a [u8]),
+    StreamStart(usize),
+    StreamData(&'a [u8]),
+    StreamEnd(bool),
+}
+
+//A reliable frame being delivered piecewise.
+#[derive(Debug, Clone, Copy)]
+struct IncomingStream {
+    start: u64,
+    next_sn: u64,
+    //Payload bytes which haven't arrived yet.
+    remaining: usize,
+    //Set if the frame turned out to be bad.  We throw away the rest of it.
+    aborted: bool,
+}
 
 /**handles acking packets, etc.*/
 #[derive(Debug)]
 pub struct DataPacketHandler {
     channel: i16,
-    address : net::SocketAddr,
     ignore_number: u64,
     last_reliable_frame: u64,
+    //True between acking the first and last packets of a reliable frame.
+    //The header of the next reliable frame can't be trusted until this is false, see do_acks.
+    in_reliable_frame: bool,
     contained_payload: usize, //Used for cost limits.
     limit: usize, //the per-channel memory limit.
     acked_packets: Vec<DataPacket>,
     unacked_packets: Vec<DataPacket>,
+    //Sequence numbers which need to be acked.  The connection sends these, so that they can be encrypted.
+    acks: Vec<u64>,
+    //Whether reliable frames are delivered as streams.
+    streaming: bool,
+    stream: Option<IncomingStream>,
+    mode: async::ChannelMode,
+    //In latest only mode, unreliable packets before this are from frames older than one we delivered.
+    unreliable_floor: u64,
+    //In reliable unordered mode, the reliable packets at or above the ignore number which were already delivered.
+    delivered: collections::BTreeSet<u64>,
+    //Expired reliable frames waiting for the frame before them, as first sequence number to (last sequence number, last reliable frame).
+    skips: collections::BTreeMap<u64, (u64, u64)>,
 }
 
 
 impl DataPacketHandler {
 
-    pub fn new(chan: i16, address: net::SocketAddr)->DataPacketHandler {
+    pub fn new(chan: i16)->DataPacketHandler {
         DataPacketHandler {
             channel: chan,
-            address: address,
             ignore_number: 0,
             last_reliable_frame: 0,
+            in_reliable_frame: false,
             contained_payload: 0,
             limit: constants::PER_CHANNEL_MEMORY_LIMIT_DEFAULT,
             acked_packets: Vec::default(),
             unacked_packets: Vec::default(),
+            acks: Vec::default(),
+            streaming: false,
+            stream: None,
+            mode: async::ChannelMode::Ordered,
+            unreliable_floor: 0,
+            delivered: collections::BTreeSet::default(),
+            skips: collections::BTreeMap::default(),
         }
     }
 
-    pub fn handle_incoming_packet<H: async::Handler>(&mut self, packet: DataPacket, service: &mut MioServiceProvider<H>) {
+    pub fn set_mode(&mut self, mode: async::ChannelMode) {
+        self.mode = mode;
+    }
+
+    /**Deliver reliable frames as streams from now on.  A frame already being delivered is finished the old way.*/
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+
+    ///True while a frame is partway through being streamed.
+    pub fn in_stream(&self)->bool {
+        self.stream.map_or(false, |s| s.aborted == false)
+    }
+
+    pub fn handle_incoming_packet(&mut self, packet: DataPacket) {
         let sn = packet.sequence_number();
         let reliable = packet.is_reliable();
+        if self.mode == async::ChannelMode::ReliableUnordered {
+            //On unordered channels, the header says which packets the sender knows we have.
+            if let Some(h) = packet.get_header() {self.raise_ignore_number(h.last_reliable_frame);}
+            if reliable && self.delivered.contains(&sn) {
+                self.ack(sn);
+                return;
+            }
+        }
         if(sn < self.ignore_number && reliable) {
-            self.ack(sn, service);
+            self.ack(sn);
             return;
         }
         else if(sn < self.ignore_number) {
             return;
         }
-        if packet.is_reliable() {self.ensure_room(packet.borrow_payload().len());}
+        if reliable == false && sn < self.unreliable_floor {return;}
+        if reliable && self.ensure_room(sn, packet.borrow_payload().len()) == false {return;}
         let new_contained_payload = self.contained_payload + packet.borrow_payload().len();
         if(new_contained_payload > self.limit) {return;}
         //If the sequence nubmer is already in acked_packets then we ack and abort.
         //Otherwise, we just abort.
         let is_in_acked = self.acked_packets.binary_search_by_key(&packet.sequence_number(), |i| i.sequence_number());
         if let Ok(_) = is_in_acked {
-            if(reliable) {self.ack(sn, service);}
+            if(reliable) {self.ack(sn);}
             return;
         }
         let is_in_unacked = self.unacked_packets.binary_search_by_key(&packet.sequence_number(), |i| i.sequence_number());
         if let Ok(_) = is_in_unacked {return;}
-        if(reliable) {
+        if(reliable && self.mode != async::ChannelMode::ReliableUnordered) {
             self.unacked_packets.insert(is_in_unacked.unwrap_err(), packet);
         }
         else {
+            //Unordered channels don't wait to ack anything.
+            if(reliable) {self.ack(sn);}
             self.acked_packets.insert(is_in_acked.unwrap_err(), packet);
         }
         self.contained_payload = new_contained_payload;
     }
 
-    pub fn do_acks<H: async::Handler>(&mut self, service: &mut MioServiceProvider<H>) {
-        //Because the acked packets are in order, failure to ack means we can stop early.
-        let mut end_index = 0;
-        for pack in self.unacked_packets.iter() {
-            let sn = pack.sequence_number();
-            if sn < self.ignore_number || sn == self.last_reliable_frame + 1 {
-                self.ignore_number = sn;
-                self.ack(sn, service);
-                end_index += 1;
+    //Everything before sn has been acked, so anything there which arrives now is a duplicate.
+    fn raise_ignore_number(&mut self, sn: u64) {
+        if sn <= self.ignore_number {return;}
+        self.ignore_number = sn;
+        self.delivered = self.delivered.split_off(&sn);
+    }
+
+    fn is_ackable(&self, packet: &DataPacket)->bool {
+        let sn = packet.sequence_number();
+        if sn <= self.ignore_number {return true;}
+        //The start of the next reliable frame.
+        //We only trust this between reliable frames: last_reliable is 0 both for "no reliable frame yet" and "the frame at sequence number 0".
+        if self.in_reliable_frame {return false;}
+        packet.borrow_header().map_or(false, |h| h.last_reliable_frame == self.last_reliable_frame)
+    }
+
+    /**The sender gave up on the reliable frame from `first` to `last`.
+
+Returns false if we can't remember the skip yet, in which case it shouldn't be acknowledged.*/
+    pub fn handle_skip(&mut self, first: u64, last: u64, last_reliable_frame: u64)->bool {
+        if last < first {return true;}
+        //If we have all of it, it'll be delivered anyway.
+        if last < self.ignore_number {return true;}
+        if self.mode == async::ChannelMode::ReliableUnordered {
+            self.drop_range(first, last);
+            for sn in cmp::max(first, self.ignore_number)..last+1 {self.delivered.insert(sn);}
+            return true;
+        }
+        if self.skips.len() >= MAX_PENDING_SKIPS && self.skips.contains_key(&first) == false {return false;}
+        self.skips.insert(first, (last, last_reliable_frame));
+        true
+    }
+
+    //Drop stored packets with sequence numbers from first to last.
+    fn drop_range(&mut self, first: u64, last: u64) {
+        let mut dropped = 0;
+        {
+            let mut in_range = |p: &DataPacket| {
+                let inside = p.sequence_number() >= first && p.sequence_number() <= last;
+                if inside {dropped += p.borrow_payload().len();}
+                inside == false
+            };
+            self.acked_packets.retain(|p| in_range(p));
+            self.unacked_packets.retain(|p| in_range(p));
+        }
+        self.contained_payload -= dropped;
+    }
+
+    //Skip the next reliable frame if the sender gave up on it.  Returns true if something was skipped.
+    fn apply_skip<F: FnMut(Delivery)>(&mut self, destination: &mut F)->bool {
+        let last_reliable_frame = self.last_reliable_frame;
+        let (first, last) = match self.skips.iter().find(|s| (s.1).1 == last_reliable_frame) {
+            Some((&first, &(last, _))) => (first, last),
+            None => return false,
+        };
+        self.skips.remove(&first);
+        if last < self.ignore_number {return false;}
+        self.drop_range(first, last);
+        if let Some(stream) = self.stream {
+            if stream.start == first {
+                if stream.aborted == false {destination(Delivery::StreamEnd(false));}
+                self.stream = None;
             }
-            else {break;}
         }
-        //Promote the packets.
-        for pack in self.unacked_packets.drain(..end_index) {
-            let ind = self.acked_packets.binary_search_by_key(&pack.sequence_number(), |i| i.sequence_number());
-            if let Err(index) = ind {
-                self.acked_packets.insert(index, pack);
+        self.ignore_number = last+1;
+        self.in_reliable_frame = false;
+        self.last_reliable_frame = first;
+        self.forget_skips_through(first);
+        true
+    }
+
+    fn forget_skips_through(&mut self, sn: u64) {
+        self.skips = self.skips.split_off(&(sn+1));
+    }
+
+    pub fn do_acks(&mut self) {
+        //Acking a packet raises the ignore number, which can make the next one ackable.
+        //The unacked packets are in order, so a single pass usually gets everything.
+        let mut index = 0;
+        while index < self.unacked_packets.len() {
+            if self.is_ackable(&self.unacked_packets[index]) == false {
+                index += 1;
+                continue;
             }
-            //Otherwise it's a duplicate, so we do nothing.
+            let pack = self.unacked_packets.remove(index);
+            let sn = pack.sequence_number();
+            self.ack(sn);
+            if sn >= self.ignore_number {
+                self.ignore_number = sn+1;
+                if pack.is_frame_start() {self.in_reliable_frame = true;}
+                if pack.is_frame_end() {self.in_reliable_frame = false;}
+            }
+            //Promote the packet.
+            let ind = self.acked_packets.binary_search_by_key(&sn, |i| i.sequence_number());
+            if let Err(i) = ind {
+                self.acked_packets.insert(i, pack);
+            }
+            //Otherwise it's a duplicate.
+            else {self.contained_payload -= pack.borrow_payload().len();}
+            index = 0;
         }
     }
 
     //Delivery logic.  Returns the number of packets delivered.
-    pub fn deliver<F: Fn(&Vec<u8>)>(&mut self, destination: F)->usize {
-        //Extract the two TLS keys.
+    pub fn deliver<F: FnMut(&[u8])>(&mut self, mut destination: F)->usize {
+        self.deliver_to(|d| if let Delivery::Message(m) = d {destination(m)})
+    }
+
+    /**Like deliver, but also streams reliable frames if this channel is streaming.  Streams count as delivered when they end.*/
+    pub fn deliver_to<F: FnMut(Delivery)>(&mut self, destination: F)->usize {
+        //Extract the TLS key.
         message_buffer.with(|message_buff| {
-            index_buffer.with(|index_buff| {
-                self.deliver_helper(destination, message_buff.borrow_mut().deref_mut(), index_buff.borrow_mut().deref_mut())
-            })
+            self.deliver_helper(destination, message_buff.borrow_mut().deref_mut())
         })
     }
 
-    fn deliver_helper<F: Fn(&Vec<u8>)>(&mut self, destination: F, message_buff: &mut Vec<u8>, index_buff: &mut Vec<usize>)->usize {
-        message_buff.clear();
-        index_buff.clear();
+    //If the packet at index starts a deliverable frame, returns the index of the packet which ends it.
+    fn find_deliverable_frame(&self, index: usize)->Option<usize> {
+        let first = &self.acked_packets[index];
+        let header = match first.get_header() {
+            Some(h) => h,
+            None => return None,
+        };
+        //There's a reliable frame we don't have yet.
+        if self.waits_for_reliable(first) && header.last_reliable_frame != self.last_reliable_frame {return None;}
+        let mut length = FRAME_HEADER_SIZE;
+        let mut sn = first.sequence_number();
+        for (offset, p) in self.acked_packets[index..].iter().enumerate() {
+            if offset > 0 {
+                //Either it starts a new frame, is a gap, or is from a different frame.
+                if p.is_frame_start() || p.sequence_number() != sn+1 || p.is_reliable() != first.is_reliable() {return None;}
+                sn += 1;
+            }
+            length += p.borrow_payload().len();
+            if p.is_frame_end() {
+                if length == header.length as usize {return Some(index+offset);}
+                else {return None;}
+            }
+        }
+        None
+    }
+
+    //Whether a frame starting with `first` has to wait for the reliable frame before it.
+    fn waits_for_reliable(&self, first: &DataPacket)->bool {
+        match self.mode {
+            async::ChannelMode::ReliableUnordered => false,
+            async::ChannelMode::UnreliableBypass => first.is_reliable(),
+            _ => true,
+        }
+    }
+
+    //Unreliable frames sent before the last reliable frame we delivered can never be delivered.
+    fn is_stale(&self, index: usize)->bool {
+        let first = &self.acked_packets[index];
+        if first.is_reliable() {return false;}
+        //The rest of a frame this far back would be ignored, so it can't be completed.
+        if self.mode == async::ChannelMode::ReliableUnordered {
+            return first.sequence_number() < self.ignore_number && self.find_deliverable_frame(index).is_none();
+        }
+        let old = first.borrow_header().map_or(false, |h| h.last_reliable_frame < self.last_reliable_frame);
+        //Frames which don't wait can still be delivered, so only incomplete ones are dropped.
+        if self.mode == async::ChannelMode::UnreliableBypass {
+            return old && self.find_deliverable_frame(index).is_none();
+        }
+        old
+    }
+
+    //If the packet at index starts the next reliable frame, which we can stream.
+    fn starts_stream(&self, index: usize)->bool {
+        let first = &self.acked_packets[index];
+        if self.streaming == false || self.mode == async::ChannelMode::ReliableUnordered || self.stream.is_some() || first.is_reliable() == false {return false;}
+        first.borrow_header().map_or(false, |h| h.last_reliable_frame == self.last_reliable_frame)
+    }
+
+    //Deliver what we can of the current stream.  Returns true if it ended.
+    fn continue_stream<F: FnMut(Delivery)>(&mut self, destination: &mut F)->bool {
+        while let Some(mut stream) = self.stream {
+            let index = match self.acked_packets.binary_search_by_key(&stream.next_sn, |i| i.sequence_number()) {
+                Ok(i) => i,
+                Err(_) => return false,
+            };
+            //A bad frame ends at its last packet, or at the start of the next frame if we never see that.
+            if stream.aborted && self.acked_packets[index].is_frame_start() && stream.next_sn != stream.start {
+                self.finish_stream(stream.start);
+                return true;
+            }
+            let packet = self.acked_packets.remove(index);
+            let (frame_start, frame_end, reliable) = (packet.is_frame_start(), packet.is_frame_end(), packet.is_reliable());
+            let payload = packet.into_payload();
+            self.contained_payload -= payload.len();
+            stream.next_sn += 1;
+            if stream.aborted == false {
+                let valid = reliable && frame_start == (stream.next_sn-1 == stream.start) && payload.len() <= stream.remaining && (frame_end == false || payload.len() == stream.remaining);
+                if valid {
+                    stream.remaining -= payload.len();
+                    if payload.is_empty() == false {destination(Delivery::StreamData(&payload));}
+                    if frame_end {destination(Delivery::StreamEnd(true));}
+                }
+                else {
+                    stream.aborted = true;
+                    destination(Delivery::StreamEnd(false));
+                }
+            }
+            if frame_end {
+                self.finish_stream(stream.start);
+                return true;
+            }
+            self.stream = Some(stream);
+        }
+        false
+    }
+
+    fn finish_stream(&mut self, start: u64) {
+        self.last_reliable_frame = start;
+        self.stream = None;
+        self.forget_skips_through(start);
+    }
+
+    fn deliver_helper<F: FnMut(Delivery)>(&mut self, mut destination: F, message_buff: &mut Vec<u8>)->usize {
         let mut delivered_count = 0;
-        //Collect all the starting packets into index_buff.
-        index_buff.extend(
-            self.acked_packets.iter().enumerate().filter(|i| i.1.is_frame_start()).map(|i| i.0)
-        );
-        for index in index_buff.iter() {
-            let header = self.acked_packets[*index].get_header().unwrap(); //This is a start of frame; bug if it doesn't have one.
-            if header.last_reliable_frame != self.last_reliable_frame {break;} //There's a reliable frame we don't have yet.
-            let mut end_index = *index;
-            let mut sn = self.acked_packets[*index].sequence_number();
-            for p in self.acked_packets[*index..].iter() {
-                //Handle the first one, possibly breaking out now.
-                if p.sequence_number() == sn {
-                    if p.is_frame_end() {break;} //It's a 1-packet message.
-                    continue; //Otherwise we skip it.
+        //Delivering or dropping a frame removes its packets, so we only advance index when we leave something in place.
+        let mut index = 0;
+        loop {
+            //Nothing after a stream can be delivered until it ends, which can make frames before it deliverable.
+            if self.stream.is_some() && self.continue_stream(&mut destination) {
+                delivered_count += 1;
+                index = 0;
+                continue;
+            }
+            if self.skips.is_empty() == false && self.apply_skip(&mut destination) {
+                index = 0;
+                continue;
+            }
+            if index >= self.acked_packets.len() {break;}
+            if self.is_stale(index) {
+                let dropped = self.acked_packets.remove(index);
+                self.contained_payload -= dropped.borrow_payload().len();
+                continue;
+            }
+            if self.starts_stream(index) {
+                let first = &self.acked_packets[index];
+                let length = first.borrow_header().map_or(0, |h| h.length as usize).saturating_sub(FRAME_HEADER_SIZE);
+                self.stream = Some(IncomingStream{start: first.sequence_number(), next_sn: first.sequence_number(), remaining: length, aborted: false});
+                destination(Delivery::StreamStart(length));
+                continue;
+            }
+            let end_index = match self.find_deliverable_frame(index) {
+                Some(e) => e,
+                None => {
+                    index += 1;
+                    continue;
                 }
-                //Either it starts a new frame, may end the current frame, or is a gap.
-                //In all three cases, we break out.
-                if p.is_frame_start() || p.is_frame_end() || p.sequence_number()-sn != 1 {break;}
-                end_index += 1;
-                sn += 1;
+            };
+            let is_reliable = self.acked_packets[index].is_reliable();
+            if is_reliable == false && self.mode == async::ChannelMode::LatestOnly && self.has_newer_deliverable(end_index) {
+                //Dropping can remove packets before index.
+                let end_sn = self.acked_packets[end_index].sequence_number();
+                self.drop_unreliable_before(end_sn+1);
+                index = 0;
+                continue;
             }
-            //We know that the range is consecutive and that the last reliable frame condition was met.
-            //If the start and end are not true, it's undeliverable and we stop.
-            if self.acked_packets[*index].is_frame_start() == false || self.acked_packets[end_index].is_frame_end() == false {break;}
-            //Otherwise, we need to assemble the frame and remove the packets.
-            let is_reliable = self.acked_packets[*index].is_reliable();
-            let new_last_reliable = self.acked_packets[*index].sequence_number();
-            for p in self.acked_packets.drain(*index..end_index+1) {
+            //We need to assemble the frame and remove the packets.
+            message_buff.clear();
+            let new_last_reliable = self.acked_packets[index].sequence_number();
+            let end_sn = self.acked_packets[end_index].sequence_number();
+            for p in self.acked_packets.drain(index..end_index+1) {
                 let mut payload = p.into_payload();
                 self.contained_payload -= payload.len();
                 message_buff.append(&mut payload);
             }
-            if is_reliable {self.last_reliable_frame = new_last_reliable;}
+            if is_reliable && self.mode == async::ChannelMode::ReliableUnordered {
+                for sn in new_last_reliable..end_sn+1 {
+                    if sn >= self.ignore_number {self.delivered.insert(sn);}
+                }
+            }
+            else if is_reliable {
+                self.last_reliable_frame = new_last_reliable;
+                self.forget_skips_through(new_last_reliable);
+            }
+            else if self.mode == async::ChannelMode::LatestOnly {
+                //Anything older which was still arriving is now useless.
+                self.drop_unreliable_before(new_last_reliable);
+                self.unreliable_floor = end_sn+1;
+                index = 0;
+            }
             delivered_count += 1;
-            destination(&message_buff);
+            destination(Delivery::Message(&message_buff));
+            //A reliable frame can make frames before it deliverable, so start over.
+            if is_reliable {index = 0;}
         }
         delivered_count
     }
 
-    //Implements the packet dropping logic to allow incoming reliable packets to evict other, less important packets.
-    pub fn ensure_room(&mut self, amount: usize) {
-        if amount < self.limit-self.contained_payload {return;}
-        let mut reliable_endpoint = self.unacked_packets.len();
-        let mut unreliable_endpoint = 0;
+    //True if an unreliable frame after end_index can be delivered now.
+    fn has_newer_deliverable(&self, end_index: usize)->bool {
+        (end_index+1..self.acked_packets.len()).any(|i| self.acked_packets[i].is_reliable() == false && self.find_deliverable_frame(i).is_some())
+    }
+
+    //Drop unreliable packets with sequence numbers below sn.
+    fn drop_unreliable_before(&mut self, sn: u64) {
+        let mut dropped = 0;
+        self.acked_packets.retain(|p| {
+            let keep = p.is_reliable() || p.sequence_number() >= sn;
+            if keep == false {dropped += p.borrow_payload().len();}
+            keep
+        });
+        self.contained_payload -= dropped;
+    }
+
+    /**Implements the packet dropping logic to allow incoming reliable packets to evict other, less important packets.
+
+Returns false if there isn't a way to make enough room, in which case nothing was dropped.*/
+    pub fn ensure_room(&mut self, sn: u64, amount: usize)->bool {
+        let available = self.limit-self.contained_payload;
+        if amount <= available {return true;}
+        let needed = amount-available;
         let mut sum = 0;
-        //This simulates a goto, the best way to do the following.
-        'outer: loop {
-            for pack in self.acked_packets.iter() {
-                if sum > amount {break 'outer;}
-                if pack.is_reliable() == false {
-                    sum += pack.borrow_payload().len();
-                }
-                unreliable_endpoint += 1;
+        //First, unreliable packets from lowest to highest sequence number.
+        let mut unreliable_victims = Vec::default();
+        for (i, pack) in self.acked_packets.iter().enumerate() {
+            if sum >= needed {break;}
+            if pack.is_reliable() == false {
+                sum += pack.borrow_payload().len();
+                unreliable_victims.push(i);
             }
-            for pack in self.unacked_packets.iter().rev() {
-                if sum > amount {break 'outer;}
-                if pack.is_reliable() {
-                    sum += pack.borrow_payload().len();
-                }
-                reliable_endpoint -= 1;
-            }
-            break;
         }
-        //Kill unreliables.
-        for i in 0..unreliable_endpoint {
-            if self.acked_packets[i].is_reliable() == false {
-                self.acked_packets.remove(i);
-            }
+        //Then reliable packets after this one, from highest to lowest.
+        let mut reliable_endpoint = self.unacked_packets.len();
+        for pack in self.unacked_packets.iter().rev() {
+            if sum >= needed || pack.sequence_number() <= sn {break;}
+            sum += pack.borrow_payload().len();
+            reliable_endpoint -= 1;
+        }
+        if sum < needed {return false;}
+        //Kill unreliables.  Going backwards keeps the indices valid.
+        for i in unreliable_victims.into_iter().rev() {
+            self.acked_packets.remove(i);
         }
         //The unacked packets are all reliable, so we can use drain.
         self.unacked_packets.drain(reliable_endpoint..);
         self.contained_payload -= sum;
+        true
+    }
+
+    pub fn ack(&mut self, sn: u64) {
+        self.acks.push(sn);
     }
 
-    pub fn ack<H: async::Handler>(&self, sn: u64, service: &mut MioServiceProvider<H>) {
-        let packet = Packet::Ack{chan: self.channel, sequence_number: sn};
-        service.send(packet, self.address);
+    //The acks which need sending.
+    pub fn drain_acks(&mut self)->vec::Drain<u64> {
+        self.acks.drain(..)
     }
 
 }
+
+//Encodes frames on channel 0, advancing the sequence number and last reliable frame as a sender would.
+#[cfg(test)]
+struct TestSender {
+    sn: u64,
+    last_reliable: u64,
+}
+
+#[cfg(test)]
+impl TestSender {
+    fn new()->TestSender {
+        TestSender{sn: 0, last_reliable: 0}
+    }
+
+    fn frame(&mut self, size: usize, reliable: bool)->Vec<DataPacket> {
+        self.frame_of(&vec![size as u8; size], reliable)
+    }
+
+    fn frame_of(&mut self, data: &[u8], reliable: bool)->Vec<DataPacket> {
+        let packets = ::frame::FrameEncoder::new(&mut data.iter(), 0, self.sn, self.last_reliable, reliable).map(|p| match p {
+            Packet::Data{packet, ..} => packet,
+            _ => panic!("Expected a data packet."),
+        }).collect::<Vec<_>>();
+        if reliable {self.last_reliable = self.sn;}
+        self.sn += packets.len() as u64;
+        packets
+    }
+}
+
+#[test]
+fn test_ordered_delivery() {
+    let mut handler = DataPacketHandler::new(0);
+    let mut sender = TestSender::new();
+    let zero = sender.frame(5, true);
+    let first = sender.frame(700, true);
+    let mut stale = sender.frame(700, false);
+    let second = sender.frame(20, true);
+    let mut delivered = Vec::default();
+    //Last reliable frame is 0 both before and after the frame at 0, so get that out of the way.
+    for p in zero {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    //The second frame waits for the first, even though it arrived first.
+    for p in second.iter() {handler.handle_incoming_packet(p.clone());}
+    handler.handle_incoming_packet(stale.remove(0));
+    handler.do_acks();
+    assert_eq!(handler.deliver(|m| delivered.push(m.len())), 0);
+    for p in first {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    //It can't be acked until the first is delivered, so it goes when the sender resends it.
+    for p in second {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![5, 700, 20]);
+    //Part of an unreliable frame from before the second one can never be delivered, so it's dropped.
+    assert_eq!(handler.ignore_number, 6);
+    assert_eq!(handler.contained_payload, 0);
+}
+
+#[test]
+fn test_streaming_delivery() {
+    let mut handler = DataPacketHandler::new(0);
+    handler.set_streaming(true);
+    let mut sender = TestSender::new();
+    let zero = sender.frame(5, true);
+    let mut big = sender.frame(1200, true);
+    let after = sender.frame(10, false);
+    let mut events = Vec::default();
+    {
+        let mut record = |d: Delivery| events.push(match d {
+            Delivery::Message(m) => format!("message {}", m.len()),
+            Delivery::StreamStart(l) => format!("start {}", l),
+            Delivery::StreamData(d) => format!("data {}", d.len()),
+            Delivery::StreamEnd(complete) => format!("end {}", complete),
+        });
+        for p in zero {handler.handle_incoming_packet(p);}
+        for p in after {handler.handle_incoming_packet(p);}
+        //The end of the big frame arrives first, but each piece goes as soon as what's before it is here.
+        let last = big.pop().unwrap();
+        handler.handle_incoming_packet(last);
+        handler.do_acks();
+        handler.deliver_to(&mut record);
+        handler.handle_incoming_packet(big.remove(0));
+        handler.do_acks();
+        handler.deliver_to(&mut record);
+        assert!(handler.in_stream());
+        handler.handle_incoming_packet(big.remove(0));
+        handler.do_acks();
+        assert_eq!(handler.deliver_to(&mut record), 2);
+    }
+    assert_eq!(events, vec!["start 5", "data 5", "end true", "start 1200", "data 500", "data 500", "data 200", "end true", "message 10"]);
+    assert!(handler.in_stream() == false);
+    assert_eq!(handler.contained_payload, 0);
+}
+
+#[test]
+fn test_compressed_stream_limit() {
+    use async_tests::{Event, RecordingHandler};
+    use std::sync::{mpsc, Arc};
+    let mut config = async::CompressionConfig::default();
+    config.channels.insert(0, async::ChannelCompression{threshold: 16, dictionary: None});
+    let config = Arc::new(config);
+    let (compressor, _) = accept(&config, &offer(&config).data).unwrap();
+    let compressed = compressor.compress(0, &[7; 100]);
+    assert!(compressed.len() < 100);
+    let (sender, events) = mpsc::channel();
+    let mut recorder = RecordingHandler::new(sender, false);
+    let mut streams = collections::HashMap::default();
+    let mut handler = DataPacketHandler::new(0);
+    handler.set_streaming(true);
+    let mut sender = TestSender::new();
+    //Only the first byte matters until the end, so the rest can be anything.
+    let mut large = vec![0u8; constants::PER_CHANNEL_MEMORY_LIMIT_DEFAULT+1];
+    large[0] = compressed[0];
+    let large = sender.frame_of(&large, true);
+    let small = sender.frame_of(&compressed, true);
+    let id = ::uuid::Uuid::new_v4();
+    let mut deliver = |handler: &mut DataPacketHandler, streams: &mut collections::HashMap<i16, IncomingStreamState>| {
+        handler.do_acks();
+        handler.deliver_to(|d| stream_delivery(id, 0, d, Some(&compressor), false, streams, &mut collections::HashMap::default(), &mut Vec::default(), &mut recorder));
+    };
+    //A compressed frame which is too large to hold is dropped as soon as it starts.
+    handler.handle_incoming_packet(large[0].clone());
+    deliver(&mut handler, &mut streams);
+    assert!(match streams.get(&0) {Some(&IncomingStreamState::Dropped) => true, _ => false});
+    for p in large.into_iter().skip(1).chain(small) {handler.handle_incoming_packet(p);}
+    deliver(&mut handler, &mut streams);
+    //Smaller ones are still delivered.
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![Event::StreamStart(0, MessageHeader::default(), 100), Event::StreamData(0, vec![7; 100]), Event::StreamEnd(0, true)]);
+    assert!(streams.is_empty());
+}
+
+#[test]
+fn test_stream_header_across_packets() {
+    use async_tests::{Event, RecordingHandler};
+    use std::sync::mpsc;
+    let (sender, events) = mpsc::channel();
+    let mut recorder = RecordingHandler::new(sender, false);
+    let mut streams = collections::HashMap::default();
+    let id = ::uuid::Uuid::new_v4();
+    let header = MessageHeader{message_type: Some(3), timestamp: Some(9), rpc: None};
+    let message = encode_message(&header, b"payload");
+    {
+        let mut deliver = |d: Delivery| stream_delivery(id, 0, d, None, true, &mut streams, &mut collections::HashMap::default(), &mut Vec::default(), &mut recorder);
+        deliver(Delivery::StreamStart(message.len()));
+        //Nothing goes to the handler until the header is complete.
+        deliver(Delivery::StreamData(&message[..5]));
+        deliver(Delivery::StreamData(&message[5..]));
+        deliver(Delivery::StreamEnd(true));
+        //A stream which ends partway through its header is never started.
+        deliver(Delivery::StreamStart(message.len()));
+        deliver(Delivery::StreamData(&message[..5]));
+        deliver(Delivery::StreamEnd(true));
+    }
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![Event::StreamStart(0, header, 7), Event::StreamData(0, b"payload".to_vec()), Event::StreamEnd(0, true)]);
+    assert!(streams.is_empty());
+}
+
+#[test]
+fn test_latest_only_delivery() {
+    let mut handler = DataPacketHandler::new(0);
+    handler.set_mode(async::ChannelMode::LatestOnly);
+    let mut sender = TestSender::new();
+    let mut partial = sender.frame(700, false);
+    let older = sender.frame(10, false);
+    let newer = sender.frame(20, false);
+    let late = sender.frame(30, false);
+    let newest = sender.frame(40, false);
+    let mut delivered = Vec::default();
+    handler.handle_incoming_packet(partial.remove(0));
+    //When two complete at once, only the newer goes.
+    for p in older {handler.handle_incoming_packet(p);}
+    for p in newer {handler.handle_incoming_packet(p);}
+    handler.deliver(|m| delivered.push(m.len()));
+    //The start of the older partial frame is gone, and the rest of it is dropped on arrival.
+    assert_eq!(handler.acked_packets.len(), 0);
+    handler.handle_incoming_packet(partial.remove(0));
+    assert_eq!(handler.acked_packets.len(), 0);
+    for p in newest {handler.handle_incoming_packet(p);}
+    handler.deliver(|m| delivered.push(m.len()));
+    //Arriving late means never being delivered.
+    for p in late {handler.handle_incoming_packet(p);}
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![20, 40]);
+    assert_eq!(handler.contained_payload, 0);
+}
+
+#[test]
+fn test_unordered_delivery() {
+    let mut handler = DataPacketHandler::new(0);
+    handler.set_mode(async::ChannelMode::ReliableUnordered);
+    //Unordered senders put the lowest sequence number they're waiting on in the header.
+    let frame = |size: usize, sn: u64, floor: u64| TestSender{sn: sn, last_reliable: floor}.frame(size, true);
+    let first = frame(700, 0, 0);
+    let second = frame(20, 2, 0);
+    let third = frame(30, 3, 3);
+    let mut delivered = Vec::default();
+    //The second doesn't wait for the first, and both are acked right away.
+    for p in second.iter() {handler.handle_incoming_packet(p.clone());}
+    handler.handle_incoming_packet(first[1].clone());
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(handler.drain_acks().collect::<Vec<_>>(), vec![2, 1]);
+    handler.handle_incoming_packet(first[0].clone());
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![20, 700]);
+    //Resends of delivered packets are acked again but not delivered again.
+    for p in first.iter().chain(second.iter()) {handler.handle_incoming_packet(p.clone());}
+    assert_eq!(handler.deliver(|m| delivered.push(m.len())), 0);
+    assert_eq!(handler.drain_acks().count(), 4);
+    //Once the sender says it has everything before 3, we stop remembering what we delivered.
+    for p in third {handler.handle_incoming_packet(p);}
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![20, 700, 30]);
+    assert_eq!(handler.ignore_number, 3);
+    assert_eq!(handler.delivered.len(), 1);
+    assert_eq!(handler.contained_payload, 0);
+}
+
+#[test]
+fn test_unreliable_bypass_delivery() {
+    let mut handler = DataPacketHandler::new(0);
+    handler.set_mode(async::ChannelMode::UnreliableBypass);
+    let mut sender = TestSender::new();
+    let zero = sender.frame(5, true);
+    let lost = sender.frame(10, true);
+    let update = sender.frame(20, false);
+    let mut incomplete = sender.frame(700, false);
+    let after = sender.frame(30, true);
+    let mut delivered = Vec::default();
+    for p in zero {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    //The unreliable update doesn't wait for the lost reliable frame, but the reliable one after it does.
+    for p in update {handler.handle_incoming_packet(p);}
+    handler.handle_incoming_packet(incomplete.remove(0));
+    for p in after.iter() {handler.handle_incoming_packet(p.clone());}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![5, 20]);
+    for p in lost {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    //As in ordered mode, the reliable frame after it is acked when it's resent.
+    for p in after {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![5, 20, 10, 30]);
+    //The incomplete frame can't finish now that a newer reliable frame is in, so it's gone.
+    assert_eq!(handler.contained_payload, 0);
+}
+
+#[test]
+fn test_skipping_expired_frames() {
+    let mut handler = DataPacketHandler::new(0);
+    let mut sender = TestSender::new();
+    let zero = sender.frame(5, true);
+    let mut expired = sender.frame(1200, true);
+    let update = sender.frame(10, false);
+    let after = sender.frame(20, true);
+    let mut delivered = Vec::default();
+    for p in zero {handler.handle_incoming_packet(p);}
+    handler.handle_incoming_packet(expired.remove(0));
+    for p in update {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    //The sender gives up on the big frame, so what was waiting on it goes.
+    assert!(handler.handle_skip(1, 3, 0));
+    handler.deliver(|m| delivered.push(m.len()));
+    for p in after {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    handler.deliver(|m| delivered.push(m.len()));
+    assert_eq!(delivered, vec![5, 10, 20]);
+    //The rest of the skipped frame is ignored if it turns up.
+    for p in expired {handler.handle_incoming_packet(p);}
+    handler.do_acks();
+    assert_eq!(handler.deliver(|m| delivered.push(m.len())), 0);
+    assert_eq!(handler.contained_payload, 0);
+    assert!(handler.skips.is_empty());
+}
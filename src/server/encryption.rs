@@ -0,0 +1,294 @@
+/*! The `fastnet_encryption` extension.
+
+The client sends an ephemeral X25519 key in the connect packet, and the server answers with its own ephemeral key, its static key if it has one, and a confirmation.
+Both sides then derive a pair of ChaCha20-Poly1305 keys with HKDF-SHA256, one per direction, and every packet after the handshake is sealed under them.
+See the encryption section of the specification for the details.*/
+use async;
+use packets::{self, Packet, Extension};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret, SharedSecret};
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+use uuid;
+
+pub const ENCRYPTION_EXTENSION: &'static str = "fastnet_encryption";
+const KEY_SIZE: usize = 32;
+const AEAD_TAG_SIZE: usize = 16;
+//The server's ephemeral key, its static key or zeros, and the confirmation.
+const RESPONSE_SIZE: usize = KEY_SIZE*2+AEAD_TAG_SIZE;
+//Both keys, then the connection tag.
+const KEY_MATERIAL_SIZE: usize = KEY_SIZE*2+8;
+const KEY_INFO: &'static [u8] = b"fastnet_encryption keys";
+const REPLAY_WINDOW_SIZE: u64 = 64;
+///How much bigger sealing makes a packet: the encrypted packet's channel, tag, and counter, then the AEAD tag.
+pub const ENCRYPTION_OVERHEAD: usize = 2+8+8+AEAD_TAG_SIZE;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    //The other side sent something malformed, or the confirmation didn't check out.
+    Invalid,
+    //The server's static key isn't the one we pinned.
+    KeyMismatch,
+}
+
+/**Tracks which counters we've seen, so that replayed packets can be dropped.
+
+Counters more than 64 behind the highest one seen are always rejected.*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayWindow {
+    highest: u64,
+    //Bit n is set if we've seen highest-n.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    //Counter 0 is used by the handshake, so it starts out seen.
+    pub fn new()->ReplayWindow {
+        ReplayWindow{highest: 0, seen: 1}
+    }
+
+    pub fn is_fresh(&self, counter: u64)->bool {
+        if counter > self.highest {return true;}
+        let behind = self.highest-counter;
+        behind < REPLAY_WINDOW_SIZE && self.seen & (1 << behind) == 0
+    }
+
+    //Only call this for packets which decrypted.
+    pub fn mark(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter-self.highest;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {0} else {self.seen << shift};
+            self.seen |= 1;
+            self.highest = counter;
+        }
+        else {
+            self.seen |= 1 << (self.highest-counter);
+        }
+    }
+}
+
+fn nonce(counter: u64)->[u8; 12] {
+    let mut n = [0u8; 12];
+    BigEndian::write_u64(&mut n[4..], counter);
+    n
+}
+
+//The header of the encrypted packet is authenticated but not encrypted.
+fn associated_data(tag: u64, counter: u64)->[u8; 16] {
+    let mut ad = [0u8; 16];
+    BigEndian::write_u64(&mut ad[..8], tag);
+    BigEndian::write_u64(&mut ad[8..], counter);
+    ad
+}
+
+/**The keys for one connection.*/
+pub struct Session {
+    tag: u64,
+    sending: ChaCha20Poly1305,
+    receiving: ChaCha20Poly1305,
+    next_counter: u64,
+    replay_window: ReplayWindow,
+}
+
+impl fmt::Debug for Session {
+    //Keys stay out of logs.
+    fn fmt(&self, f: &mut fmt::Formatter)->fmt::Result {
+        write!(f, "Session {{ tag: {}, next_counter: {} }}", self.tag, self.next_counter)
+    }
+}
+
+impl Session {
+    fn derive(id: uuid::Uuid, secrets: &[&SharedSecret], transcript: &[&[u8; KEY_SIZE]], is_client: bool)->Session {
+        let mut ikm = Vec::with_capacity(KEY_SIZE*secrets.len());
+        for s in secrets {ikm.extend_from_slice(s.as_bytes());}
+        let mut info = KEY_INFO.to_vec();
+        for k in transcript {info.extend_from_slice(&k[..]);}
+        let mut material = [0u8; KEY_MATERIAL_SIZE];
+        Hkdf::<Sha256>::new(Some(id.as_bytes()), &ikm).expand(&info, &mut material).expect("HKDF can always produce 72 bytes");
+        let client_to_server = ChaCha20Poly1305::new(Key::from_slice(&material[..KEY_SIZE]));
+        let server_to_client = ChaCha20Poly1305::new(Key::from_slice(&material[KEY_SIZE..KEY_SIZE*2]));
+        let (sending, receiving) = if is_client {(client_to_server, server_to_client)} else {(server_to_client, client_to_server)};
+        Session {
+            tag: BigEndian::read_u64(&material[KEY_SIZE*2..]),
+            sending: sending,
+            receiving: receiving,
+            next_counter: 1,
+            replay_window: ReplayWindow::new(),
+        }
+    }
+
+    /**Identifies the connection in encrypted packets, without giving away its id.*/
+    pub fn tag(&self)->u64 {
+        self.tag
+    }
+
+    /**Encrypt an encoded packet.*/
+    pub fn seal(&mut self, plaintext: &[u8])->Option<Packet> {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        self.seal_with_counter(counter, plaintext).map(|c| Packet::Encrypted{tag: self.tag, counter: counter, ciphertext: c})
+    }
+
+    fn seal_with_counter(&self, counter: u64, plaintext: &[u8])->Option<Vec<u8>> {
+        let ad = associated_data(self.tag, counter);
+        self.sending.encrypt(Nonce::from_slice(&nonce(counter)), Payload{msg: plaintext, aad: &ad}).ok()
+    }
+
+    /**Decrypt the contents of an encrypted packet.
+
+Returns None if it's been tampered with or is a replay.*/
+    pub fn open(&mut self, counter: u64, ciphertext: &[u8])->Option<Vec<u8>> {
+        if self.replay_window.is_fresh(counter) == false {return None;}
+        let ad = associated_data(self.tag, counter);
+        let plaintext = match self.receiving.decrypt(Nonce::from_slice(&nonce(counter)), Payload{msg: ciphertext, aad: &ad}) {
+            Ok(p) => p,
+            Err(_) => return None,
+        };
+        self.replay_window.mark(counter);
+        Some(plaintext)
+    }
+}
+
+/**The client's half of the key exchange, kept until the connected packet arrives.*/
+pub struct ClientHandshake {
+    secret: StaticSecret,
+    public: PublicKey,
+    pinned_key: Option<[u8; KEY_SIZE]>,
+}
+
+impl fmt::Debug for ClientHandshake {
+    fn fmt(&self, f: &mut fmt::Formatter)->fmt::Result {
+        write!(f, "ClientHandshake {{ public: {:?}, pinned_key: {:?} }}", self.public.as_bytes(), self.pinned_key)
+    }
+}
+
+impl ClientHandshake {
+    //The secret is only used for this connection, but has to survive resending the connect packet.
+    pub fn new(pinned_key: Option<[u8; KEY_SIZE]>)->ClientHandshake {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        ClientHandshake{secret: secret, public: public, pinned_key: pinned_key}
+    }
+
+    pub fn extension(&self)->Extension {
+        Extension{name: ENCRYPTION_EXTENSION.to_string(), data: self.public.as_bytes().to_vec()}
+    }
+
+    /**Finish the key exchange using the server's half from the connected packet.*/
+    pub fn finish(&self, id: uuid::Uuid, response: &[u8])->Result<Session, HandshakeError> {
+        if response.len() != RESPONSE_SIZE {return Err(HandshakeError::Invalid);}
+        let mut server_ephemeral = [0u8; KEY_SIZE];
+        let mut server_static = [0u8; KEY_SIZE];
+        server_ephemeral.copy_from_slice(&response[..KEY_SIZE]);
+        server_static.copy_from_slice(&response[KEY_SIZE..KEY_SIZE*2]);
+        let confirmation = &response[KEY_SIZE*2..];
+        if let Some(pinned) = self.pinned_key {
+            if pinned != server_static {return Err(HandshakeError::KeyMismatch);}
+        }
+        let ephemeral_secret = self.secret.diffie_hellman(&PublicKey::from(server_ephemeral));
+        if ephemeral_secret.was_contributory() == false {return Err(HandshakeError::Invalid);}
+        let static_secret = if server_static == [0u8; KEY_SIZE] {None} else {Some(self.secret.diffie_hellman(&PublicKey::from(server_static)))};
+        let mut secrets = vec![&ephemeral_secret];
+        if let Some(ref s) = static_secret {
+            if s.was_contributory() == false {return Err(HandshakeError::Invalid);}
+            secrets.push(s);
+        }
+        let mut session = Session::derive(id, &secrets, &[self.public.as_bytes(), &server_ephemeral, &server_static], true);
+        //The confirmation is an empty packet with counter 0.  Only a server which derived the same keys could have made it.
+        let ad = associated_data(session.tag, 0);
+        if session.receiving.decrypt(Nonce::from_slice(&nonce(0)), Payload{msg: confirmation, aad: &ad}).is_err() {return Err(HandshakeError::Invalid);}
+        Ok(session)
+    }
+}
+
+/**The server's side of the key exchange.
+
+Returns the session and the extension for the connected packet.*/
+pub fn respond(config: &async::EncryptionConfig, id: uuid::Uuid, client_key: &[u8])->Result<(Session, Extension), HandshakeError> {
+    if client_key.len() != KEY_SIZE {return Err(HandshakeError::Invalid);}
+    let mut client_public = [0u8; KEY_SIZE];
+    client_public.copy_from_slice(client_key);
+    let client_public_key = PublicKey::from(client_public);
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let ephemeral_secret = ephemeral.diffie_hellman(&client_public_key);
+    if ephemeral_secret.was_contributory() == false {return Err(HandshakeError::Invalid);}
+    let mut secrets = vec![&ephemeral_secret];
+    let mut static_public = [0u8; KEY_SIZE];
+    let static_secret = config.static_key.map(|k| {
+        let secret = StaticSecret::from(k);
+        static_public = PublicKey::from(&secret).to_bytes();
+        secret.diffie_hellman(&client_public_key)
+    });
+    if let Some(ref s) = static_secret {secrets.push(s);}
+    let session = Session::derive(id, &secrets, &[&client_public, ephemeral_public.as_bytes(), &static_public], false);
+    let confirmation = try!(session.seal_with_counter(0, &[]).ok_or(HandshakeError::Invalid));
+    let mut data = Vec::with_capacity(RESPONSE_SIZE);
+    data.extend_from_slice(ephemeral_public.as_bytes());
+    data.extend_from_slice(&static_public);
+    data.extend_from_slice(&confirmation);
+    Ok((session, Extension{name: ENCRYPTION_EXTENSION.to_string(), data: data}))
+}
+
+pub fn generate_key()->[u8; KEY_SIZE] {
+    StaticSecret::random_from_rng(OsRng).to_bytes()
+}
+
+pub fn public_key(secret: &[u8; KEY_SIZE])->[u8; KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+#[test]
+fn test_replay_window() {
+    let mut window = ReplayWindow::new();
+    assert!(window.is_fresh(0) == false);
+    window.mark(5);
+    assert!(window.is_fresh(5) == false);
+    assert!(window.is_fresh(3));
+    window.mark(3);
+    assert!(window.is_fresh(3) == false);
+    window.mark(100);
+    //64 behind is too old, but 63 isn't.
+    assert!(window.is_fresh(36) == false);
+    assert!(window.is_fresh(37));
+    assert!(window.is_fresh(5) == false);
+    assert!(window.is_fresh(101));
+}
+
+#[test]
+fn test_handshake() {
+    let id = uuid::Uuid::new_v4();
+    let static_key = generate_key();
+    let config = async::EncryptionConfig{policy: async::EncryptionPolicy::Preferred, static_key: Some(static_key)};
+    let client = ClientHandshake::new(Some(public_key(&static_key)));
+    let (mut server_session, response) = respond(&config, id, &client.extension().data).unwrap();
+    let mut client_session = client.finish(id, &response.data).unwrap();
+    assert_eq!(client_session.tag(), server_session.tag());
+    let sealed = client_session.seal(b"hello").unwrap();
+    if let Packet::Encrypted{counter, ref ciphertext, ..} = sealed {
+        assert_eq!(server_session.open(counter, ciphertext), Some(b"hello".to_vec()));
+        //A replay.
+        assert_eq!(server_session.open(counter, ciphertext), None);
+        //And a forgery.
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_eq!(server_session.open(counter+1, &tampered), None);
+    }
+    else {panic!("Expected an encrypted packet.");}
+    //The overhead is exactly what sealing adds on the wire.
+    let data = Packet::Data{chan: 3, packet: packets::DataPacketBuilder::with_payload(0, vec![1; 50]).build()};
+    let mut plaintext = [0u8; 100];
+    let plaintext_size = packets::encode_packet(&data, &mut plaintext).unwrap();
+    let mut sealed = [0u8; 200];
+    let sealed_size = packets::encode_packet(client_session.seal(&plaintext[..plaintext_size]).unwrap(), &mut sealed).unwrap();
+    assert_eq!(sealed_size, plaintext_size+ENCRYPTION_OVERHEAD);
+    assert_eq!(super::packet_size(&data), plaintext_size+4);
+    //Pinning some other key fails.
+    let other = ClientHandshake::new(Some(public_key(&generate_key())));
+    let (_, response) = respond(&config, id, &other.extension().data).unwrap();
+    assert_eq!(other.finish(id, &response.data).err(), Some(HandshakeError::KeyMismatch));
+}
@@ -0,0 +1,32 @@
+/*! The `fastnet_message_headers` extension.
+
+The client lists the message header versions it understands in the connect packet, and the server answers with the one both sides will use.
+Without the extension in the connected packet, messages have no headers, which keeps older peers working.*/
+use packets::{self, Extension};
+
+pub const MESSAGE_HEADERS_EXTENSION: &'static str = "fastnet_message_headers";
+
+/**The extension for the client's connect packet.*/
+pub fn offer_message_headers()->Extension {
+    Extension{name: MESSAGE_HEADERS_EXTENSION.to_string(), data: vec![packets::MESSAGE_HEADER_VERSION]}
+}
+
+/**The server's answer to the client's offer, or None if there's no version in common.*/
+pub fn accept_message_headers(offer: &[u8])->Option<Extension> {
+    if offer.contains(&packets::MESSAGE_HEADER_VERSION) == false {return None;}
+    Some(offer_message_headers())
+}
+
+/**True if the server's answer says to use headers.*/
+pub fn message_headers_accepted(response: &[u8])->bool {
+    response == [packets::MESSAGE_HEADER_VERSION]
+}
+
+#[test]
+fn test_message_headers_negotiation() {
+    let offer = offer_message_headers();
+    let response = accept_message_headers(&offer.data).unwrap();
+    assert!(message_headers_accepted(&response.data));
+    assert_eq!(accept_message_headers(&[5, 6]), None);
+    assert!(message_headers_accepted(&[]) == false);
+}
@@ -7,61 +7,121 @@ use byteorder::{self, BigEndian, ByteOrder};
 use std::collections;
 use std::net;
 use std::thread;
+use std::mem;
+use std::panic;
 use std::io;
 use std::sync::mpsc;
 use std::time;
 use std::borrow::{Borrow};
+use std::cmp;
+use std::fs;
+use std::sync::Arc;
 use mio;
-use mio::udp;
+use mio::net::UdpSocket;
 use uuid;
+use congestion::{CongestionController, NewRenoController};
+use capture::PcapWriter;
+use sha2::Sha256;
 
 const SOCKET_TOKEN: mio::Token = mio::Token(0);
+const WAKER_TOKEN: mio::Token = mio::Token(1);
+//The timer wheel only needs to be fine enough for the 200 MS tick.
+const TIMER_TICK_MS: u64 = 10;
+const TIMER_SLOTS: usize = 128;
+//Answers to packets from addresses we haven't verified can be at most this many times larger than what they answer.
+const AMPLIFICATION_FACTOR: usize = 3;
+//No extension we know of has a name anywhere near this long.
+const MAX_EXTENSION_NAME_LENGTH: usize = 64;
 
 #[derive(Debug, Copy, Clone)]
 pub enum TimeoutTypes {
     Timeout1000,
     Timeout200,
+    //For pacing.
+    Timeout10,
 }
 
 pub enum MioHandlerCommand<H: async::Handler> {
-    DoCall(Box<Fn(&mut MioHandler<H>)+Send>),
+    DoCall(Box<FnOnce(&mut MioHandler<H>)+Send>),
+    //Stop the event loop, first closing connections gracefully if there's a timeout.
+    Shutdown(Option<time::Duration>),
 }
 
 /*This doesn't have a good name.
 
 Basically it exists so that we can pass some stuff around without making the borrow checker mad.  Primarily it "provides" services, so we call it for that.*/
-pub struct MioServiceProvider<'a, H: async::Handler> {
-    pub socket: &'a udp::UdpSocket,
+pub struct MioServiceProvider<H: async::Handler> {
+    pub socket: UdpSocket,
     pub incoming_packet_buffer: [u8; 1000],
     pub outgoing_packet_buffer: [u8; 1000],
+    //Packets are encoded here before being encrypted into outgoing_packet_buffer.
+    pub plaintext_buffer: [u8; 1000],
     pub handler: H,
+    //The cap on what all connections send together.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    pub capture: Option<Capture>,
 }
 
-pub struct MioHandler<'a, H: async::Handler> {
-    service: MioServiceProvider<'a, H>,
-    connections: collections::HashMap<net::SocketAddr, Connection>,
+//Where datagrams are being captured to, and our address for them.
+pub struct Capture {
+    writer: PcapWriter<Box<io::Write+Send>>,
+    local: net::SocketAddr,
+}
+
+pub struct MioHandler<H: async::Handler> {
+    service: MioServiceProvider<H>,
+    connections: collections::HashMap<uuid::Uuid, Connection>,
+    //Which connection is at which address.  Changes when peers move.
+    addresses: collections::HashMap<net::SocketAddr, uuid::Uuid>,
+    //Which connection owns which encryption tag.
+    tags: collections::HashMap<u64, uuid::Uuid>,
+    encryption: async::EncryptionConfig,
+    //Connect packets have to echo one of these before we remember anything about the client.
+    cookies: CookieJar,
+    status_query_limiter: RateLimiter,
+    connect_limiter: RateLimiter,
+    stats: async::Stats,
+    //Makes the congestion controller for each new connection.
+    congestion_control: Box<Fn()->Box<CongestionController>+Send>,
+    bandwidth: async::BandwidthConfig,
+    channel_priorities: collections::HashMap<i16, u8>,
+    streaming_channels: collections::HashSet<i16>,
+    receipt_channels: collections::HashSet<i16>,
+    channel_modes: collections::HashMap<i16, async::ChannelMode>,
+    compression: Option<Arc<async::CompressionConfig>>,
     connection_timeout_duration: time::Duration,
-    //This is a workaround because maps don't have retain.
-    connection_key_vector: Vec<net::SocketAddr>,
+    //Set once we start shutting down, after which we refuse new connections.
+    shutting_down: bool,
 }
 
-impl<'a, H: async::Handler> MioHandler<'a, H> {
-    pub fn new(socket: &'a udp::UdpSocket, handler: H)->MioHandler<'a, H> {
+impl<H: async::Handler> MioHandler<H> {
+    pub fn new(socket: UdpSocket, handler: H)->MioHandler<H> {
+        let rate_limits = async::RateLimitConfig::default();
         MioHandler {
-            service: MioServiceProvider {
-                socket: socket,
-                incoming_packet_buffer: [0u8; 1000],
-                outgoing_packet_buffer: [0u8; 1000],
-                handler: handler,
-            },
+            service: MioServiceProvider::new(socket, handler),
             connections: collections::HashMap::new(),
-            connection_key_vector: Vec::default(),
+            addresses: collections::HashMap::new(),
+            tags: collections::HashMap::new(),
+            encryption: async::EncryptionConfig::default(),
+            cookies: CookieJar::new(),
+            status_query_limiter: RateLimiter::new(rate_limits.status_queries_per_second, rate_limits.status_query_burst),
+            connect_limiter: RateLimiter::new(rate_limits.connect_attempts_per_second, rate_limits.connect_attempt_burst),
+            stats: async::Stats::default(),
+            congestion_control: Box::new(|| Box::new(NewRenoController::new())),
+            bandwidth: async::BandwidthConfig::default(),
+            channel_priorities: collections::HashMap::new(),
+            streaming_channels: collections::HashSet::new(),
+            receipt_channels: collections::HashSet::new(),
+            channel_modes: collections::HashMap::new(),
+            compression: None,
             connection_timeout_duration: time::Duration::from_secs(10),
+            shutting_down: false,
         }
     }
 
     fn got_packet(&mut self, size: usize, address: net::SocketAddr) {
         if size == 0 {return;}
+        self.service.capture_datagram(true, address, size);
         let maybe_packet = {
             let slice = &self.service.incoming_packet_buffer[0..size];
             let computed_checksum = crc32::checksum_castagnoli(&slice[4..]);
@@ -72,25 +132,139 @@ impl<'a, H: async::Handler> MioHandler<'a, H> {
             }
             else {packets::decode_packet(&slice[4..])}
         };
-        if let Err(_) = maybe_packet {return;}
-        let packet = maybe_packet.unwrap();
+        let packet = match maybe_packet {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        //The connection which decrypted the packet, if it was encrypted.
+        let (packet, secured_id) = match packet {
+            packets::Packet::Encrypted{tag, counter, ref ciphertext} => match self.decrypt(tag, counter, ciphertext) {
+                Some(p) => p,
+                None => return,
+            },
+            p@_ => (p, None),
+        };
         debug!("Incoming from {:?}: {:?}", address, packet);
-        if let Some(ref mut conn) = self.connections.get_mut(&address) {
-            if conn.handle_incoming_packet(&packet, &mut self.service) {return;}
+        let mut handled = false;
+        let mut closed = false;
+        let existing_id = self.addresses.get(&address).cloned();
+        if let Some(id) = existing_id {
+            if let Some(conn) = self.connections.get_mut(&id) {
+                if conn.accepts(&packet, secured_id) {
+                    handled = conn.handle_incoming_packet(&packet, &mut self.service);
+                    closed = conn.is_closed();
+                    if let Some(ref session) = conn.session {self.tags.insert(session.tag(), id);}
+                }
+                //Someone without the keys is pretending to be this peer.
+                else {return;}
+            }
+            //Get rid of it now, so that the address is free for a new connection.
+            if closed {
+                self.connections.remove(&id);
+                self.addresses.remove(&address);
+                self.tags.retain(|_, i| *i != id);
+            }
         }
+        //The tag of an encrypted packet names its connection, so there's no need to wait for a heartbeat to notice that the peer moved.
+        //The packet is authentic and can't be a replay, so the connection uses it, but nothing goes to the new address until the challenge is answered.
+        let moved_id = match (secured_id, &packet) {
+            (_, &packets::Packet::PathResponse{..}) => None,
+            (Some(id), _) if existing_id != Some(id) => Some(id),
+            _ => None,
+        };
+        if let Some(id) = moved_id {
+            let mut old_address = None;
+            if let Some(conn) = self.connections.get_mut(&id) {
+                handled = conn.handle_incoming_packet(&packet, &mut self.service);
+                if conn.is_closed() {old_address = Some(conn.address);}
+                else {conn.challenge_path(address, &mut self.service);}
+            }
+            if let Some(old) = old_address {
+                self.connections.remove(&id);
+                self.addresses.remove(&old);
+                self.tags.retain(|_, i| *i != id);
+            }
+        }
+        if handled {return;}
         match packet {
-            packets ::Packet::Connect(id) => {
-                if let Some(c) = self.connections.get(&address) {
-                    self.service.send(packets::Packet::Connected(c.id), address);
+            packets ::Packet::Connect{id, ref extensions} => {
+                if self.connect_limiter.allow(address.ip(), time::Instant::now()) == false {
+                    self.stats.connect_attempts_dropped += 1;
                     return;
                 }
-                let conn = Connection::from_connection_request(address, id);
-                self.connections.insert(address, conn);
-                self.service.send(packets::Packet::Connected(id), address);
+                if let Some(existing) = self.addresses.get(&address).and_then(|i| self.connections.get(i)) {
+                    //Our connected packet was lost, so send the same one again.
+                    self.service.send(packets::Packet::Connected{id: existing.id, extensions: existing.handshake_extensions.clone()}, address);
+                    return;
+                }
+                //Make sure the client is really at this address before doing any work.
+                if self.cookies.check(address, id, extensions) == false {
+                    //Answering anything smaller would let people use us to amplify traffic.
+                    if size < COOKIE_PACKET_SIZE {return;}
+                    self.service.send(packets::Packet::Cookie{id: id, cookie: self.cookies.make(address, id)}, address);
+                    return;
+                }
+                //A UUID collision, which the spec lets us ignore.
+                if self.connections.contains_key(&id) {return;}
+                if self.shutting_down {
+                    self.service.send_limited(packets::Packet::Aborted("shutting down".to_string()), address, size*AMPLIFICATION_FACTOR);
+                    return;
+                }
+                if unordered_channels_match(&self.channel_modes, extensions) == false {
+                    self.service.send_limited(packets::Packet::Aborted(CHANNEL_MODE_MISMATCH.to_string()), address, size*AMPLIFICATION_FACTOR);
+                    return;
+                }
+                let mut conn = match Connection::from_connection_request(address, id, extensions, &self.encryption) {
+                    Ok(c) => c,
+                    Err(message) => {
+                        self.service.send_limited(packets::Packet::Aborted(message.to_string()), address, size*AMPLIFICATION_FACTOR);
+                        return;
+                    },
+                };
+                self.setup_connection(&mut conn);
+                conn.accept_compression(extensions);
+                conn.accept_message_headers(extensions);
+                self.service.send(packets::Packet::Connected{id: id, extensions: conn.handshake_extensions.clone()}, address);
+                if let Some(ref session) = conn.session {self.tags.insert(session.tag(), id);}
+                self.connections.insert(id, conn);
+                self.addresses.insert(address, id);
                 self.service.handler.connected(id, None);
             },
             packets::Packet::StatusRequest(ref req) => {
-                self.service.send(packets::Packet::StatusResponse(status_translator::translate(req)), address);
+                let allowed = match *req {
+                    packets::StatusRequest::ExtensionQuery(ref name) if name.len() > MAX_EXTENSION_NAME_LENGTH => false,
+                    _ => self.status_query_limiter.allow(address.ip(), time::Instant::now()),
+                };
+                //The response is only sent if it isn't too much larger than the query.
+                if allowed == false || self.service.send_limited(packets::Packet::StatusResponse(status_translator::translate(req)), address, size*AMPLIFICATION_FACTOR) == false {
+                    self.stats.status_queries_dropped += 1;
+                }
+            },
+            //We already forgot the connection, but the closed packet we sent must have been lost.
+            //Anything encrypted came from a connection we still have, so it isn't that.
+            packets::Packet::Close(id) if secured_id.is_none() && self.connections.contains_key(&id) == false => {
+                self.service.send(packets::Packet::Closed(id), address);
+            },
+            //The peer might have moved.  Unencrypted connections only notice with heartbeats, because they're the only packets after the handshake which carry the id.
+            packets::Packet::Heartbeat{id, ..} => {
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    if conn.accepts(&packet, secured_id) {conn.challenge_path(address, &mut self.service);}
+                }
+            },
+            packets::Packet::PathResponse{id, challenge} => {
+                let old_address = match self.connections.get_mut(&id) {
+                    Some(conn) => {
+                        if conn.accepts(&packet, secured_id) == false {return;}
+                        let old = conn.address;
+                        if conn.handle_path_response(address, challenge) == false {return;}
+                        old
+                    },
+                    None => return,
+                };
+                self.addresses.remove(&old_address);
+                self.addresses.insert(address, id);
+                info!("Connection {} moved from {} to {}", id, old_address, address);
+                self.service.handler.peer_address_changed(id, old_address, address);
             },
             p@_ => {
                 debug!("Previous packet was unhandled.");
@@ -98,30 +272,280 @@ impl<'a, H: async::Handler> MioHandler<'a, H> {
         }
     }
 
-    pub fn connect(&mut self, address: net:: SocketAddr, request_id: u64) {
-        let id = uuid::Uuid::new_v4();
+    //Open an encrypted packet.  Returns the packet inside and the connection it belongs to.
+    fn decrypt(&mut self, tag: u64, counter: u64, ciphertext: &[u8])->Option<(packets::Packet, Option<uuid::Uuid>)> {
+        let id = match self.tags.get(&tag) {
+            Some(&i) => i,
+            None => return None,
+        };
+        let plaintext = match self.connections.get_mut(&id).and_then(|c| c.session.as_mut()).and_then(|s| s.open(counter, ciphertext)) {
+            Some(p) => p,
+            None => return None,
+        };
+        match packets::decode_packet(&plaintext) {
+            //Nesting encryption is never legitimate.
+            Ok(packets::Packet::Encrypted{..}) | Err(_) => None,
+            Ok(p) => Some((p, Some(id))),
+        }
+    }
+
+    /**Connect to a peer.
+
+If `pinned_key` is provided, the connection is encrypted and the server must prove that it has the matching static key.*/
+    pub fn connect(&mut self, address: net:: SocketAddr, request_id: u64, pinned_key: Option<[u8; 32]>) {
+        if self.addresses.contains_key(&address) {
+            self.service.handler.request_failed(request_id, async::Error::AlreadyConnected);
+            return;
+        }
+        let mut id = uuid::Uuid::new_v4();
+        while self.connections.contains_key(&id) {id = uuid::Uuid::new_v4();}
         info!("New connection, id = {}", id);
         let mut conn = Connection::new(address, id);
+        conn.encryption_policy = if pinned_key.is_some() {async::EncryptionPolicy::Required} else {self.encryption.policy};
+        conn.pinned_key = pinned_key;
+        self.setup_connection(&mut conn);
         conn.establish(Some(request_id), &mut self.service);
-        self.connections.insert(address, conn);
+        self.connections.insert(id, conn);
+        self.addresses.insert(address, id);
+    }
+
+    //Apply the configuration that new connections get.
+    fn setup_connection(&self, conn: &mut Connection) {
+        conn.congestion = (self.congestion_control)();
+        conn.bandwidth_limit = self.bandwidth.per_connection_bytes_per_second.map(|rate| BandwidthLimit::new(rate, self.bandwidth.per_connection_burst, time::Instant::now()));
+        conn.drop_unreliable = self.bandwidth.drop_unreliable;
+        for (&channel, &priority) in self.channel_priorities.iter() {conn.send_queue.set_priority(channel, priority);}
+        conn.compression_config = self.compression.clone();
+        conn.streaming_channels = self.streaming_channels.clone();
+        conn.receipt_channels = self.receipt_channels.clone();
+        conn.channel_modes = self.channel_modes.clone();
+    }
+
+    //If there's a handle, the handler hears about messages which can't be sent.
+    pub fn send_message(&mut self, id: uuid::Uuid, channel: u16, header: &packets::MessageHeader, payload: &[u8], reliable: bool, handle: Option<async::MessageHandle>)->Result<(), async::Error> {
+        match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => {
+                if conn.send_message(channel as i16, header, payload, reliable, handle, &mut self.service) == false {
+                    self.stats.unreliable_messages_dropped += 1;
+                }
+                Ok(())
+            },
+            _ => {
+                match handle {
+                    Some(h) if reliable => self.service.handler.message_failed(id, channel, h, async::Error::PeerNotFound),
+                    Some(h) if self.receipt_channels.contains(&(channel as i16)) => self.service.handler.unreliable_lost(id, channel, h),
+                    _ => {},
+                }
+                Err(async::Error::PeerNotFound)
+            },
+        }
+    }
+
+    pub fn send_message_with_ttl(&mut self, id: uuid::Uuid, channel: u16, header: &packets::MessageHeader, payload: &[u8], ttl: time::Duration, handle: async::MessageHandle) {
+        match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.send_expiring_message(channel as i16, header, payload, time::Instant::now()+ttl, handle, &mut self.service),
+            _ => self.service.handler.message_failed(id, channel, handle, async::Error::PeerNotFound),
+        }
+    }
+
+    pub fn send_request(&mut self, id: uuid::Uuid, channel: u16, payload: &[u8], timeout: time::Duration, handle: async::MessageHandle) {
+        match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.send_request(channel as i16, payload, time::Instant::now()+timeout, handle, &mut self.service),
+            _ => self.service.handler.response_failed(id, channel, handle, async::Error::PeerNotFound),
+        }
+    }
+
+    pub fn send_response(&mut self, id: uuid::Uuid, channel: u16, request_id: u64, payload: &[u8])->Result<(), async::Error> {
+        match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => {
+                conn.send_response(channel as i16, request_id, payload, &mut self.service);
+                Ok(())
+            },
+            _ => Err(async::Error::PeerNotFound),
+        }
+    }
+
+    pub fn cancel_request(&mut self, id: uuid::Uuid, handle: async::MessageHandle) {
+        if let Some(conn) = self.connections.get_mut(&id) {conn.cancel_request(handle.0);}
+    }
+
+    pub fn send_stream(&mut self, id: uuid::Uuid, channel: u16, reader: Box<io::Read+Send>, length: u64, request_id: u64) {
+        match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.send_stream(channel as i16, reader, length, request_id, &mut self.service),
+            _ => self.service.handler.request_failed(request_id, async::Error::PeerNotFound),
+        }
+    }
+
+    pub fn offer_asset(&mut self, id: uuid::Uuid, transfer: u64, file: fs::File, size: u64, hash: [u8; 32], name: String) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.offer_asset(transfer, file, size, hash, name, &mut self.service),
+            _ => Err(async::Error::PeerNotFound),
+        };
+        if let Err(e) = result {self.service.handler.asset_failed(id, async::TransferDirection::Outgoing, transfer, e);}
+    }
+
+    pub fn accept_asset(&mut self, id: uuid::Uuid, transfer: u64, file: fs::File, offset: u64, hasher: Sha256) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.accept_asset(transfer, file, offset, hasher, &mut self.service),
+            _ => Err(async::Error::PeerNotFound),
+        };
+        if let Err(e) = result {self.service.handler.asset_failed(id, async::TransferDirection::Incoming, transfer, e);}
+    }
+
+    pub fn cancel_asset(&mut self, id: uuid::Uuid, direction: async::TransferDirection, transfer: u64) {
+        let result = match self.connections.get_mut(&id) {
+            Some(conn) if conn.is_established() => conn.cancel_asset(direction, transfer, &mut self.service),
+            _ => Err(async::Error::PeerNotFound),
+        };
+        if let Err(e) = result {self.service.handler.asset_failed(id, direction, transfer, e);}
     }
 
     pub fn disconnect(&mut self, id: uuid::Uuid, request_id: u64) {
-        //todo: fill this out.
+        match self.connections.get_mut(&id) {
+            Some(c) if c.is_established() => c.close(Some(request_id), &mut self.service),
+            _ => self.service.handler.request_failed(request_id, async::Error::PeerNotFound),
+        }
     }
 
     pub fn configure_timeout(&mut self, timeout_ms: u64) {
         self.connection_timeout_duration = time::Duration::from_millis(timeout_ms);
     }
+
+    pub fn configure_encryption(&mut self, config: async::EncryptionConfig) {
+        self.encryption = config;
+    }
+
+    pub fn configure_rate_limits(&mut self, config: async::RateLimitConfig) {
+        self.status_query_limiter.configure(config.status_queries_per_second, config.status_query_burst);
+        self.connect_limiter.configure(config.connect_attempts_per_second, config.connect_attempt_burst);
+    }
+
+    pub fn configure_compression(&mut self, config: async::CompressionConfig) {
+        self.compression = if config.enabled {Some(Arc::new(config))} else {None};
+    }
+
+    pub fn configure_congestion_control(&mut self, factory: Box<Fn()->Box<CongestionController>+Send>) {
+        self.congestion_control = factory;
+    }
+
+    pub fn configure_bandwidth(&mut self, config: async::BandwidthConfig) {
+        self.bandwidth = config;
+        let now = time::Instant::now();
+        self.service.bandwidth_limit = config.total_bytes_per_second.map(|rate| BandwidthLimit::new(rate, config.total_burst, now));
+        for c in self.connections.values_mut() {
+            c.bandwidth_limit = config.per_connection_bytes_per_second.map(|rate| BandwidthLimit::new(rate, config.per_connection_burst, now));
+            c.drop_unreliable = config.drop_unreliable;
+        }
+    }
+
+    pub fn configure_channel_priority(&mut self, channel: u16, priority: u8) {
+        self.channel_priorities.insert(channel as i16, priority);
+        for c in self.connections.values_mut() {c.send_queue.set_priority(channel as i16, priority);}
+    }
+
+    pub fn configure_streaming(&mut self, channel: u16, enabled: bool) {
+        if enabled {self.streaming_channels.insert(channel as i16);}
+        else {self.streaming_channels.remove(&(channel as i16));}
+        for c in self.connections.values_mut() {c.set_streaming(channel as i16, enabled);}
+    }
+
+    pub fn configure_unreliable_receipts(&mut self, channel: u16, enabled: bool) {
+        if enabled {self.receipt_channels.insert(channel as i16);}
+        else {self.receipt_channels.remove(&(channel as i16));}
+        for c in self.connections.values_mut() {c.set_unreliable_receipts(channel as i16, enabled);}
+    }
+
+    pub fn configure_channel_mode(&mut self, channel: u16, mode: async::ChannelMode) {
+        self.channel_modes.insert(channel as i16, mode);
+        for c in self.connections.values_mut() {c.set_channel_mode(channel as i16, mode);}
+    }
+
+    pub fn start_capture(&mut self, writer: PcapWriter<Box<io::Write+Send>>) {
+        //If we can't find out our address, the capture is still useful without it.
+        let local = self.service.socket.local_addr().unwrap_or(net::SocketAddr::new(net::Ipv4Addr::new(0, 0, 0, 0).into(), 0));
+        self.service.capture = Some(Capture{writer: writer, local: local});
+    }
+
+    pub fn stop_capture(&mut self) {
+        if let Some(mut c) = self.service.capture.take() {
+            if let Err(e) = c.writer.flush() {warn!("Couldn't finish the capture: {}", e);}
+        }
+    }
+
+    pub fn stats(&self)->async::Stats {
+        self.stats
+    }
+
+    //Start closing everything and stop accepting connections.
+    fn close_all(&mut self) {
+        self.shutting_down = true;
+        for c in self.connections.values_mut() {
+            if c.is_established() {c.close(None, &mut self.service);}
+            else {c.set_closed(async::Error::ConnectionAborted, &mut self.service);}
+        }
+        self.remove_closed();
+    }
+
+    //True while any connection is waiting for the other side to acknowledge a close.
+    fn is_closing(&self)->bool {
+        self.connections.values().any(|c| if let ConnectionState::Closing{..} = c.state {true} else {false})
+    }
+
+    //Forget every connection without telling the peers.
+    fn abandon_all(&mut self) {
+        for c in self.connections.values_mut() {
+            c.set_closed(async::Error::ConnectionAborted, &mut self.service);
+        }
+        self.connections.clear();
+        self.addresses.clear();
+        self.tags.clear();
+    }
+
+    fn into_handler(mut self)->H {
+        self.abandon_all();
+        self.service.handler
+    }
+
+    fn remove_closed(&mut self) {
+        self.connections.retain(|_, c| c.is_closed() == false);
+        let connections = &self.connections;
+        self.addresses.retain(|_, id| connections.contains_key(id));
+        self.tags.retain(|_, id| connections.contains_key(id));
+    }
 }
 
-impl<'A, H: async::Handler> MioServiceProvider<'A, H> {
+impl<H: async::Handler> MioServiceProvider<H> {
+    pub fn new(socket: UdpSocket, handler: H)->MioServiceProvider<H> {
+        MioServiceProvider {
+            socket: socket,
+            incoming_packet_buffer: [0u8; 1000],
+            outgoing_packet_buffer: [0u8; 1000],
+            plaintext_buffer: [0u8; 1000],
+            handler: handler,
+            bandwidth_limit: None,
+            capture: None,
+        }
+    }
+
     pub fn send<P: Borrow<packets::Packet>>(&mut self, packet: P, address: net::SocketAddr)->bool {
+        let limit = self.outgoing_packet_buffer.len();
+        self.send_limited(packet, address, limit)
+    }
+
+    /**Send a packet, unless it's more than `limit` bytes including the checksum.
+
+Used to make sure that answers to unverified addresses aren't much larger than the packets they answer.*/
+    pub fn send_limited<P: Borrow<packets::Packet>>(&mut self, packet: P, address: net::SocketAddr, limit: usize)->bool {
         debug!("sending to {:?}: {:?}", address, packet.borrow());
         if let Ok(size) = packets::encode_packet(packet, &mut self.outgoing_packet_buffer[4..]) {
+            if 4+size > limit {
+                debug!("Not sending {} bytes, limit is {}", 4+size, limit);
+                return false;
+            }
             let checksum = crc32::checksum_castagnoli(&self.outgoing_packet_buffer[4..4+size]);
             BigEndian::write_u32(&mut self.outgoing_packet_buffer[..4], checksum);
-            if let Ok(Some(sent_bytes)) = self.socket.send_to(&self.outgoing_packet_buffer[..4+size], &address) {
+            if let Ok(sent_bytes) = self.socket.send_to(&self.outgoing_packet_buffer[..4+size], address) {
+                self.capture_datagram(false, address, sent_bytes);
                 if sent_bytes == 4+size {return true;}
                 else {return false;}
             }
@@ -129,114 +553,479 @@ impl<'A, H: async::Handler> MioServiceProvider<'A, H> {
         }
         else {return false;};
     }
-}
 
-impl<'a, H: async::Handler+Send> mio::Handler for MioHandler<'a, H> {
-    type Timeout = TimeoutTypes;
-    type Message = MioHandlerCommand<H>;
+    //Write the first `size` bytes of the incoming or outgoing buffer to the capture, if there is one.
+    fn capture_datagram(&mut self, incoming: bool, address: net::SocketAddr, size: usize) {
+        let result = match self.capture {
+            Some(ref mut c) if incoming => c.writer.write_datagram(time::SystemTime::now(), address, c.local, &self.incoming_packet_buffer[..size]),
+            Some(ref mut c) => c.writer.write_datagram(time::SystemTime::now(), c.local, address, &self.outgoing_packet_buffer[..size]),
+            None => return,
+        };
+        if let Err(e) = result {
+            warn!("Stopping the capture after an error: {}", e);
+            self.capture = None;
+        }
+    }
 
-    fn ready(&mut self, event_loop: &mut mio::EventLoop<Self>, token: mio::Token, events: mio::EventSet) {
-        //We only have one socket, so can avoid the match on the token.
-        if events.is_error() {
-            //We need to do something sensible here, probably a callback with whatever state we can get.
+    //So that captures can be read while the server is running.
+    fn flush_capture(&mut self) {
+        let flushed = self.capture.as_mut().map_or(Ok(()), |c| c.writer.flush());
+        if let Err(e) = flushed {
+            warn!("Stopping the capture after an error: {}", e);
+            self.capture = None;
         }
-        if events.is_readable() {
-            let result = self.service.socket.recv_from(&mut self.service.incoming_packet_buffer);
-            if let Ok(Some((size, address))) = result {
-                self.got_packet(size, address);
+    }
+
+    /**Send a packet, encrypting it if there's a session.
+
+The handshake is always sent in the clear, because the other side can't have the keys yet.*/
+    pub fn send_secured<P: Borrow<packets::Packet>>(&mut self, packet: P, address: net::SocketAddr, session: Option<&mut Session>)->bool {
+        let session = match session {
+            Some(s) if packet.borrow().is_handshake() == false => s,
+            _ => return self.send(packet, address),
+        };
+        debug!("sending encrypted to {:?}: {:?}", address, packet.borrow());
+        let size = match packets::encode_packet(packet, &mut self.plaintext_buffer) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        match session.seal(&self.plaintext_buffer[..size]) {
+            Some(encrypted) => self.send(encrypted, address),
+            None => false,
+        }
+    }
+}
+
+//These used to be the implementation of mio::Handler, before mio stopped providing an event loop.
+impl<H: async::Handler> MioHandler<H> {
+    //Returns an error if the socket is broken.
+    fn ready(&mut self, event: &mio::event::Event)->Result<(), io::Error> {
+        if event.is_error() {
+            match self.service.socket.take_error() {
+                Ok(Some(e)) | Err(e) => return Err(e),
+                Ok(None) => {},
+            }
+        }
+        if event.is_readable() {
+            //Readiness is edge-triggered, so we have to drain the socket.
+            loop {
+                let result = self.service.socket.recv_from(&mut self.service.incoming_packet_buffer);
+                match result {
+                    Ok((size, address)) => self.got_packet(size, address),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    //Some platforms report ICMP errors for packets we sent earlier this way.  They don't mean the socket is broken.
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset || e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => return Err(e),
+                }
             }
         }
+        Ok(())
     }
 
-    fn timeout(&mut self, event_loop: &mut mio::EventLoop<Self>, timeout: Self::Timeout) {
-        let rereg = match timeout {
+    //Returns how long until the timeout should fire again, in MS.
+    fn timeout(&mut self, timeout: TimeoutTypes)->u64 {
+        match timeout {
+            TimeoutTypes::Timeout10 => {
+                for i in self.connections.iter_mut() {i.1.tick10(&mut self.service)}
+                10
+            },
             TimeoutTypes::Timeout200 => {
                 for i in self.connections.iter_mut() {i.1.tick200(&mut self.service)}
+                //Failed connection attempts and finished closes.
+                self.remove_closed();
                 200
             },
             TimeoutTypes::Timeout1000 => {
-                self.connection_key_vector.clear();
                 let now = time::Instant::now();
                 for i in self.connections.iter_mut() {
                     i.1.tick1000(&mut self.service);
                     if now.duration_since(i.1.last_received_packet_time) > self.connection_timeout_duration {
-                        self.connection_key_vector.push(*i.0);
-                        self.service.handler.disconnected(i.1.id, None);
+                        i.1.set_closed(async::Error::TimedOut, &mut self.service);
                     }
                 }
-                for i in self.connection_key_vector.iter() {
-                    self.connections.remove(&i);
-                }
+                self.remove_closed();
+                self.status_query_limiter.prune(now);
+                self.connect_limiter.prune(now);
+                self.service.flush_capture();
                 1000
             },
+        }
+    }
+
+}
+
+/**Owns everything needed to run a MioHandler: the poll, the timers, and the receiving end of the command channel.
+
+This is shared between the threaded and polled modes.*/
+pub struct MioEventLoop<H: async::Handler> {
+    poll: mio::Poll,
+    events: mio::Events,
+    timers: TimerWheel<TimeoutTypes>,
+    expired_timers: Vec<TimeoutTypes>,
+    commands: mpsc::Receiver<MioHandlerCommand<H>>,
+    handler: MioHandler<H>,
+    //The address the socket is bound to, for replacing it if it breaks.
+    address: net::SocketAddr,
+    //Set once a shutdown is requested.  We stop when everything is closed or this passes, whichever is first.
+    shutdown_deadline: Option<time::Instant>,
+}
+
+impl<H: async::Handler> MioEventLoop<H> {
+    //Creates the socket and the event loop, and arms the timers.
+    //Also returns the sending half of the command channel and the waker that goes with it.
+    pub fn new(address: net::SocketAddr, handler: H)->Result<(MioEventLoop<H>, mpsc::Sender<MioHandlerCommand<H>>, Arc<mio::Waker>), io::Error> {
+        let mut socket = try!(UdpSocket::bind(address));
+        //If we were asked for port 0, this is the port we actually got.
+        let address = try!(socket.local_addr());
+        let poll = try!(mio::Poll::new());
+        try!(poll.registry().register(&mut socket, SOCKET_TOKEN, mio::Interest::READABLE));
+        let waker = try!(mio::Waker::new(poll.registry(), WAKER_TOKEN));
+        let (sender, receiver) = mpsc::channel();
+        let mut timers = TimerWheel::new(TIMER_TICK_MS, TIMER_SLOTS);
+        let now = time::Instant::now();
+        timers.schedule(now, 1000, TimeoutTypes::Timeout1000);
+        timers.schedule(now, 200, TimeoutTypes::Timeout200);
+        timers.schedule(now, 10, TimeoutTypes::Timeout10);
+        let event_loop = MioEventLoop {
+            poll: poll,
+            events: mio::Events::with_capacity(128),
+            timers: timers,
+            expired_timers: Vec::default(),
+            commands: receiver,
+            handler: MioHandler::new(socket, handler),
+            address: address,
+            shutdown_deadline: None,
         };
-        event_loop.timeout_ms(timeout, rereg).unwrap();
+        Ok((event_loop, sender, Arc::new(waker)))
     }
 
-    fn notify(&mut self, event_loop: &mut mio::EventLoop<Self>, message: Self::Message) {
-        match message {
-            MioHandlerCommand::DoCall(ref f) => f(self),
+    /**Run one iteration: wait for events or the next timer, then process commands and timers.
+
+Waits at most `timeout`, or forever if it's None.
+Errors go to the handler.  If they can't be recovered from, the event loop stops as though it were shut down.*/
+    pub fn run_once(&mut self, timeout: Option<time::Duration>) {
+        if self.is_finished() {return;}
+        let now = time::Instant::now();
+        let next_timer = self.timers.next_timeout(now);
+        let timeout = match (timeout, next_timer) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        //Don't sleep through the end of a shutdown.
+        let timeout = match self.shutdown_deadline {
+            Some(d) => {
+                let remaining = if d > now {d-now} else {time::Duration::from_millis(0)};
+                Some(timeout.map_or(remaining, |t| cmp::min(t, remaining)))
+            },
+            None => timeout,
+        };
+        match self.poll.poll(&mut self.events, timeout) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => {
+                self.fail(e);
+                return;
+            },
+            Ok(_) => {},
+        }
+        let mut socket_error = None;
+        for event in self.events.iter() {
+            if event.token() == SOCKET_TOKEN {
+                if let Err(e) = self.handler.ready(event) {socket_error = Some(e);}
+            }
+            //The waker only exists to break us out of poll so that we look at the command channel.
+        }
+        if let Some(e) = socket_error {self.recover_socket(e);}
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                MioHandlerCommand::DoCall(f) => f(&mut self.handler),
+                MioHandlerCommand::Shutdown(timeout) => self.begin_shutdown(timeout),
+            }
+        }
+        let now = time::Instant::now();
+        self.timers.expire(now, &mut self.expired_timers);
+        for timeout in self.expired_timers.drain(..) {
+            let rereg = self.handler.timeout(timeout);
+            self.timers.schedule(now, rereg, timeout);
         }
     }
-}
 
-fn mio_server_thread< H: async::Handler+Send>(address: net::SocketAddr, handler: H, notify_created: mpsc::Sender<Result<mio::Sender<MioHandlerCommand<H>>, io::Error>>) {
-    let maybe_socket = match address {
-        net::SocketAddr::V4(_) => udp::UdpSocket::v4(),
-        net::SocketAddr::V6(_) => udp::UdpSocket::v6()
-    };
-    if let Err(what) = maybe_socket {
-        notify_created.send(Err(what)).unwrap();
-        return;
+    /**Replace a broken socket with a new one on the same address.
+
+Connections survive this, though packets may be lost.  If a new socket can't be made, the error is fatal.*/
+    fn recover_socket(&mut self, error: io::Error) {
+        warn!("Socket error: {}.  Replacing the socket.", error);
+        if let Err(e) = self.rebind() {self.fail(e);}
     }
-    let socket = maybe_socket.unwrap();
-    if let  Err(what) = socket.bind(&address) {
-        notify_created.send(Err(what)).unwrap();
-        return;
+
+    fn rebind(&mut self)->Result<(), io::Error> {
+        //The old socket has to be closed before we can bind its address again, so put a temporary one in its place.
+        let unspecified: net::SocketAddr = match self.address {
+            net::SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            net::SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let temporary = try!(UdpSocket::bind(unspecified));
+        let mut old = mem::replace(&mut self.handler.service.socket, temporary);
+        try!(self.poll.registry().deregister(&mut old));
+        drop(old);
+        let mut socket = try!(UdpSocket::bind(self.address));
+        try!(self.poll.registry().register(&mut socket, SOCKET_TOKEN, mio::Interest::READABLE));
+        self.handler.service.socket = socket;
+        Ok(())
     }
-    let maybe_loop  = mio::EventLoop::new();
-    if let Err(what) = maybe_loop {
-        notify_created.send(Err(what)).unwrap();
-        return;
+
+    //Something broke which we can't fix.  Tell the handler and stop.
+    fn fail(&mut self, error: io::Error) {
+        error!("Fatal error: {}", error);
+        self.handler.service.handler.server_error(async::Error::IoError(error));
+        self.handler.abandon_all();
+        self.begin_shutdown(None);
     }
-    let mut event_loop = maybe_loop.unwrap();
-    let mut handler = MioHandler::new(&socket, handler);
-    if let Err(what)  = event_loop.register(&socket, SOCKET_TOKEN, mio::EventSet::all(), mio::PollOpt::level()) {
-        notify_created.send(Err(what)).unwrap();
-        return;
+
+    /**Start shutting down.
+
+With a timeout, connections are closed gracefully and we keep going until they finish or it passes.  Without one, we stop immediately.*/
+    pub fn begin_shutdown(&mut self, timeout: Option<time::Duration>) {
+        let now = time::Instant::now();
+        match timeout {
+            Some(t) => {
+                self.handler.close_all();
+                self.shutdown_deadline = Some(now+t);
+            },
+            None => self.shutdown_deadline = Some(now),
+        }
+    }
+
+    //True once the event loop has stopped, either because a shutdown finished or because of a fatal error.
+    pub fn is_finished(&self)->bool {
+        match self.shutdown_deadline {
+            Some(d) => time::Instant::now() >= d || self.handler.is_closing() == false,
+            None => false,
+        }
     }
-    let timer_error = Err(io::Error::new(io::ErrorKind::Other, "Couldn't create the timer."));
-    if let Err(_) = event_loop.timeout_ms(TimeoutTypes::Timeout1000, 1000) {
-        notify_created.send(timer_error).unwrap();
-        return;
+
+    /**Run until shut down, then return the user's handler.
+
+Anything still connected at that point is forgotten, and the handler is told.*/
+    pub fn run(mut self)->H {
+        while self.is_finished() == false {
+            self.run_once(None);
+        }
+        self.handler.into_handler()
     }
-    if let Err(_) = event_loop.timeout_ms(TimeoutTypes::Timeout200, 200) {
-        notify_created.send(timer_error);
-        return;
+
+    pub fn handler(&mut self)->&mut MioHandler<H> {
+        &mut self.handler
     }
-    let sender = event_loop.channel();
-    notify_created.send(Ok(sender));
-    event_loop.run(&mut handler);
+}
+
+enum MioServerMode<H: async::Handler> {
+    //The event loop runs in a background thread and we talk to it through the command channel.
+    Threaded {
+        thread: thread::JoinHandle<H>,
+        sender: mpsc::Sender<MioHandlerCommand<H>>,
+        waker: Arc<mio::Waker>,
+    },
+    //The event loop only runs when the owner of the server polls it.
+    Polled {
+        event_loop: MioEventLoop<H>,
+    },
+    //Left behind by shutdown, so that drop has nothing to do.
+    Stopped,
 }
 
 pub struct MioServer<H: async::Handler> {
-    thread: thread::JoinHandle<()>,
-    sender: mio::Sender<MioHandlerCommand<H>>,
+    mode: MioServerMode<H>,
 }
 
 impl<H: async::Handler+Send+'static> MioServer<H> {
     pub fn new(address: net::SocketAddr, handler: H)->Result<MioServer<H>, io::Error> {
-        let (sender, receiver) = mpsc::channel();
-        let join_handle = thread::spawn(move || mio_server_thread(address, handler, sender));
-        let message_sender = try!(receiver.recv().unwrap());
+        let (event_loop, sender, waker) = try!(MioEventLoop::new(address, handler));
+        let join_handle = thread::spawn(move || event_loop.run());
         Ok(MioServer {
-            thread: join_handle,
-            sender: message_sender,
+            mode: MioServerMode::Threaded {
+                thread: join_handle,
+                sender: sender,
+                waker: waker,
+            }
         })
     }
+}
+
+impl<H: async::Handler> MioServer<H> {
+    /**Make a server which doesn't spawn a thread.
 
-    pub fn with<F: Fn(&mut MioHandler<H>)+Send+'static>(&mut self, func: F) {
-        let command = MioHandlerCommand::DoCall(Box::new(func));
-        self.sender.send(command);
+Nothing happens until `poll` is called, and all handler callbacks run inside `poll`.*/
+    pub fn new_polled(address: net::SocketAddr, handler: H)->Result<MioServer<H>, io::Error> {
+        //Commands run immediately in this mode, so the channel goes unused.
+        let (event_loop, _, _) = try!(MioEventLoop::new(address, handler));
+        Ok(MioServer {
+            mode: MioServerMode::Polled {
+                event_loop: event_loop,
+            }
+        })
+    }
+
+    pub fn is_polled(&self)->bool {
+        if let MioServerMode::Polled{..} = self.mode {true}
+        else {false}
+    }
+
+    /**Run one step of the event loop, waiting at most `timeout_ms` for something to happen.
+
+Does nothing if the server is threaded.  Fails with `ServerStopped` once a fatal error has been reported to the handler.*/
+    pub fn poll(&mut self, timeout_ms: u64)->Result<(), async::Error> {
+        if let MioServerMode::Polled{ref mut event_loop} = self.mode {
+            event_loop.run_once(Some(time::Duration::from_millis(timeout_ms)));
+            if event_loop.is_finished() {return Err(async::Error::ServerStopped);}
+        }
+        Ok(())
+    }
+
+    /**Run a function on the event loop.
+
+In the polled mode, we run the function immediately.  Fails with `ServerStopped` if the event loop is gone, in which case the function is dropped.*/
+    pub fn with<F: FnOnce(&mut MioHandler<H>)+Send+'static>(&mut self, func: F)->Result<(), async::Error> {
+        match self.mode {
+            MioServerMode::Threaded{ref sender, ref waker, ..} => {
+                let command = MioHandlerCommand::DoCall(Box::new(func));
+                //This only fails if the thread stopped because of a fatal error.
+                try!(sender.send(command).map_err(|_| async::Error::ServerStopped));
+                waker.wake().map_err(async::Error::IoError)
+            },
+            MioServerMode::Polled{ref mut event_loop} => {
+                if event_loop.is_finished() {return Err(async::Error::ServerStopped);}
+                func(event_loop.handler());
+                Ok(())
+            },
+            MioServerMode::Stopped => Err(async::Error::ServerStopped),
+        }
+    }
+
+    /**Stop the event loop and return the handler.
+
+If `close_timeout` is provided, connections are closed gracefully first, waiting at most that long.
+If the background thread panicked, the panic is propagated.*/
+    pub fn shutdown(mut self, close_timeout: Option<time::Duration>)->H {
+        match self.stop(close_timeout) {
+            Some(Ok(handler)) => handler,
+            Some(Err(what)) => panic::resume_unwind(what),
+            None => unreachable!(),
+        }
+    }
+
+    //Returns None if we already stopped.
+    fn stop(&mut self, close_timeout: Option<time::Duration>)->Option<thread::Result<H>> {
+        match mem::replace(&mut self.mode, MioServerMode::Stopped) {
+            MioServerMode::Threaded{thread, sender, waker} => {
+                //If these fail, the thread is already gone and join tells us why.
+                let _ = sender.send(MioHandlerCommand::Shutdown(close_timeout));
+                let _ = waker.wake();
+                Some(thread.join())
+            },
+            MioServerMode::Polled{mut event_loop} => {
+                event_loop.begin_shutdown(close_timeout);
+                Some(Ok(event_loop.run()))
+            },
+            MioServerMode::Stopped => None,
+        }
+    }
+}
+
+impl<H: async::Handler> Drop for MioServer<H> {
+    //Stops without closing connections gracefully and drops the handler.
+    fn drop(&mut self) {
+        let _ = self.stop(None);
+    }
+}
+
+#[cfg(test)]
+fn recording_event_loop()->(MioEventLoop<::async_tests::RecordingHandler>, mpsc::Receiver<::async_tests::Event>) {
+    let (sender, events) = mpsc::channel();
+    let (event_loop, _, _) = MioEventLoop::new("127.0.0.1:0".parse().unwrap(), ::async_tests::RecordingHandler::new(sender, false)).unwrap();
+    (event_loop, events)
+}
+
+#[test]
+fn test_socket_recovery() {
+    use async_tests::Event;
+    let (mut server, server_events) = recording_event_loop();
+    let address = server.address;
+    server.recover_socket(io::Error::new(io::ErrorKind::Other, "broken"));
+    assert_eq!(server.handler.service.socket.local_addr().unwrap(), address);
+    //The new socket is registered, so peers can still reach us.
+    let (mut client, client_events) = recording_event_loop();
+    client.handler().connect(address, 1, None);
+    let deadline = time::Instant::now()+time::Duration::from_secs(5);
+    while client_events.try_iter().any(|e| if let Event::Connected(_) = e {true} else {false}) == false {
+        assert!(time::Instant::now() < deadline);
+        client.run_once(Some(time::Duration::from_millis(1)));
+        server.run_once(Some(time::Duration::from_millis(1)));
+    }
+    assert!(server.is_finished() == false);
+    assert!(server_events.try_iter().all(|e| if let Event::ServerError(_) = e {false} else {true}));
+}
+
+#[test]
+fn test_fatal_error() {
+    use async_tests::Event;
+    let (mut event_loop, events) = recording_event_loop();
+    event_loop.fail(io::Error::new(io::ErrorKind::Other, "broken"));
+    assert!(event_loop.is_finished());
+    assert!(match events.try_recv() {Ok(Event::ServerError(e)) => e.contains("broken"), _ => false});
+    let mut server = MioServer{mode: MioServerMode::Polled{event_loop: event_loop}};
+    assert!(match server.poll(0) {Err(async::Error::ServerStopped) => true, _ => false});
+    assert!(match server.with(|_| {}) {Err(async::Error::ServerStopped) => true, _ => false});
+}
+
+#[test]
+fn test_commands_after_thread_stops() {
+    let (sender, _events) = mpsc::channel();
+    let mut server = MioServer::new("127.0.0.1:0".parse().unwrap(), ::async_tests::RecordingHandler::new(sender, false)).unwrap();
+    server.with(|_| panic!("A deliberate panic in the event loop")).unwrap();
+    //Once the thread is gone, commands fail instead of disappearing.
+    let deadline = time::Instant::now()+time::Duration::from_secs(5);
+    loop {
+        match server.with(|_| {}) {
+            Err(async::Error::ServerStopped) => break,
+            _ => assert!(time::Instant::now() < deadline),
+        }
+        thread::sleep(time::Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_encrypted_peer_moves_without_heartbeat() {
+    use async_tests::Event;
+    let (mut server, server_events) = recording_event_loop();
+    let (mut client, client_events) = recording_event_loop();
+    let server_address = server.address;
+    client.handler().connect(server_address, 1, None);
+    let deadline = time::Instant::now()+time::Duration::from_secs(5);
+    let mut id = None;
+    while id.is_none() {
+        assert!(time::Instant::now() < deadline);
+        client.run_once(Some(time::Duration::from_millis(1)));
+        server.run_once(Some(time::Duration::from_millis(1)));
+        id = client_events.try_iter().filter_map(|e| if let Event::Connected(i) = e {Some(i)} else {None}).next();
+    }
+    let id = id.unwrap();
+    assert!(server.handler.connections[&id].session.is_some());
+    //Pretend a NAT gave the client a new port.
+    let mut socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let new_address = socket.local_addr().unwrap();
+    client.poll.registry().register(&mut socket, SOCKET_TOKEN, mio::Interest::READABLE).unwrap();
+    let mut old = mem::replace(&mut client.handler.service.socket, socket);
+    client.poll.registry().deregister(&mut old).unwrap();
+    drop(old);
+    //An unreliable message is never resent, so it only arrives if the server uses packets from the new address straight away.
+    client.handler().send_message(id, 2, &packets::MessageHeader::default(), b"moved", false, None).unwrap();
+    let mut received = false;
+    while received == false || server.handler.connections[&id].address != new_address {
+        assert!(time::Instant::now() < deadline);
+        client.run_once(Some(time::Duration::from_millis(1)));
+        server.run_once(Some(time::Duration::from_millis(1)));
+        received = received || server_events.try_iter().any(|e| e == Event::Message(2, b"moved".to_vec()));
     }
+    assert_eq!(server.handler.addresses.get(&new_address), Some(&id));
 }
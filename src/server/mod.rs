@@ -6,9 +6,32 @@ mod connection;
 mod data_packet_handler;
 mod ack_manager;
 mod roundtrip_estimator;
+mod timer_wheel;
+mod encryption;
+mod cookies;
+mod rate_limiter;
+mod pacer;
+mod scheduler;
+mod compression;
+mod assets;
+mod channel_modes;
+mod receipts;
+mod message_headers;
 
 pub use self::mio_server::*;
 pub use self::connection::*;
 pub use self::roundtrip_estimator::*;
 pub use self::ack_manager::*;
+pub use self::data_packet_handler::*;
+pub use self::timer_wheel::*;
+pub use self::encryption::*;
+pub use self::cookies::*;
+pub use self::rate_limiter::*;
+pub use self::pacer::*;
+pub use self::scheduler::*;
+pub use self::compression::*;
+pub use self::assets::*;
+pub use self::channel_modes::*;
+pub use self::receipts::*;
+pub use self::message_headers::*;
 
@@ -0,0 +1,56 @@
+use std::time;
+
+//The pacer can save up this long's worth of sending, so that the 10 MS tick doesn't slow things down.
+const MAX_BURST_MS: f64 = 10.0;
+//But it can always send at least this much at once, so that slow rates still send whole packets.
+const MIN_BURST: f64 = 2000.0;
+
+/**Spreads data packets out over time instead of sending them all at once.*/
+#[derive(Debug)]
+pub struct Pacer {
+    budget: f64,
+    last_update: time::Instant,
+}
+
+impl Pacer {
+    pub fn new(now: time::Instant)->Pacer {
+        Pacer{budget: MIN_BURST, last_update: now}
+    }
+
+    /**Add what's been earned since the last call at `rate` bytes a second.*/
+    pub fn refill(&mut self, rate: f64, now: time::Instant) {
+        if now <= self.last_update {return;}
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed_secs = elapsed.as_secs() as f64+elapsed.subsec_nanos() as f64/1e9;
+        let max_budget = (rate*MAX_BURST_MS/1000.0).max(MIN_BURST);
+        self.budget = (self.budget+rate*elapsed_secs).min(max_budget);
+        self.last_update = now;
+    }
+
+    /**Returns true if `bytes` may be sent now.
+
+We allow going into debt by one packet, so that a packet larger than the budget can ever go.*/
+    pub fn take(&mut self, bytes: usize)->bool {
+        if self.budget <= 0.0 {return false;}
+        self.budget -= bytes as f64;
+        true
+    }
+}
+
+#[test]
+fn test_pacer() {
+    let start = time::Instant::now();
+    let mut pacer = Pacer::new(start);
+    assert!(pacer.take(1000));
+    assert!(pacer.take(1000));
+    assert!(pacer.take(1000) == false);
+    //At 100 KB a second, 10 MS is 1000 bytes.
+    pacer.refill(100000.0, start+time::Duration::from_millis(10));
+    assert!(pacer.take(1000));
+    assert!(pacer.take(1000) == false);
+    //Waiting longer doesn't save up more than the minimum burst.
+    pacer.refill(100000.0, start+time::Duration::from_secs(1));
+    assert!(pacer.take(1000));
+    assert!(pacer.take(1000));
+    assert!(pacer.take(1000) == false);
+}
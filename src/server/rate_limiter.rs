@@ -0,0 +1,173 @@
+use congestion::MAX_SEGMENT_SIZE;
+use std::cmp;
+use std::collections;
+use std::net;
+use std::time;
+
+//Past this many addresses, new ones are refused until the table is pruned.
+//Otherwise, spoofing lots of addresses would let someone use as much memory as they like.
+const MAX_TRACKED_ADDRESSES: usize = 65536;
+
+/**A token bucket.
+
+Holds up to `burst` tokens and gains `per_second` tokens a second.  Each packet takes one.*/
+#[derive(Debug, Copy, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_update: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: u32, now: time::Instant)->TokenBucket {
+        TokenBucket{tokens: burst as f64, last_update: now}
+    }
+
+    fn refill(&mut self, per_second: u32, burst: u32, now: time::Instant) {
+        if now <= self.last_update {return;}
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed_secs = elapsed.as_secs() as f64+elapsed.subsec_nanos() as f64/1e9;
+        self.tokens = (self.tokens+elapsed_secs*per_second as f64).min(burst as f64);
+        self.last_update = now;
+    }
+
+    //Returns false if the bucket is empty.
+    pub fn take(&mut self, per_second: u32, burst: u32, now: time::Instant)->bool {
+        self.take_many(1, per_second, burst, now)
+    }
+
+    //Takes `count` tokens, or none at all if there aren't that many.
+    pub fn take_many(&mut self, count: u32, per_second: u32, burst: u32, now: time::Instant)->bool {
+        if self.has(count, per_second, burst, now) == false {return false;}
+        self.tokens -= count as f64;
+        true
+    }
+
+    pub fn has(&mut self, count: u32, per_second: u32, burst: u32, now: time::Instant)->bool {
+        self.refill(per_second, burst, now);
+        self.tokens >= count as f64
+    }
+
+    //Takes `count` tokens even if that leaves the bucket in debt.
+    pub fn force_take(&mut self, count: u32, per_second: u32, burst: u32, now: time::Instant) {
+        self.refill(per_second, burst, now);
+        self.tokens -= count as f64;
+    }
+
+    pub fn is_full(&mut self, per_second: u32, burst: u32, now: time::Instant)->bool {
+        self.refill(per_second, burst, now);
+        self.tokens >= burst as f64
+    }
+}
+
+/**Limits how often each IP may do something.
+
+Ports are ignored, since changing them costs an attacker nothing.*/
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: collections::HashMap<net::IpAddr, TokenBucket>,
+    per_second: u32,
+    burst: u32,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32)->RateLimiter {
+        RateLimiter{buckets: collections::HashMap::default(), per_second: per_second, burst: burst}
+    }
+
+    pub fn configure(&mut self, per_second: u32, burst: u32) {
+        self.per_second = per_second;
+        self.burst = burst;
+    }
+
+    /**Returns true if `address` may go ahead.*/
+    pub fn allow(&mut self, address: net::IpAddr, now: time::Instant)->bool {
+        let (per_second, burst) = (self.per_second, self.burst);
+        if self.buckets.len() >= MAX_TRACKED_ADDRESSES && self.buckets.contains_key(&address) == false {return false;}
+        self.buckets.entry(address).or_insert_with(|| TokenBucket::new(burst, now)).take(per_second, burst, now)
+    }
+
+    /**Forget addresses whose buckets have refilled, since a new bucket would be the same.*/
+    pub fn prune(&mut self, now: time::Instant) {
+        let (per_second, burst) = (self.per_second, self.burst);
+        self.buckets.retain(|_, b| b.is_full(per_second, burst, now) == false);
+    }
+}
+
+/**Limits how many bytes a second may be sent.
+
+The burst is never less than one packet, or some packets could never go.*/
+#[derive(Debug, Copy, Clone)]
+pub struct BandwidthLimit {
+    bucket: TokenBucket,
+    bytes_per_second: u32,
+    burst: u32,
+}
+
+impl BandwidthLimit {
+    pub fn new(bytes_per_second: u32, burst: u32, now: time::Instant)->BandwidthLimit {
+        let burst = cmp::max(burst, MAX_SEGMENT_SIZE as u32);
+        BandwidthLimit{bucket: TokenBucket::new(burst, now), bytes_per_second: bytes_per_second, burst: burst}
+    }
+
+    /**Returns true if `bytes` may be sent now.  Doesn't use up anything; call `consume` once they're sent.*/
+    pub fn allows(&mut self, bytes: usize, now: time::Instant)->bool {
+        self.bucket.has(bytes as u32, self.bytes_per_second, self.burst, now)
+    }
+
+    /**Returns true if `bytes` can go out behind nothing else, without running into debt.
+
+Anything past the burst has to wait for the bucket to refill however full it is, so only up to the burst is checked.  A full bucket allows anything.*/
+    pub fn allows_backlog(&mut self, bytes: usize, now: time::Instant)->bool {
+        let bytes = cmp::min(bytes, self.burst as usize);
+        self.allows(bytes, now)
+    }
+
+    /**Count `bytes` against the limit.
+
+This always succeeds, since it's used for packets which have to go anyway such as resends.  Going over delays what comes after.*/
+    pub fn consume(&mut self, bytes: usize, now: time::Instant) {
+        self.bucket.force_take(bytes as u32, self.bytes_per_second, self.burst, now);
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let mut limiter = RateLimiter::new(2, 3);
+    let start = time::Instant::now();
+    let first: net::IpAddr = "127.0.0.1".parse().unwrap();
+    let second: net::IpAddr = "127.0.0.2".parse().unwrap();
+    for _ in 0..3 {assert!(limiter.allow(first, start));}
+    assert!(limiter.allow(first, start) == false);
+    //Other addresses have their own buckets.
+    assert!(limiter.allow(second, start));
+    //Half a second is one more token at 2 per second.
+    let later = start+time::Duration::from_millis(500);
+    assert!(limiter.allow(first, later));
+    assert!(limiter.allow(first, later) == false);
+    //Once a bucket is full again, it can be forgotten.
+    limiter.prune(later);
+    assert_eq!(limiter.buckets.len(), 1);
+    limiter.prune(start+time::Duration::from_secs(10));
+    assert_eq!(limiter.buckets.len(), 0);
+}
+
+#[test]
+fn test_bandwidth_limit() {
+    let start = time::Instant::now();
+    //Too small a burst is raised to one packet.
+    let mut limit = BandwidthLimit::new(10000, 10, start);
+    assert!(limit.allows(MAX_SEGMENT_SIZE, start));
+    assert!(limit.allows(MAX_SEGMENT_SIZE+1, start) == false);
+    limit.consume(MAX_SEGMENT_SIZE, start);
+    assert!(limit.allows(1, start) == false);
+    //Resends can put it into debt, which takes longer to pay off.
+    limit.consume(MAX_SEGMENT_SIZE, start);
+    assert!(limit.allows(500, start+time::Duration::from_millis(100)) == false);
+    assert!(limit.allows(500, start+time::Duration::from_millis(150)));
+    //More than the burst is fine for a full bucket, but not once anything has gone.
+    let mut limit = BandwidthLimit::new(10000, 2000, start);
+    assert!(limit.allows_backlog(5000, start));
+    limit.consume(1, start);
+    assert!(limit.allows_backlog(5000, start) == false);
+    assert!(limit.allows_backlog(1999, start));
+}
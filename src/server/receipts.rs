@@ -0,0 +1,154 @@
+/*! Receipts for unreliable packets.
+
+On channels configured with `Server::configure_unreliable_receipts`, unreliable packets have their receipt flag set.
+The receiver remembers which flagged packets arrived as the newest sequence number and a bitfield of the 64 before it, and sends this to the sender in a receipt packet every tick.
+Since every receipt covers the packets before it, losing one costs nothing unless the next one is too late to cover them.*/
+use async;
+use packets;
+use std::collections;
+use std::mem;
+use std::time;
+
+//How many packets before the newest one a receipt covers.
+const RECEIPT_BITS: u64 = 64;
+
+/**The receiving half: which flagged packets arrived on one channel.*/
+#[derive(Debug, Default)]
+pub struct ReceiptWindow {
+    newest: Option<u64>,
+    received: u64,
+    //Set when something arrived since the last receipt.
+    changed: bool,
+}
+
+impl ReceiptWindow {
+    pub fn new()->ReceiptWindow {
+        ReceiptWindow::default()
+    }
+
+    pub fn record(&mut self, sequence_number: u64) {
+        match self.newest {
+            Some(n) if sequence_number > n => {
+                let distance = sequence_number-n;
+                self.received = if distance > RECEIPT_BITS {0} else {self.received.checked_shl(distance as u32).unwrap_or(0) | 1 << (distance-1)};
+                self.newest = Some(sequence_number);
+            },
+            //Too old to be reported is the same as lost.
+            Some(n) if sequence_number < n => {
+                let distance = n-sequence_number;
+                if distance > RECEIPT_BITS {return;}
+                self.received |= 1 << (distance-1);
+            },
+            Some(_) => return,
+            None => self.newest = Some(sequence_number),
+        }
+        self.changed = true;
+    }
+
+    ///The receipt packet to send, if anything arrived since the last one.
+    pub fn take_receipt(&mut self, channel: i16)->Option<packets::Packet> {
+        if self.changed == false {return None;}
+        self.changed = false;
+        self.newest.map(|n| packets::Packet::Receipt{chan: channel, sequence_number: n, received: self.received})
+    }
+}
+
+#[derive(Debug)]
+struct PendingFrame {
+    //Packets which haven't been reported yet.
+    missing: collections::BTreeSet<u64>,
+    handle: async::MessageHandle,
+    sent: time::Instant,
+}
+
+/**The sending half: unreliable frames whose fate hasn't been reported yet.*/
+#[derive(Debug, Default)]
+pub struct ReceiptTracker {
+    //Keyed by channel and the frame's first sequence number.
+    frames: collections::BTreeMap<(i16, u64), PendingFrame>,
+}
+
+impl ReceiptTracker {
+    pub fn new()->ReceiptTracker {
+        ReceiptTracker::default()
+    }
+
+    pub fn track(&mut self, channel: i16, first: u64, last: u64, handle: async::MessageHandle, now: time::Instant) {
+        self.frames.insert((channel, first), PendingFrame{missing: (first..last+1).collect(), handle: handle, sent: now});
+    }
+
+    pub fn is_empty(&self)->bool {
+        self.frames.is_empty()
+    }
+
+    /**Apply a receipt from the peer.
+
+Returns the frames which are now known to have arrived, with true, and those which can't be reported anymore because the receipts have moved past them, with false.*/
+    pub fn handle_receipt(&mut self, channel: i16, sequence_number: u64, received: u64)->Vec<(async::MessageHandle, bool)> {
+        let mut results = Vec::default();
+        let mut finished = Vec::default();
+        for (k, f) in self.frames.range_mut((channel, 0)..=(channel, sequence_number)) {
+            let arrived: Vec<u64> = f.missing.range(..=sequence_number).cloned().filter(|&sn| {
+                let distance = sequence_number-sn;
+                distance == 0 || (distance <= RECEIPT_BITS && received & (1 << (distance-1)) > 0)
+            }).collect();
+            for sn in arrived {f.missing.remove(&sn);}
+            match f.missing.iter().next_back() {
+                None => results.push((f.handle, true)),
+                Some(&newest) if newest < sequence_number && sequence_number-newest > RECEIPT_BITS => results.push((f.handle, false)),
+                Some(_) => continue,
+            }
+            finished.push(*k);
+        }
+        for k in finished {self.frames.remove(&k);}
+        results
+    }
+
+    ///Give up on frames sent more than `timeout` ago.
+    pub fn expire(&mut self, now: time::Instant, timeout: time::Duration)->Vec<(i16, async::MessageHandle)> {
+        let expired: Vec<(i16, u64)> = self.frames.iter().filter(|f| now >= f.1.sent+timeout).map(|f| *f.0).collect();
+        expired.into_iter().map(|k| (k.0, self.frames.remove(&k).unwrap().handle)).collect()
+    }
+
+    ///Give up on every frame, i.e. because the connection closed.
+    pub fn drain(&mut self)->Vec<(i16, async::MessageHandle)> {
+        let frames = mem::replace(&mut self.frames, collections::BTreeMap::default());
+        frames.into_iter().map(|(k, f)| (k.0, f.handle)).collect()
+    }
+}
+
+#[test]
+fn test_receipt_window() {
+    let mut window = ReceiptWindow::new();
+    assert_eq!(window.take_receipt(0), None);
+    for &sn in [5u64, 3, 7, 4, 7].iter() {window.record(sn);}
+    assert_eq!(window.take_receipt(0), Some(packets::Packet::Receipt{chan: 0, sequence_number: 7, received: 0b1110}));
+    assert_eq!(window.take_receipt(0), None);
+    //Sliding by exactly the window keeps the old newest packet in the last bit.
+    window.record(71);
+    assert_eq!(window.take_receipt(0), Some(packets::Packet::Receipt{chan: 0, sequence_number: 71, received: 1 << 63}));
+    window.record(500);
+    assert_eq!(window.take_receipt(0), Some(packets::Packet::Receipt{chan: 0, sequence_number: 500, received: 0}));
+    //Too old to report.
+    window.record(400);
+    assert_eq!(window.take_receipt(0), None);
+}
+
+#[test]
+fn test_receipt_tracker() {
+    let now = time::Instant::now();
+    let mut tracker = ReceiptTracker::new();
+    tracker.track(0, 0, 1, async::MessageHandle(1), now);
+    tracker.track(0, 2, 2, async::MessageHandle(2), now);
+    tracker.track(0, 3, 3, async::MessageHandle(3), now);
+    tracker.track(1, 0, 0, async::MessageHandle(4), now);
+    //Packet 0 and 3 arrived.
+    assert_eq!(tracker.handle_receipt(0, 3, 0b100), vec![(async::MessageHandle(3), true)]);
+    //A later receipt for packet 1 finishes the first frame.
+    assert_eq!(tracker.handle_receipt(0, 3, 0b110), vec![(async::MessageHandle(1), true)]);
+    //Packet 2 never arrived, and the receipts moved past it.
+    assert_eq!(tracker.handle_receipt(0, 70, 0), vec![(async::MessageHandle(2), false)]);
+    assert_eq!(tracker.expire(now, time::Duration::from_secs(1)), vec![]);
+    assert_eq!(tracker.expire(now+time::Duration::from_secs(1), time::Duration::from_secs(1)), vec![(1, async::MessageHandle(4))]);
+    assert!(tracker.is_empty());
+}
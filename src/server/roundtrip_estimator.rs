@@ -14,6 +14,8 @@ use packets::{Packet};
     estimation: Vec<u32>,
     required_echoes: usize,
     last_estimate: Option<u32>,
+    //Updated on every echo, for congestion control.
+    smoothed: Option<time::Duration>,
 }
 
 impl RoundtripEstimator {
@@ -24,6 +26,7 @@ impl RoundtripEstimator {
             estimation: Vec::default(),
             required_echoes: required_echoes,
             last_estimate: None,
+            smoothed: None,
         }
     }
 
@@ -31,8 +34,14 @@ impl RoundtripEstimator {
         self.last_estimate
     }
 
+    /**An exponentially weighted average of every roundtrip, as in TCP.*/
+    pub fn get_smoothed_estimate(&self)->Option<time::Duration> {
+        self.smoothed
+    }
+
     //Called once a second by established connections.
-    pub fn tick<H: async::Handler>(&mut self, address: net::SocketAddr, endpoint_id: uuid::Uuid, service: &mut MioServiceProvider<H>) {
+    //Echoes are sent with send, so that the connection can encrypt them.
+    pub fn tick<F: FnMut(Packet)>(&mut self, endpoint_id: uuid::Uuid, mut send: F) {
         let now = time::Instant::now();
         //Kill all echoes older than 5 seconds.
         let mut removing = Vec::with_capacity(self.expected_echoes.len());
@@ -51,22 +60,30 @@ impl RoundtripEstimator {
             for i in 0..needed_echoes {
                 let uuid = uuid::Uuid::new_v4();
                 self.expected_echoes.insert(uuid, now);
-                service.send(Packet::Echo{endpoint: endpoint_id, uuid: uuid}, address);
+                send(Packet::Echo{endpoint: endpoint_id, uuid: uuid});
             }
         }
     }
 
-    pub fn handle_echo<H: async::Handler>(&mut self, connection_id: uuid::Uuid, echo_id: uuid::Uuid, service: &mut MioServiceProvider<H>) {
+    //Returns the roundtrip, if this was one of our echoes.
+    pub fn handle_echo<H: async::Handler>(&mut self, connection_id: uuid::Uuid, echo_id: uuid::Uuid, service: &mut MioServiceProvider<H>)->Option<time::Duration> {
+        let mut sample = None;
         if let Some(&instant) = self.expected_echoes.get(&echo_id) {
             let dur = time::Instant::now().duration_since(instant);
             let dur_ms: u64 = dur.as_secs()*1000+dur.subsec_nanos() as u64/1000000u64;
             self.estimation.push(dur_ms as u32);
             self.expected_echoes.remove(&echo_id);
+            self.smoothed = Some(match self.smoothed {
+                Some(s) => (s*7+dur)/8,
+                None => dur,
+            });
+            sample = Some(dur);
         }
         if self.estimation.len() >= self.required_echoes {
             let average: u32 = self.estimation.iter().fold(0, ops::Add::add)/self.estimation.len() as u32;
             self.estimation.clear();
             service.handler.roundtrip_estimate(connection_id, average);
         }
+        sample
     }
 }
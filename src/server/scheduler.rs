@@ -0,0 +1,193 @@
+use packets;
+use super::packet_size;
+#[cfg(test)]
+use super::test_packet;
+use std::collections;
+
+///The priority of channels which haven't been given one.
+pub const DEFAULT_CHANNEL_PRIORITY: u8 = 16;
+
+#[derive(Debug, Default)]
+struct ChannelQueue {
+    packets: collections::VecDeque<packets::Packet>,
+    //Where this channel is in virtual time.  The channel furthest behind goes next.
+    start: f64,
+}
+
+/**Decides which channel sends next.
+
+This is start-time fair queueing: each channel gets a share of what's sent proportional to its priority, so high priority channels go first but low priority channels are never starved.
+A channel which was idle rejoins at the current virtual time, so it can't save up for a burst that would lock everyone else out.
+Packets on a channel always go in the order they were queued.*/
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    channels: collections::BTreeMap<i16, ChannelQueue>,
+    priorities: collections::HashMap<i16, u8>,
+    virtual_time: f64,
+    queued_bytes: usize,
+    //Added to the size of every packet, i.e. for encryption.
+    packet_overhead: usize,
+}
+
+impl Scheduler {
+    pub fn new()->Scheduler {
+        Scheduler::default()
+    }
+
+    pub fn with_priority(channel: i16, priority: u8)->Scheduler {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_priority(channel, priority);
+        scheduler
+    }
+
+    /**Set the priority of a channel.  0 is treated as 1.*/
+    pub fn set_priority(&mut self, channel: i16, priority: u8) {
+        self.priorities.insert(channel, priority);
+    }
+
+    /**Count `overhead` more bytes for each packet.
+
+Only call this while nothing is queued, or the queued bytes will be off.*/
+    pub fn set_packet_overhead(&mut self, overhead: usize) {
+        self.packet_overhead = overhead;
+    }
+
+    fn size(&self, packet: &packets::Packet)->usize {
+        packet_size(packet)+self.packet_overhead
+    }
+
+    fn weight(&self, channel: i16)->f64 {
+        let priority = self.priorities.get(&channel).cloned().unwrap_or(DEFAULT_CHANNEL_PRIORITY);
+        if priority == 0 {1.0} else {priority as f64}
+    }
+
+    pub fn push(&mut self, channel: i16, packet: packets::Packet) {
+        self.queued_bytes += self.size(&packet);
+        let virtual_time = self.virtual_time;
+        let queue = self.channels.entry(channel).or_insert_with(ChannelQueue::default);
+        if queue.packets.is_empty() && queue.start < virtual_time {queue.start = virtual_time;}
+        queue.packets.push_back(packet);
+    }
+
+    /**The channel which should send next, ignoring those in `skip`.*/
+    pub fn next(&self, skip: &[i16])->Option<i16> {
+        let mut best: Option<(i16, f64)> = None;
+        for (&channel, queue) in self.channels.iter() {
+            if queue.packets.is_empty() || skip.contains(&channel) {continue;}
+            if best.map_or(true, |(_, start)| queue.start < start) {best = Some((channel, queue.start));}
+        }
+        best.map(|(channel, _)| channel)
+    }
+
+    pub fn peek(&self, channel: i16)->Option<&packets::Packet> {
+        self.channels.get(&channel).and_then(|q| q.packets.front())
+    }
+
+    /**Take the next packet from `channel`, charging the channel for it.*/
+    pub fn pop(&mut self, channel: i16)->Option<packets::Packet> {
+        let weight = self.weight(channel);
+        let overhead = self.packet_overhead;
+        let queue = match self.channels.get_mut(&channel) {
+            Some(q) => q,
+            None => return None,
+        };
+        let packet = match queue.packets.pop_front() {
+            Some(p) => p,
+            None => return None,
+        };
+        let size = packet_size(&packet)+overhead;
+        self.virtual_time = queue.start;
+        queue.start += size as f64/weight;
+        self.queued_bytes -= size;
+        Some(packet)
+    }
+
+    ///Remove the packets queued on `channel` with sequence numbers from `first` to `last`.
+    pub fn remove_range(&mut self, channel: i16, first: u64, last: u64) {
+        let mut removed = 0;
+        let overhead = self.packet_overhead;
+        if let Some(queue) = self.channels.get_mut(&channel) {
+            queue.packets.retain(|p| match *p {
+                packets::Packet::Data{ref packet, ..} if packet.sequence_number() >= first && packet.sequence_number() <= last => {
+                    removed += packet_size(p)+overhead;
+                    false
+                },
+                _ => true,
+            });
+        }
+        self.queued_bytes -= removed;
+    }
+
+    ///The sequence number of the first packet queued on `channel`.
+    pub fn first_sequence_number_on(&self, channel: i16)->Option<u64> {
+        match self.peek(channel) {
+            Some(&packets::Packet::Data{ref packet, ..}) => Some(packet.sequence_number()),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self)->bool {
+        self.channels.values().all(|q| q.packets.is_empty())
+    }
+
+    pub fn queued_bytes_on(&self, channel: i16)->usize {
+        self.channels.get(&channel).map_or(0, |q| q.packets.iter().map(|p| self.size(p)).sum())
+    }
+
+    //The total size of the queued packets.
+    pub fn queued_bytes(&self)->usize {
+        self.queued_bytes
+    }
+}
+
+#[test]
+fn test_scheduler_priorities() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_priority(0, 3);
+    scheduler.set_priority(1, 1);
+    for sn in 0..20 {
+        scheduler.push(1, test_packet(1, sn));
+        scheduler.push(0, test_packet(0, sn));
+    }
+    //Channel 0 gets three packets for each of channel 1's, so channel 1 still makes progress.
+    let mut order = Vec::default();
+    for _ in 0..8 {
+        let chan = scheduler.next(&[]).unwrap();
+        order.push(chan);
+        scheduler.pop(chan);
+    }
+    assert_eq!(order, vec![0, 1, 0, 0, 0, 1, 0, 0]);
+    //Channels stay in order, and skipped channels are passed over.
+    assert_eq!(scheduler.next(&[0]), Some(1));
+    match scheduler.pop(1) {
+        Some(packets::Packet::Data{packet, ..}) => assert_eq!(packet.sequence_number(), 2),
+        _ => panic!("Expected a data packet"),
+    }
+}
+
+#[test]
+fn test_scheduler_idle_channels() {
+    let mut scheduler = Scheduler::new();
+    for sn in 0..10 {scheduler.push(0, test_packet(0, sn));}
+    for _ in 0..10 {scheduler.pop(0);}
+    assert!(scheduler.is_empty());
+    assert_eq!(scheduler.queued_bytes(), 0);
+    //Channel 1 was idle while channel 0 sent, but doesn't get to catch up all at once.
+    scheduler.push(0, test_packet(0, 10));
+    scheduler.push(0, test_packet(0, 11));
+    scheduler.push(1, test_packet(1, 0));
+    scheduler.push(1, test_packet(1, 1));
+    let mut order = Vec::default();
+    while let Some(chan) = scheduler.next(&[]) {
+        order.push(chan);
+        scheduler.pop(chan);
+    }
+    assert_eq!(order, vec![1, 0, 1, 0]);
+    //Encryption makes every packet bigger.
+    scheduler.set_packet_overhead(10);
+    scheduler.push(2, test_packet(2, 0));
+    assert_eq!(scheduler.queued_bytes(), packet_size(&test_packet(2, 0))+10);
+    assert_eq!(scheduler.queued_bytes_on(2), scheduler.queued_bytes());
+    scheduler.pop(2);
+    assert_eq!(scheduler.queued_bytes(), 0);
+}
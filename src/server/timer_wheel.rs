@@ -0,0 +1,108 @@
+use std::time;
+use std::mem;
+
+/**A hashed timer wheel.
+
+Time is divided into ticks of a fixed length, and each tick maps onto a slot.  Timers are stored in the slot of the tick in which they expire, along with that tick so that timers more than one revolution in the future are left alone.
+
+Fastnet only ever has a handful of timers, so the point of this is not speed; it's that scheduling and expiring are both cheap no matter how many timers get added later.*/
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    slots: Vec<Vec<(u64, T)>>,
+    tick_ms: u64,
+    start: time::Instant,
+    //The first tick which hasn't been processed yet.
+    current_tick: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick_ms: u64, slot_count: usize)->TimerWheel<T> {
+        let mut slots = Vec::with_capacity(slot_count);
+        for _ in 0..slot_count {slots.push(Vec::default());}
+        TimerWheel {
+            slots: slots,
+            tick_ms: tick_ms,
+            start: time::Instant::now(),
+            current_tick: 0,
+        }
+    }
+
+    fn tick_at(&self, instant: time::Instant)->u64 {
+        let elapsed = instant.duration_since(self.start);
+        let elapsed_ms = elapsed.as_secs()*1000+elapsed.subsec_nanos() as u64/1000000;
+        elapsed_ms/self.tick_ms
+    }
+
+    /**Schedule `item` to expire `delay_ms` after `now`.
+
+Timers are rounded up to the next tick, so they never fire early.*/
+    pub fn schedule(&mut self, now: time::Instant, delay_ms: u64, item: T) {
+        let now = self.tick_at(now);
+        let mut ticks = delay_ms/self.tick_ms;
+        if ticks*self.tick_ms < delay_ms {ticks += 1;}
+        //Something scheduled in the past or for right now goes in the next slot we process.
+        let deadline = if now+ticks < self.current_tick {self.current_tick} else {now+ticks};
+        let slot = (deadline%self.slots.len() as u64) as usize;
+        self.slots[slot].push((deadline, item));
+    }
+
+    /**How long after `now` the next timer expires, if there is one.*/
+    pub fn next_timeout(&self, now: time::Instant)->Option<time::Duration> {
+        let earliest = self.slots.iter().flat_map(|s| s.iter()).map(|i| i.0).min();
+        earliest.map(|deadline| {
+            let deadline_instant = self.start+time::Duration::from_millis(deadline*self.tick_ms);
+            if deadline_instant > now {deadline_instant-now}
+            else {time::Duration::from_millis(0)}
+        })
+    }
+
+    /**Move all timers which have expired by `now` into `destination`, in the order they expire.*/
+    pub fn expire(&mut self, now: time::Instant, destination: &mut Vec<T>) {
+        let now_tick = self.tick_at(now);
+        if now_tick < self.current_tick {return;}
+        //If we fell behind by more than a revolution, visiting every slot once is enough.
+        let slot_count = self.slots.len() as u64;
+        let first_tick = if now_tick-self.current_tick >= slot_count {now_tick+1-slot_count} else {self.current_tick};
+        for tick in first_tick..now_tick+1 {
+            let slot = &mut self.slots[(tick%slot_count) as usize];
+            if slot.iter().any(|i| i.0 <= now_tick) == false {continue;}
+            let (expired, remaining): (Vec<_>, Vec<_>) = mem::replace(slot, Vec::default()).into_iter().partition(|i| i.0 <= now_tick);
+            *slot = remaining;
+            destination.extend(expired.into_iter().map(|i| i.1));
+        }
+        self.current_tick = now_tick+1;
+    }
+}
+
+#[test]
+fn test_timer_wheel_expiry() {
+    let mut wheel = TimerWheel::new(10, 8);
+    let start = wheel.start;
+    wheel.schedule(start, 200, 1);
+    wheel.schedule(start, 30, 2);
+    assert_eq!(wheel.next_timeout(start), Some(time::Duration::from_millis(30)));
+    let mut expired = Vec::default();
+    wheel.expire(start+time::Duration::from_millis(20), &mut expired);
+    assert_eq!(expired, vec![]);
+    wheel.expire(start+time::Duration::from_millis(45), &mut expired);
+    assert_eq!(expired, vec![2]);
+    expired.clear();
+    //200 MS is more than one revolution, so this must not fire when its slot comes around the first time.
+    wheel.expire(start+time::Duration::from_millis(150), &mut expired);
+    assert_eq!(expired, vec![]);
+    wheel.expire(start+time::Duration::from_millis(210), &mut expired);
+    assert_eq!(expired, vec![1]);
+    assert_eq!(wheel.next_timeout(start), None);
+}
+
+#[test]
+fn test_timer_wheel_falling_behind() {
+    let mut wheel = TimerWheel::new(10, 4);
+    let start = wheel.start;
+    wheel.schedule(start, 10, 1);
+    wheel.schedule(start, 20, 2);
+    let mut expired = Vec::default();
+    wheel.expire(start+time::Duration::from_millis(1000), &mut expired);
+    expired.sort();
+    assert_eq!(expired, vec![1, 2]);
+}
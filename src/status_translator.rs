@@ -5,7 +5,7 @@ use std::net;
 use std::convert;
 
 pub static PROTOCOL_VERSION: &'static str = "1.0";
-pub static SUPPORTED_EXTENSIONS: &'static [&'static str] = &[];
+pub static SUPPORTED_EXTENSIONS: &'static [&'static str] = &["fastnet_encryption", "fastnet_compression", "fastnet_unordered_channels", "fastnet_message_headers"];
 
 pub fn translate(request: &packets::StatusRequest)->packets::StatusResponse {
     match *request {
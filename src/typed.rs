@@ -0,0 +1,163 @@
+/*! Typed messages, serialized with serde.
+
+This module is only available with the `serde` feature.
+
+A `TypeRegistry` gives each message type a stable id and the channel it's sent on.  Both sides must register the same types with the same ids.
+`Server::send_typed` serializes a message and puts its id in the message header, and a `TypedDispatcher` turns incoming messages back into values and calls the callback for their type.
+
+Messages are encoded with bincode, using variable-length integers.  Type ids travel in the message header, so they need a peer which supports headers.*/
+use async::{Error, Result, MessageHeader};
+use bincode::{self, Options};
+use serde::{Serialize, de::DeserializeOwned};
+use std::any;
+use std::collections;
+use uuid;
+use MAX_CHANNEL;
+
+/**Which types may be sent as typed messages, their ids, and their channels.*/
+#[derive(Debug, Clone, Default)]
+pub struct TypeRegistry {
+    //Channels, by type id.
+    by_id: collections::HashMap<u32, u16>,
+    by_type: collections::HashMap<any::TypeId, u32>,
+}
+
+fn options()->impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl TypeRegistry {
+    pub fn new()->TypeRegistry {
+        TypeRegistry::default()
+    }
+
+    /**Register `T` with the id `type_id`, to be sent on `channel`.
+
+Several types may share a channel.  Fails with `Error::DuplicateMessageType` if the id or the type is already registered.*/
+    pub fn register<T: 'static>(&mut self, type_id: u32, channel: u16)->Result<()> {
+        if channel > MAX_CHANNEL {return Err(Error::InvalidChannel);}
+        let key = any::TypeId::of::<T>();
+        if self.by_id.contains_key(&type_id) || self.by_type.contains_key(&key) {return Err(Error::DuplicateMessageType);}
+        self.by_id.insert(type_id, channel);
+        self.by_type.insert(key, type_id);
+        Ok(())
+    }
+
+    ///The id and channel of `T`, if it's registered.
+    pub fn lookup<T: 'static>(&self)->Option<(u32, u16)> {
+        self.by_type.get(&any::TypeId::of::<T>()).map(|&id| (id, self.by_id[&id]))
+    }
+
+    ///True if any type is registered to `channel`.
+    pub fn uses_channel(&self, channel: u16)->bool {
+        self.by_id.values().any(|&c| c == channel)
+    }
+
+    /**Serialize a message, returning the channel, header, and payload to send.
+
+Fails with `Error::UnregisteredType` if `T` isn't registered.*/
+    pub fn encode<T: Serialize+'static>(&self, message: &T)->Result<(u16, MessageHeader, Vec<u8>)> {
+        let (type_id, channel) = try!(self.lookup::<T>().ok_or(Error::UnregisteredType));
+        let payload = try!(options().serialize(message).map_err(|e| Error::InvalidMessage(e.to_string())));
+        Ok((channel, MessageHeader{message_type: Some(type_id), timestamp: None, rpc: None}, payload))
+    }
+
+    /**Deserialize the payload of a message which arrived on `channel` with `header`.
+
+Fails with `Error::UnknownMessageType` if the header has no type or an unregistered one, and `Error::InvalidMessage` if the type is on the wrong channel or the payload doesn't decode as `T`.*/
+    pub fn decode<T: DeserializeOwned+'static>(&self, channel: u16, header: &MessageHeader, payload: &[u8])->Result<T> {
+        let type_id = try!(header.message_type.ok_or(Error::UnknownMessageType(None)));
+        match self.lookup::<T>() {
+            Some((id, _)) if id != type_id => return Err(Error::InvalidMessage(format!("message type {} isn't {}", type_id, any::type_name::<T>()))),
+            Some((_, c)) if c != channel => return Err(Error::InvalidMessage(format!("{} arrived on channel {} instead of {}", any::type_name::<T>(), channel, c))),
+            Some(_) => {},
+            None => return Err(Error::UnknownMessageType(Some(type_id))),
+        }
+        options().deserialize(payload).map_err(|e| Error::InvalidMessage(format!("bad {}: {}", any::type_name::<T>(), e)))
+    }
+}
+
+type Callback<S> = Box<FnMut(&mut S, uuid::Uuid, u16, &MessageHeader, &[u8])->Result<()>+Send>;
+
+/**Calls typed callbacks for incoming messages.
+
+Callbacks get `&mut S` as well as the message, so that they can reach whatever the handler keeps.  Call `dispatch` from `Handler::incoming_message_with_header`.*/
+pub struct TypedDispatcher<S> {
+    registry: TypeRegistry,
+    callbacks: collections::HashMap<u32, Callback<S>>,
+}
+
+impl<S> TypedDispatcher<S> {
+    pub fn new(registry: TypeRegistry)->TypedDispatcher<S> {
+        TypedDispatcher{registry: registry, callbacks: collections::HashMap::default()}
+    }
+
+    pub fn registry(&self)->&TypeRegistry {
+        &self.registry
+    }
+
+    /**Call `callback` with the peer's id for each incoming `T`, replacing any earlier callback.
+
+Fails with `Error::UnregisteredType` if `T` isn't registered.*/
+    pub fn on<T, F>(&mut self, mut callback: F)->Result<()>
+    where T: DeserializeOwned+'static, F: FnMut(&mut S, uuid::Uuid, T)+Send+'static {
+        let (type_id, _) = try!(self.registry.lookup::<T>().ok_or(Error::UnregisteredType));
+        let registry = self.registry.clone();
+        self.callbacks.insert(type_id, Box::new(move |state, id, channel, header, payload| {
+            let message = try!(registry.decode::<T>(channel, header, payload));
+            callback(state, id, message);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /**Decode a message and call the callback for its type.
+
+Returns false without doing anything if no types are registered to `channel`, so that untyped channels can be handled as usual.
+Fails with `Error::UnknownMessageType` if there's no callback for the message's type, or whatever `TypeRegistry::decode` fails with.*/
+    pub fn dispatch(&mut self, state: &mut S, id: uuid::Uuid, channel: u16, header: &MessageHeader, payload: &[u8])->Result<bool> {
+        if self.registry.uses_channel(channel) == false {return Ok(false);}
+        let type_id = try!(header.message_type.ok_or(Error::UnknownMessageType(None)));
+        let callback = try!(self.callbacks.get_mut(&type_id).ok_or(Error::UnknownMessageType(Some(type_id))));
+        try!(callback(state, id, channel, header, payload));
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_typed_dispatch() {
+    let mut registry = TypeRegistry::new();
+    registry.register::<(u32, String)>(1, 5).unwrap();
+    registry.register::<Vec<u16>>(2, 5).unwrap();
+    registry.register::<bool>(3, 6).unwrap();
+    assert!(match registry.register::<u8>(1, 5) {Err(Error::DuplicateMessageType) => true, _ => false});
+    assert!(match registry.encode(&1u64) {Err(Error::UnregisteredType) => true, _ => false});
+    let mut dispatcher = TypedDispatcher::<Vec<String>>::new(registry.clone());
+    dispatcher.on(|log: &mut Vec<String>, _, m: (u32, String)| log.push(format!("{} {}", m.0, m.1))).unwrap();
+    dispatcher.on(|log: &mut Vec<String>, _, m: Vec<u16>| log.push(format!("{:?}", m))).unwrap();
+    let mut log = Vec::default();
+    let id = uuid::Uuid::new_v4();
+    let (channel, header, payload) = registry.encode(&(7u32, "hi".to_string())).unwrap();
+    assert_eq!((channel, header.message_type), (5, Some(1)));
+    assert!(dispatcher.dispatch(&mut log, id, channel, &header, &payload).unwrap());
+    let (channel, header, payload) = registry.encode(&vec![1u16, 300]).unwrap();
+    assert!(dispatcher.dispatch(&mut log, id, channel, &header, &payload).unwrap());
+    assert_eq!(log, vec!["7 hi".to_string(), "[1, 300]".to_string()]);
+    //Untyped channels are left alone.
+    assert_eq!(dispatcher.dispatch(&mut log, id, 0, &MessageHeader::default(), &[1]).unwrap(), false);
+    //Registered but without a callback, and not registered at all.
+    let (channel, header, payload) = registry.encode(&true).unwrap();
+    assert!(match dispatcher.dispatch(&mut log, id, channel, &header, &payload) {Err(Error::UnknownMessageType(Some(3))) => true, _ => false});
+    assert!(match dispatcher.dispatch(&mut log, id, 5, &MessageHeader{message_type: Some(9), timestamp: None, rpc: None}, &payload) {Err(Error::UnknownMessageType(Some(9))) => true, _ => false});
+    assert!(match dispatcher.dispatch(&mut log, id, 5, &MessageHeader::default(), &payload) {Err(Error::UnknownMessageType(None)) => true, _ => false});
+    //Garbage, and a type on the wrong channel.
+    assert!(match dispatcher.dispatch(&mut log, id, 5, &header_for(2), &[5, 1]) {Err(Error::InvalidMessage(_)) => true, _ => false});
+    let mut moved = TypedDispatcher::<Vec<String>>::new(registry.clone());
+    moved.on(|_: &mut Vec<String>, _, _: bool| {}).unwrap();
+    assert!(match moved.dispatch(&mut log, id, 5, &header_for(3), &[1]) {Err(Error::InvalidMessage(_)) => true, _ => false});
+}
+
+#[cfg(test)]
+fn header_for(type_id: u32)->MessageHeader {
+    MessageHeader{message_type: Some(type_id), timestamp: None, rpc: None}
+}